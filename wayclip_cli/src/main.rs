@@ -8,11 +8,14 @@ use inquire::{Confirm, Text};
 use std::env;
 use std::path::Path;
 use std::process::ExitCode;
+use std::time::Duration;
 use tokio::process::Command;
 use wayclip_core::control::DaemonManager;
+use wayclip_core::encoder::Container;
+use wayclip_core::protocol::{AudioSourceKind, DaemonClient, DaemonStatus};
 use wayclip_core::{
-    Collect, PullClipsArgs, WAYCLIP_TRIGGER_PATH, api, delete_file, gather_clip_data,
-    rename_all_entries, settings::Settings,
+    Collect, PullClipsArgs, api, delete_file, gather_clip_data, rename_all_entries,
+    settings::Settings,
 };
 
 pub mod auth;
@@ -38,7 +41,34 @@ pub enum Commands {
         #[command(subcommand)]
         action: DaemonCommand,
     },
-    Save,
+    Save {
+        #[arg(
+            short = 'l',
+            long = "last",
+            value_parser = parse_duration,
+            help = "Only save the last part of the buffer, e.g. 30s, 2m or 90"
+        )]
+        last: Option<Duration>,
+    },
+    #[command(about = "Change the volume or mute state of an audio source while recording")]
+    Audio {
+        #[arg(help = "Audio source, mic or bg (desktop)")]
+        source: AudioSourceKind,
+        #[arg(short = 'v', long = "volume", help = "Volume in percent")]
+        volume: Option<u8>,
+        #[arg(short = 'm', long = "mute", conflicts_with_all = ["unmute", "toggle"])]
+        mute: bool,
+        #[arg(short = 'u', long = "unmute", conflicts_with = "toggle")]
+        unmute: bool,
+        #[arg(short = 't', long = "toggle", help = "Flip the mute state")]
+        toggle: bool,
+        #[arg(
+            short = 'p',
+            long = "persist",
+            help = "Also save the change to settings.json"
+        )]
+        persist: bool,
+    },
     List {
         #[arg(short = 't', long = "timestamp")]
         timestamp: bool,
@@ -88,7 +118,12 @@ pub enum DaemonCommand {
     Start,
     Stop,
     Restart,
-    Status,
+    Status {
+        #[arg(long = "json", help = "Print the status as JSON")]
+        json: bool,
+    },
+    Reload,
+    ResetSource,
 }
 
 #[tokio::main]
@@ -111,7 +146,22 @@ async fn run() -> Result<()> {
         Commands::Logout => handle_logout().await?,
         Commands::Me => handle_me().await?,
         Commands::Share { name } => handle_share(name).await?,
-        Commands::Save => handle_save().await?,
+        Commands::Save { last } => handle_save(*last).await?,
+        Commands::Audio {
+            source,
+            volume,
+            mute,
+            unmute,
+            toggle,
+            persist,
+        } => {
+            let muted = match (*mute, *unmute) {
+                (true, _) => Some(true),
+                (_, true) => Some(false),
+                _ => None,
+            };
+            handle_audio(*source, *volume, muted, *toggle, *persist).await?
+        }
         Commands::List { .. } => handle_list(&cli.command).await?,
         Commands::Manage => handle_manage().await?,
         Commands::Config { editor } => handle_config(editor.as_deref()).await?,
@@ -125,8 +175,14 @@ async fn run() -> Result<()> {
                 DaemonCommand::Start => manager.start().await?,
                 DaemonCommand::Stop => manager.stop().await?,
                 DaemonCommand::Restart => manager.restart().await?,
-                DaemonCommand::Status => {
-                    manager.status().await?;
+                DaemonCommand::Status { json } => handle_daemon_status(&manager, *json).await?,
+                DaemonCommand::Reload => {
+                    manager.reload().await?;
+                    println!("{} Daemon reloaded settings.", "✔".green());
+                }
+                DaemonCommand::ResetSource => {
+                    manager.reset_source().await?;
+                    println!("{} Capture source reset.", "✔".green());
                 }
             }
         }
@@ -182,10 +238,14 @@ async fn handle_share(clip_name: &str) -> Result<()> {
     let settings = Settings::load().await?;
     let clips_path = Settings::home_path().join(&settings.save_path_from_home_string);
 
-    let clip_path = if clip_name.ends_with(".mp4") {
+    let clip_path = if Container::from_path(Path::new(clip_name)).is_some() {
         clips_path.join(clip_name)
     } else {
-        clips_path.join(format!("{}.mp4", clip_name))
+        Container::ALL
+            .iter()
+            .map(|container| clips_path.join(format!("{}.{}", clip_name, container.extension())))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| clips_path.join(format!("{}.mp4", clip_name)))
     };
 
     if !clip_path.exists() {
@@ -224,20 +284,164 @@ async fn handle_share(clip_name: &str) -> Result<()> {
     Ok(())
 }
 
-async fn handle_save() -> Result<()> {
-    let mut trigger_command = Command::new(WAYCLIP_TRIGGER_PATH);
-    let status = trigger_command
-        .status()
-        .await
-        .context("Failed to execute the trigger process. Is the daemon running?")?;
-    if status.success() {
-        println!("{}", "✔ Trigger process finished successfully.".green());
+fn parse_duration(raw: &str) -> Result<Duration, String> {
+    let raw = raw.trim();
+    let (number, multiplier) = if let Some(n) = raw.strip_suffix("ms") {
+        (n, 0.001)
+    } else if let Some(n) = raw.strip_suffix('s') {
+        (n, 1.0)
+    } else if let Some(n) = raw.strip_suffix('m') {
+        (n, 60.0)
+    } else if let Some(n) = raw.strip_suffix('h') {
+        (n, 3600.0)
     } else {
-        bail!("Trigger process failed with status: {}", status);
+        (raw, 1.0)
+    };
+
+    let value: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid duration '{raw}', expected something like 30s or 2m"))?;
+    if !value.is_finite() || value <= 0.0 {
+        return Err(String::from("duration must be above zero"));
     }
+    Ok(Duration::from_secs_f64(value * multiplier))
+}
+
+async fn handle_save(last: Option<Duration>) -> Result<()> {
+    let mut client = DaemonClient::connect_default().await?;
+    let path = client.save(last).await?;
+    println!("{} Saved clip to {}", "✔".green(), path.display());
     Ok(())
 }
 
+async fn handle_audio(
+    source: AudioSourceKind,
+    volume: Option<u8>,
+    muted: Option<bool>,
+    toggle: bool,
+    persist: bool,
+) -> Result<()> {
+    let mut client = DaemonClient::connect_default().await?;
+    // Without any changes this just reports the current level.
+    let mut level = client.set_audio(source, volume, muted, persist).await?;
+    if toggle {
+        level = client.toggle_mute(source, persist).await?;
+    }
+    let state = if level.muted {
+        "muted".yellow()
+    } else {
+        "unmuted".green()
+    };
+    println!(
+        "{} {} audio at {}%, {}",
+        "✔".green(),
+        level.source,
+        level.volume,
+        state
+    );
+    Ok(())
+}
+
+async fn handle_daemon_status(manager: &DaemonManager, json: bool) -> Result<()> {
+    let status = manager.status().await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print_daemon_status(&status);
+    }
+    Ok(())
+}
+
+fn format_duration_ms(ms: u64) -> String {
+    let secs = ms / 1000;
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m {s:02}s"),
+        (h, m, s) => format!("{h}h {m:02}m {s:02}s"),
+    }
+}
+
+fn print_daemon_status(status: &DaemonStatus) {
+    let state = match status.state.as_str() {
+        "Playing" => status.state.green(),
+        "Degraded" => status.state.red(),
+        _ => status.state.yellow(),
+    };
+    println!(
+        "{:<12} {} (up {}, {} restarts)",
+        "Pipeline:".bold(),
+        state,
+        format_duration_ms(status.uptime_ms),
+        status.restarts
+    );
+    if let Some(error) = &status.error {
+        println!("{:<12} {}", "Error:".bold(), error.red());
+    }
+    println!(
+        "{:<12} {}, {:.1} MB, {} kbps",
+        "Buffer:".bold(),
+        format_duration_ms(status.buffered_ms),
+        status.buffered_bytes as f64 / 1_048_576.0,
+        status.bitrate_kbps
+    );
+    let dropped = status.dropped_frames.to_string();
+    println!(
+        "{:<12} {}",
+        "Dropped:".bold(),
+        if status.dropped_frames > 0 {
+            dropped.yellow()
+        } else {
+            dropped.normal()
+        }
+    );
+
+    let sources: Vec<String> = status
+        .sources
+        .iter()
+        .map(|source| {
+            let mut details = Vec::new();
+            if let Some(node_id) = source.node_id {
+                details.push(format!("node {node_id}"));
+            }
+            if source.muted {
+                details.push(String::from("muted"));
+            }
+            if details.is_empty() {
+                source.name.clone()
+            } else {
+                format!("{} ({})", source.name, details.join(", "))
+            }
+        })
+        .collect();
+    println!("{:<12} {}", "Sources:".bold(), sources.join(", "));
+
+    println!(
+        "{:<12} {}",
+        "Saves:".bold(),
+        if status.is_saving {
+            format!("1 in progress, {} queued", status.queued_saves)
+        } else {
+            String::from("idle")
+        }
+    );
+    match &status.last_save {
+        Some(save) => {
+            let when = save.finished_at.format("%Y-%m-%d %H:%M:%S");
+            match (&save.path, &save.error) {
+                (_, Some(error)) => {
+                    println!("{:<12} {} at {}", "Last save:".bold(), error.red(), when)
+                }
+                (Some(path), None) => {
+                    println!("{:<12} {} at {}", "Last save:".bold(), path.display(), when)
+                }
+                (None, None) => println!("{:<12} {}", "Last save:".bold(), when),
+            }
+        }
+        None => println!("{:<12} none yet", "Last save:".bold()),
+    }
+}
+
 async fn handle_config(editor: Option<&str>) -> Result<()> {
     let editor_name = editor
         .map(String::from)
@@ -265,6 +469,12 @@ async fn handle_config(editor: Option<&str>) -> Result<()> {
     if !status.success() {
         bail!("Editor process failed with status: {}", status);
     }
+
+    let manager = DaemonManager::new();
+    if manager.is_running().await? {
+        manager.reload().await?;
+        println!("{} Daemon reloaded settings.", "✔".green());
+    }
     Ok(())
 }
 
@@ -0,0 +1,677 @@
+use crate::settings::Settings;
+use crate::{log_to, logging::Logger};
+use anyhow::{bail, Context, Result};
+use gstreamer::ElementFactory;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::str::FromStr;
+use tokio::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaClass {
+    Source,
+    Sink,
+}
+
+impl MediaClass {
+    // Virtual sources (e.g. noise filters) record just like hardware ones.
+    pub fn from_media_class(class: &str) -> Option<Self> {
+        match class {
+            "Audio/Source" | "Audio/Source/Virtual" => Some(MediaClass::Source),
+            "Audio/Sink" => Some(MediaClass::Sink),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioNode {
+    pub id: u32,
+    pub node_name: String,
+    pub description: String,
+    pub media_class: MediaClass,
+    // Channel positions as PipeWire names them (FL, FR, ...), empty when not reported.
+    pub channels: Vec<String>,
+    pub is_default: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AudioGraph {
+    pub nodes: Vec<AudioNode>,
+    pub default_source: Option<String>,
+    pub default_sink: Option<String>,
+}
+
+impl AudioGraph {
+    pub fn find(&self, node_name: &str) -> Option<&AudioNode> {
+        self.nodes.iter().find(|node| node.node_name == node_name)
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = &AudioNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.media_class == MediaClass::Source)
+    }
+
+    pub fn sinks(&self) -> impl Iterator<Item = &AudioNode> {
+        self.nodes
+            .iter()
+            .filter(|node| node.media_class == MediaClass::Sink)
+    }
+
+    // The node to record from: the configured one, or the current default of `class`
+    // when following the defaults or when none is configured. None while it's missing.
+    pub fn resolve(
+        &self,
+        class: MediaClass,
+        configured: &str,
+        follow_default: bool,
+    ) -> Option<&AudioNode> {
+        let default = match class {
+            MediaClass::Source => &self.default_source,
+            MediaClass::Sink => &self.default_sink,
+        };
+        let node_name = match default {
+            Some(default) if follow_default || configured.is_empty() => default.as_str(),
+            _ => configured,
+        };
+        self.find(node_name)
+    }
+
+    // What a recording branch should do about this snapshot. `current` is the node it
+    // records from, None when it was left out of the pipeline because its node was missing.
+    pub fn relink(
+        &self,
+        class: MediaClass,
+        configured: &str,
+        follow_default: bool,
+        current: Option<u32>,
+    ) -> Relink {
+        match (self.resolve(class, configured, follow_default), current) {
+            // Unplugged devices are picked up again once they come back.
+            (None, _) => Relink::Keep,
+            (Some(node), None) => Relink::Rebuild { node_id: node.id },
+            (Some(node), Some(current)) if node.id == current => Relink::Keep,
+            (Some(node), Some(_)) => Relink::Move { node_id: node.id },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relink {
+    Keep,
+    // Point the running source at another node, it keeps its volume element.
+    Move { node_id: u32 },
+    // The node is back, adding its branch takes a new pipeline.
+    Rebuild { node_id: u32 },
+}
+
+// Snapshot of the audio nodes and defaults through `pw-dump`, whose JSON doesn't
+// change with the locale like `pw-cli` and `pactl` text does. This is the fallback
+// for not talking to the PipeWire registry directly, each call is one subprocess.
+pub async fn query() -> Result<AudioGraph> {
+    let output = Command::new("pw-dump")
+        .output()
+        .await
+        .context("Failed to run pw-dump. Is PipeWire installed?")?;
+    if !output.status.success() {
+        bail!(
+            "pw-dump error: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_pw_dump(&String::from_utf8_lossy(&output.stdout))
+}
+
+pub fn parse_pw_dump(json: &str) -> Result<AudioGraph> {
+    let objects: Vec<Value> = serde_json::from_str(json).context("Invalid pw-dump output")?;
+
+    let mut graph = AudioGraph::default();
+    for object in &objects {
+        match object["type"].as_str() {
+            Some("PipeWire:Interface:Node") => graph.nodes.extend(parse_node(object)),
+            Some("PipeWire:Interface:Metadata")
+                if object["props"]["metadata.name"].as_str() == Some("default") =>
+            {
+                for entry in object["metadata"].as_array().into_iter().flatten() {
+                    let name = metadata_name(&entry["value"]);
+                    match entry["key"].as_str() {
+                        Some("default.audio.source") => graph.default_source = name,
+                        Some("default.audio.sink") => graph.default_sink = name,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for node in &mut graph.nodes {
+        let default = match node.media_class {
+            MediaClass::Source => &graph.default_source,
+            MediaClass::Sink => &graph.default_sink,
+        };
+        node.is_default = default.as_deref() == Some(node.node_name.as_str());
+    }
+    Ok(graph)
+}
+
+fn parse_node(object: &Value) -> Option<AudioNode> {
+    let props = &object["info"]["props"];
+    let media_class = MediaClass::from_media_class(props["media.class"].as_str()?)?;
+    let node_name = props["node.name"].as_str()?.to_string();
+    let description = props["node.description"]
+        .as_str()
+        .or_else(|| props["device.description"].as_str())
+        .unwrap_or(&node_name)
+        .to_string();
+
+    Some(AudioNode {
+        id: object["id"].as_u64()? as u32,
+        node_name,
+        description,
+        media_class,
+        channels: channel_positions(object),
+        is_default: false,
+    })
+}
+
+// The negotiated format knows the layout best, idle nodes only have the props.
+fn channel_positions(object: &Value) -> Vec<String> {
+    let format_positions = object["info"]["params"]["Format"]
+        .as_array()
+        .and_then(|formats| formats.first())
+        .and_then(|format| format["position"].as_array());
+    if let Some(positions) = format_positions {
+        return positions
+            .iter()
+            .filter_map(|p| p.as_str().map(String::from))
+            .collect();
+    }
+
+    let props = &object["info"]["props"];
+    if let Some(positions) = props["audio.position"].as_str() {
+        return positions
+            .split(|c: char| c == ',' || c.is_whitespace() || c == '[' || c == ']')
+            .filter(|p| !p.is_empty())
+            .map(String::from)
+            .collect();
+    }
+    match props["audio.channels"].as_u64() {
+        Some(1) => vec![String::from("MONO")],
+        Some(count) => (0..count).map(|i| format!("AUX{i}")).collect(),
+        None => Vec::new(),
+    }
+}
+
+// Default entries hold `{"name": ...}`, older versions store it as a JSON string.
+fn metadata_name(value: &Value) -> Option<String> {
+    match value {
+        Value::Object(_) => value["name"].as_str().map(String::from),
+        Value::String(raw) => serde_json::from_str::<Value>(raw)
+            .ok()
+            .and_then(|v| v["name"].as_str().map(String::from)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoiseSuppression {
+    Off,
+    Webrtc,
+    Rnnoise,
+}
+
+impl FromStr for NoiseSuppression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "off" | "none" => Ok(NoiseSuppression::Off),
+            "webrtc" => Ok(NoiseSuppression::Webrtc),
+            "rnnoise" => Ok(NoiseSuppression::Rnnoise),
+            other => bail!("Unknown noise suppression '{other}' (expected off, webrtc or rnnoise)"),
+        }
+    }
+}
+
+impl fmt::Display for NoiseSuppression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoiseSuppression::Off => write!(f, "off"),
+            NoiseSuppression::Webrtc => write!(f, "webrtc"),
+            NoiseSuppression::Rnnoise => write!(f, "rnnoise"),
+        }
+    }
+}
+
+impl NoiseSuppression {
+    fn factory(&self) -> Option<&'static str> {
+        match self {
+            NoiseSuppression::Off => None,
+            NoiseSuppression::Webrtc => Some("webrtcdsp"),
+            NoiseSuppression::Rnnoise => Some("audiornnoise"),
+        }
+    }
+
+    // Both want 48kHz, in the sample format they process natively.
+    fn launch_segment(&self) -> &'static str {
+        match self {
+            NoiseSuppression::Off => "",
+            NoiseSuppression::Webrtc => {
+                "audio/x-raw,format=S16LE,rate=48000 ! \
+                webrtcdsp echo-cancel=false gain-control=false \
+                noise-suppression=true noise-suppression-level=high"
+            }
+            NoiseSuppression::Rnnoise => "audio/x-raw,format=F32LE,rate=48000 ! audiornnoise",
+        }
+    }
+}
+
+// Downward expander that all but silences the mic below the threshold (keyboard, fans).
+// In expander mode a ratio below 1 lifts quiet samples instead, so it has to be well above.
+const NOISE_GATE: &str =
+    "audiodynamic mode=expander characteristics=hard-knee threshold=0.02 ratio=10";
+// Evens out shouting and whispering so peaks don't clip the mix.
+const COMPRESSOR: &str =
+    "audiodynamic mode=compressor characteristics=soft-knee threshold=0.3 ratio=0.25";
+
+// Optional clean-up stages for the mic branch, each one skipped when its plugin is missing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MicProcessing {
+    stages: Vec<&'static str>,
+}
+
+impl MicProcessing {
+    pub fn select(settings: &Settings, logger: &Logger) -> Result<Self> {
+        let suppression: NoiseSuppression = settings.mic_noise_suppression.parse()?;
+        Ok(Self::select_with(
+            suppression,
+            settings.mic_noise_gate,
+            settings.mic_compressor,
+            logger,
+            |name| ElementFactory::find(name).is_some(),
+        ))
+    }
+
+    pub fn select_with(
+        suppression: NoiseSuppression,
+        noise_gate: bool,
+        compressor: bool,
+        logger: &Logger,
+        is_available: impl Fn(&str) -> bool,
+    ) -> Self {
+        let mut stages = Vec::new();
+
+        // The other suppressor is better than none.
+        let fallback = match suppression {
+            NoiseSuppression::Webrtc => NoiseSuppression::Rnnoise,
+            NoiseSuppression::Rnnoise => NoiseSuppression::Webrtc,
+            NoiseSuppression::Off => NoiseSuppression::Off,
+        };
+        if let Some(factory) = suppression.factory() {
+            match [suppression, fallback]
+                .into_iter()
+                .find(|s| s.factory().is_some_and(&is_available))
+            {
+                Some(picked) => {
+                    if picked != suppression {
+                        log_to!(logger, Warn, [GST] => "Noise suppression element '{}' not found, using {} instead.", factory, picked);
+                    }
+                    stages.push(picked.launch_segment());
+                }
+                None => {
+                    log_to!(logger, Warn, [GST] => "No noise suppression element found (install webrtcdsp or audiornnoise), recording the mic unfiltered.");
+                }
+            }
+        }
+
+        for (enabled, name, segment) in [
+            (noise_gate, "noise gate", NOISE_GATE),
+            (compressor, "compressor", COMPRESSOR),
+        ] {
+            if !enabled {
+                continue;
+            }
+            if is_available("audiodynamic") {
+                stages.push(segment);
+            } else {
+                log_to!(logger, Warn, [GST] => "Element 'audiodynamic' not found, skipping the mic {}.", name);
+            }
+        }
+
+        if !stages.is_empty() {
+            log_to!(logger, Info, [GST] => "Mic processing: {}", stages.join(" ! "));
+        }
+        Self { stages }
+    }
+
+    // Goes between the resampled mic audio and its volume element, empty without stages.
+    pub fn launch_segment(&self) -> String {
+        self.stages
+            .iter()
+            .map(|stage| format!("audioconvert ! {stage} ! audioconvert ! "))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn graph(fixture: &str) -> AudioGraph {
+        parse_pw_dump(fixture).expect("fixture should parse")
+    }
+
+    fn node<'a>(graph: &'a AudioGraph, node_name: &str) -> &'a AudioNode {
+        graph
+            .find(node_name)
+            .unwrap_or_else(|| panic!("{node_name} should be in the graph"))
+    }
+
+    #[test]
+    fn reads_default_sink_and_source_metadata() {
+        let graph = graph(include_str!("../tests/fixtures/pw-dump-defaults.json"));
+
+        assert_eq!(
+            graph.default_sink.as_deref(),
+            Some("alsa_output.pci-0000_00_1f.3.analog-stereo")
+        );
+        assert_eq!(
+            graph.default_source.as_deref(),
+            Some("alsa_input.usb-Blue_Microphones_Yeti-00.mono-fallback")
+        );
+
+        let sink = node(&graph, "alsa_output.pci-0000_00_1f.3.analog-stereo");
+        assert_eq!(sink.id, 52);
+        assert_eq!(sink.media_class, MediaClass::Sink);
+        assert_eq!(sink.description, "Built-in Audio Analog Stereo");
+        assert_eq!(sink.channels, ["FL", "FR"]);
+        assert!(sink.is_default);
+
+        let mic = node(
+            &graph,
+            "alsa_input.usb-Blue_Microphones_Yeti-00.mono-fallback",
+        );
+        assert_eq!(mic.media_class, MediaClass::Source);
+        assert_eq!(mic.description, "Yeti Stereo Microphone Mono");
+        assert_eq!(mic.channels, ["MONO"]);
+        assert!(mic.is_default);
+
+        // No node description, the device one stands in.
+        let line_in = node(&graph, "alsa_input.pci-0000_00_1f.3.analog-stereo");
+        assert_eq!(line_in.media_class, MediaClass::Source);
+        assert_eq!(line_in.description, "Built-in Audio");
+        assert_eq!(line_in.channels, ["FL", "FR"]);
+        assert!(!line_in.is_default);
+    }
+
+    #[test]
+    fn skips_devices_and_video_nodes() {
+        let graph = graph(include_str!("../tests/fixtures/pw-dump-defaults.json"));
+
+        assert_eq!(graph.sinks().count(), 1);
+        assert_eq!(graph.sources().count(), 2);
+        assert!(graph
+            .find("v4l2_input.pci-0000_00_14.0-usb-0_6_1.0")
+            .is_none());
+    }
+
+    #[test]
+    fn monitors_record_as_sources() {
+        let graph = graph(include_str!("../tests/fixtures/pw-dump-monitor.json"));
+
+        let monitor = node(
+            &graph,
+            "loopback.monitor.alsa_output.pci-0000_00_1f.3.analog-stereo",
+        );
+        assert_eq!(monitor.media_class, MediaClass::Source);
+        assert_eq!(
+            monitor.description,
+            "Monitor of Built-in Audio Analog Stereo"
+        );
+        assert_eq!(monitor.channels, ["FL", "FR"]);
+        assert!(!monitor.is_default);
+
+        // Application streams aren't devices to record from.
+        assert!(graph.find("Firefox").is_none());
+        assert_eq!(graph.nodes.len(), 2);
+        assert_eq!(graph.default_source, None);
+    }
+
+    #[test]
+    fn reads_multichannel_layouts() {
+        let graph = graph(include_str!("../tests/fixtures/pw-dump-multichannel.json"));
+
+        // String encoded metadata values from older PipeWire versions.
+        assert_eq!(
+            graph.default_sink.as_deref(),
+            Some("alsa_output.pci-0000_01_00.1.hdmi-surround")
+        );
+        assert_eq!(
+            graph.default_source.as_deref(),
+            Some("alsa_input.usb-Focusrite_Scarlett_4i4-00.multichannel-input")
+        );
+
+        // The negotiated format wins over the configured positions.
+        let hdmi = node(&graph, "alsa_output.pci-0000_01_00.1.hdmi-surround");
+        assert_eq!(hdmi.channels, ["FL", "FR", "FC", "LFE", "SL", "SR"]);
+        assert!(hdmi.is_default);
+
+        let surround = node(
+            &graph,
+            "alsa_output.usb-Creative_Sound_Blaster-00.analog-surround-71",
+        );
+        assert_eq!(surround.media_class, MediaClass::Sink);
+        assert_eq!(surround.description, "Living Room 7.1");
+        assert_eq!(
+            surround.channels,
+            ["FL", "FR", "FC", "LFE", "RL", "RR", "SL", "SR"]
+        );
+        assert!(!surround.is_default);
+
+        let interface = node(
+            &graph,
+            "alsa_input.usb-Focusrite_Scarlett_4i4-00.multichannel-input",
+        );
+        assert_eq!(interface.media_class, MediaClass::Source);
+        assert_eq!(interface.channels, ["AUX0", "AUX1", "AUX2", "AUX3"]);
+        assert!(interface.is_default);
+    }
+
+    #[test]
+    fn missing_default_metadata_leaves_no_defaults() {
+        let graph = graph(include_str!("../tests/fixtures/pw-dump-no-default.json"));
+
+        // Keys in other metadata objects don't count as defaults.
+        assert_eq!(graph.default_sink, None);
+        assert_eq!(graph.default_source, None);
+        assert!(graph.nodes.iter().all(|node| !node.is_default));
+
+        let sink = node(&graph, "alsa_output.pci-0000_00_1f.3.analog-stereo");
+        assert_eq!(sink.channels, ["AUX0", "AUX1"]);
+
+        // Nothing describes this one but its name.
+        let source = node(&graph, "alsa_input.pci-0000_00_1f.3.analog-stereo");
+        assert_eq!(
+            source.description,
+            "alsa_input.pci-0000_00_1f.3.analog-stereo"
+        );
+        assert!(source.channels.is_empty());
+    }
+
+    #[test]
+    fn resolves_configured_and_default_nodes() {
+        let defaults = graph(include_str!("../tests/fixtures/pw-dump-defaults.json"));
+        let line_in = "alsa_input.pci-0000_00_1f.3.analog-stereo";
+        let id = |node: Option<&AudioNode>| node.map(|node| node.id);
+
+        assert_eq!(
+            id(defaults.resolve(MediaClass::Source, line_in, false)),
+            Some(61)
+        );
+        // Following the defaults wins over the configured device.
+        assert_eq!(
+            id(defaults.resolve(MediaClass::Source, line_in, true)),
+            Some(58)
+        );
+        // Nothing configured means the default of the class.
+        assert_eq!(
+            id(defaults.resolve(MediaClass::Source, "", false)),
+            Some(58)
+        );
+        assert_eq!(id(defaults.resolve(MediaClass::Sink, "", false)), Some(52));
+        assert_eq!(id(defaults.resolve(MediaClass::Sink, "gone", false)), None);
+
+        // Without defaults the configured device is all there is.
+        let no_default = graph(include_str!("../tests/fixtures/pw-dump-no-default.json"));
+        assert_eq!(
+            id(no_default.resolve(MediaClass::Source, line_in, true)),
+            Some(58)
+        );
+        assert_eq!(id(no_default.resolve(MediaClass::Source, "", true)), None);
+    }
+
+    #[test]
+    fn configured_node_reappears() {
+        let mic = "alsa_input.usb-Blue_Microphones_Yeti-00.mono-fallback";
+        let unplugged = graph(include_str!("../tests/fixtures/pw-dump-monitor.json"));
+        let plugged = graph(include_str!("../tests/fixtures/pw-dump-defaults.json"));
+
+        // Left out of the pipeline while it's gone, and nothing to do about it yet.
+        assert_eq!(
+            unplugged.relink(MediaClass::Source, mic, false, None),
+            Relink::Keep
+        );
+        // Back again, its branch has to be added.
+        assert_eq!(
+            plugged.relink(MediaClass::Source, mic, false, None),
+            Relink::Rebuild { node_id: 58 }
+        );
+        // A running branch stays on its node while the device is briefly gone.
+        assert_eq!(
+            unplugged.relink(MediaClass::Source, mic, false, Some(58)),
+            Relink::Keep
+        );
+        assert_eq!(
+            plugged.relink(MediaClass::Source, mic, false, Some(58)),
+            Relink::Keep
+        );
+        // Same device under a new id after replugging.
+        assert_eq!(
+            plugged.relink(MediaClass::Source, mic, false, Some(47)),
+            Relink::Move { node_id: 58 }
+        );
+    }
+
+    #[test]
+    fn following_defaults_moves_to_the_new_default() {
+        let stereo = graph(include_str!("../tests/fixtures/pw-dump-defaults.json"));
+        let surround = graph(include_str!("../tests/fixtures/pw-dump-multichannel.json"));
+
+        assert_eq!(
+            stereo.relink(MediaClass::Sink, "", true, Some(52)),
+            Relink::Keep
+        );
+        assert_eq!(
+            surround.relink(MediaClass::Sink, "", true, Some(52)),
+            Relink::Move { node_id: 45 }
+        );
+        assert_eq!(
+            surround.relink(MediaClass::Source, "", true, Some(58)),
+            Relink::Move { node_id: 49 }
+        );
+    }
+
+    #[test]
+    fn rejects_output_that_is_not_pw_dump_json() {
+        assert!(parse_pw_dump("Failed to connect to PipeWire").is_err());
+        assert!(parse_pw_dump("[]").unwrap().nodes.is_empty());
+    }
+
+    fn logger() -> Logger {
+        let log = env::temp_dir().join(format!("wayclip-audio-test-{}.log", process::id()));
+        Logger::new(log).unwrap()
+    }
+
+    // The mic stages picked when only `installed` elements exist.
+    fn mic_stages(
+        suppression: NoiseSuppression,
+        noise_gate: bool,
+        compressor: bool,
+        installed: &[&str],
+    ) -> Vec<&'static str> {
+        MicProcessing::select_with(suppression, noise_gate, compressor, &logger(), |name| {
+            installed.contains(&name)
+        })
+        .stages
+    }
+
+    #[test]
+    fn mic_suppression_falls_back_to_the_other_element() {
+        let rnnoise = NoiseSuppression::Rnnoise.launch_segment();
+        let webrtc = NoiseSuppression::Webrtc.launch_segment();
+        let both = ["audiornnoise", "webrtcdsp"];
+
+        assert_eq!(
+            mic_stages(NoiseSuppression::Rnnoise, false, false, &both),
+            [rnnoise]
+        );
+        assert_eq!(
+            mic_stages(NoiseSuppression::Webrtc, false, false, &both),
+            [webrtc]
+        );
+        assert_eq!(
+            mic_stages(NoiseSuppression::Rnnoise, false, false, &["webrtcdsp"]),
+            [webrtc]
+        );
+        assert_eq!(
+            mic_stages(NoiseSuppression::Webrtc, false, false, &["audiornnoise"]),
+            [rnnoise]
+        );
+        assert!(mic_stages(NoiseSuppression::Rnnoise, false, false, &[]).is_empty());
+        assert!(mic_stages(NoiseSuppression::Off, false, false, &both).is_empty());
+    }
+
+    #[test]
+    fn mic_gate_and_compressor_need_audiodynamic() {
+        assert_eq!(
+            mic_stages(
+                NoiseSuppression::Rnnoise,
+                true,
+                true,
+                &["audiornnoise", "audiodynamic"]
+            ),
+            [
+                NoiseSuppression::Rnnoise.launch_segment(),
+                NOISE_GATE,
+                COMPRESSOR
+            ]
+        );
+        // The gate still applies when neither suppressor is installed.
+        assert_eq!(
+            mic_stages(NoiseSuppression::Rnnoise, true, false, &["audiodynamic"]),
+            [NOISE_GATE]
+        );
+        assert!(mic_stages(NoiseSuppression::Off, true, true, &[]).is_empty());
+        assert_eq!(MicProcessing::default().launch_segment(), "");
+    }
+
+    #[test]
+    fn noise_gate_pushes_quiet_samples_down() {
+        let ratio: f32 = NOISE_GATE
+            .split_whitespace()
+            .find_map(|prop| prop.strip_prefix("ratio="))
+            .and_then(|ratio| ratio.parse().ok())
+            .expect("the gate should set a ratio");
+        assert!(NOISE_GATE.contains("mode=expander"));
+        assert!(
+            ratio > 1.0,
+            "an expander ratio of {ratio} boosts the noise floor"
+        );
+    }
+}
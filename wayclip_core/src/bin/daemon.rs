@@ -1,380 +1,513 @@
+use anyhow::Context;
 use ashpd::desktop::{
-    screencast::{CursorMode, Screencast, SourceType},
-    PersistMode,
+    screencast::{CursorMode, Screencast},
+    PersistMode, Session,
 };
-use gst::prelude::{Cast, ElementExt, GstBinExt, ObjectExt};
+use gst::prelude::{Cast, ElementExt, ElementExtManual, GstBinExt, ObjectExt, PadExt};
 use gstreamer::{self as gst};
-use gstreamer_app::AppSink;
+use gstreamer_app::{AppSink, AppSrc};
+use serde_json::json;
 use std::env;
 use std::error::Error;
 use std::fs::{create_dir_all, metadata, remove_file};
-use std::os::unix::io::AsRawFd;
-use std::process::{exit, Stdio};
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::process::exit;
 use std::sync::{
-    atomic::{AtomicBool, AtomicUsize, Ordering},
+    atomic::{AtomicU64, AtomicUsize, Ordering},
     Arc, Mutex,
 };
 use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::UnixListener;
-use tokio::process::Command;
+use tokio::net::{UnixListener, UnixStream};
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 use wayclip_core::{
-    cleanup, generate_preview_clip, get_pipewire_node_id, handle_bus_messages, log_to,
-    logging::Logger, ring::RingBuffer, send_status_to_gui, settings::Settings, setup_hyprland,
+    audio::{self, MediaClass, Relink},
+    capture::{
+        forget_restore_token, load_restore_token, pick_streams, save_restore_token, CaptureBackend,
+        CaptureSource, VideoStream,
+    },
+    cleanup,
+    cursor::{parse_cursor_mode, pick_cursor_mode, CursorOverlay},
+    encoder::{check_settings, Container},
+    generate_preview_clip, handle_bus_messages, log_to,
+    logging::Logger,
+    pidfile::PidLock,
+    pipeline::{AudioInput, PipelineConfig, VideoInput},
+    protocol::{
+        encode_response, parse_request, AudioLevel, AudioSourceKind, DaemonError, DaemonStatus,
+        Request, Response, SaveResult, SourceStatus,
+    },
+    remux::{remux_matroska, RemuxError},
+    ring::{Chunk, RingBuffer},
+    runtime::{is_same_user, prepare_runtime_dir},
+    send_status_to_gui,
+    settings::Settings,
+    setup_hyprland,
 };
 
-const SAVE_COOLDOWN: Duration = Duration::from_secs(2);
+const RESTART_BACKOFF_MIN: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(30);
+// Failed rebuilds on the same stream before asking the portal for a new one.
+const PORTAL_REOPEN_AFTER: u32 = 2;
+// A pipeline that ran this long counts as recovered, backoff starts over.
+const RESTART_STABLE_AFTER: Duration = Duration::from_secs(60);
+const SAVE_QUEUE_SIZE: usize = 8;
+// How often default device changes and returning audio nodes are looked for.
+const AUDIO_POLL_INTERVAL: Duration = Duration::from_secs(3);
+// Chunks waiting to be pushed into the ring, a few seconds worth even at high bitrates.
+const FRAME_QUEUE_SIZE: usize = 1024;
+// Newest part of the buffer the reported bitrate is averaged over.
+const STATUS_BITRATE_WINDOW: gst::ClockTime = gst::ClockTime::from_seconds(5);
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let settings = Settings::load().await?;
-    let log_dir = "/tmp/wayclip";
-    create_dir_all(log_dir).expect("Failed to create log directory");
-    let timestamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+type DaemonCommand = (Request, oneshot::Sender<Response>);
 
-    let logger = Logger::new(format!("{log_dir}/wayclip-{timestamp}.log"))
-        .expect("Failed to create daemon logger");
+struct Capture<'a> {
+    // Both None with the test backend, which runs without a screencast.
+    session: Option<Session<'a, Screencast<'a>>>,
+    pipewire_fd: Option<OwnedFd>,
+    streams: Vec<VideoStream>,
+    // What the portal agreed to, may differ from the setting.
+    cursor_mode: CursorMode,
+}
 
-    log_to!(logger, Info, [DAEMON] => "Starting...");
-    log_to!(logger, Debug, [DAEMON] => "Settings loaded: {:?}", settings);
+impl Capture<'_> {
+    fn test() -> Self {
+        Self {
+            session: None,
+            pipewire_fd: None,
+            streams: Vec::new(),
+            cursor_mode: CursorMode::Hidden,
+        }
+    }
 
-    env::set_var(
-        "GST_DEBUG",
-        "pipewiresrc:4,audiomixer:4,audioconvert:4,audioresample:4,opusenc:4,matroskamux:4,3",
-    );
-    gst::init().expect("Failed to init gstreamer");
-    if metadata(&settings.daemon_socket_path).is_ok() {
-        if let Err(e) = remove_file(&settings.daemon_socket_path) {
-            log_to!(logger, Error, [UNIX] => "Failed to remove existing daemon socket file: {}", e);
-            exit(1);
+    fn is_test(&self) -> bool {
+        self.session.is_none()
+    }
+
+    async fn close(&self, logger: &Logger) {
+        if let Some(session) = &self.session {
+            if let Err(e) = session.close().await {
+                log_to!(logger, Warn, [ASH] => "Failed to close old screencast session, {}", e);
+            }
         }
     }
+}
 
-    send_status_to_gui(
-        settings.gui_socket_path.clone(),
-        String::from("Starting"),
-        &logger,
-    );
+// Tracks pipeline failures and when to try bringing the pipeline back.
+struct Supervisor {
+    failure: Option<String>,
+    restarts: u32,
+    attempts: u32,
+    retry_at: Instant,
+    running_since: Instant,
+    // The screencast session was closed without a new one to replace it.
+    capture_lost: bool,
+}
 
-    let listener =
-        UnixListener::bind(&settings.daemon_socket_path).expect("Failed to bind unix socket");
+impl Supervisor {
+    fn new() -> Self {
+        Self {
+            failure: None,
+            restarts: 0,
+            attempts: 0,
+            retry_at: Instant::now(),
+            running_since: Instant::now(),
+            capture_lost: false,
+        }
+    }
 
-    if std::env::var("DESKTOP_SESSION") == Ok("hyprland".to_string()) {
-        setup_hyprland(&logger).await;
-    } else {
-        log_to!(logger, Info, [HYPR] => "Not using hyprland. Please bind Alt+C to trigger save.");
+    fn is_healthy(&self) -> bool {
+        self.failure.is_none()
     }
 
-    let proxy = Screencast::new()
-        .await
-        .expect("Failed to create screencast proxy");
-    let session = proxy
-        .create_session()
-        .await
-        .expect("Failed to create screencast session");
-    proxy
-        .select_sources(
-            &session,
-            CursorMode::Hidden,
-            enumflags2::BitFlags::from(SourceType::Monitor),
-            false,
-            None,
-            PersistMode::Application,
-        )
-        .await
-        .expect("Failed to select sources");
+    fn failed(&mut self, reason: String) {
+        if self.running_since.elapsed() >= RESTART_STABLE_AFTER {
+            self.attempts = 0;
+        }
+        self.failure = Some(reason);
+        self.schedule_retry();
+    }
 
-    log_to!(logger, Info, [ASH] => "Starting screencast session");
-    let response = proxy
-        .start(&session, None)
-        .await
-        .expect("Failed to start screencast session")
-        .response()
-        .expect("Failed to get screencast response");
-    let stream = response
-        .streams()
-        .first()
-        .expect("No streams found in response");
-    let node_id = stream.pipe_wire_node_id();
-    log_to!(logger, Info, [ASH] => "Streams: {:?}", stream);
+    fn schedule_retry(&mut self) {
+        let backoff = RESTART_BACKOFF_MIN * 2u32.pow(self.attempts.min(5));
+        self.retry_at = Instant::now() + backoff.min(RESTART_BACKOFF_MAX);
+        self.attempts += 1;
+    }
 
-    let pipewire_fd = proxy
-        .open_pipe_wire_remote(&session)
-        .await
-        .expect("Failed to open pipewire remote");
-    log_to!(logger, Info, [ASH] => "Pipewire fd: {:?}", pipewire_fd.as_raw_fd());
+    fn should_reopen_portal(&self) -> bool {
+        self.capture_lost || self.attempts > PORTAL_REOPEN_AFTER
+    }
 
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    fn recovered(&mut self) {
+        self.failure = None;
+        self.restarts += 1;
+        self.running_since = Instant::now();
+    }
+}
 
-    let clip_duration = gst::ClockTime::from_seconds(settings.clip_length_s);
-    let ring_buffer = Arc::new(Mutex::new(RingBuffer::new(clip_duration, &logger)));
-    let is_saving = Arc::new(AtomicBool::new(false));
-
-    let mut pipeline_parts = Vec::new();
-    let has_audio = settings.include_bg_audio || settings.include_mic_audio;
-    pipeline_parts.push("matroskamux name=mux ! appsink name=sink".to_string());
-
-    // Mine: Little outdated
-    // pipeline_parts.push(format!(
-    //     "pipewiresrc do-timestamp=true fd={fd} path={path} ! \
-    //     queue ! \
-    //     video/x-raw,format=BGRx ! \
-    //     videoconvert ! \
-    //     video/x-raw,format=NV12 ! \
-    //     videorate ! \
-    //     video/x-raw,framerate={fps}/1 ! \
-    //     cudaupload ! \
-    //     nvh264enc bitrate={bitrate} ! \
-    //     h264parse ! \
-    //     queue ! \
-    //     mux.video_0",
-    //     fd = pipewire_fd.as_raw_fd(),
-    //     path = node_id,
-    //     fps = settings.clip_fps,
-    //     bitrate = settings.video_bitrate
-    // ));
-
-    // Mine: Last working one with frames
-    // pipeline_parts.push(format!(
-    //     "pipewiresrc do-timestamp=true fd={fd} path={path} ! \
-    //     queue ! \
-    //     video/x-raw,format=BGRx ! \
-    //     videoconvert ! \
-    //     video/x-raw,format=NV12 ! \
-    //     videorate ! \
-    //     video/x-raw,framerate={fps}/1 ! \
-    //     cudaupload ! \
-    //     nvh264enc bitrate={bitrate} ! \
-    //     h264parse ! \
-    //     queue ! \
-    //     mux.video_0",
-    //     fd = pipewire_fd.as_raw_fd(),
-    //     path = node_id,
-    //     fps = settings.clip_fps,
-    //     bitrate = settings.video_bitrate
-    // ));
-
-    // GROK
-    // pipeline_parts.push(format!(
-    //     "pipewiresrc do-timestamp=true fd={fd} path={path} ! \
-    // queue ! \
-    // video/x-raw(memory:SystemMemory),format=BGRx ! \
-    // videoconvert ! \
-    // video/x-raw,format=NV12 ! \
-    // videorate ! \
-    // video/x-raw,framerate={fps}/1 ! \
-    // cudaupload ! \
-    // nvh264enc bitrate={bitrate} ! \
-    // h264parse ! \
-    // queue ! \
-    // mux.video_0",
-    //     fd = pipewire_fd.as_raw_fd(),
-    //     path = node_id,
-    //     fps = settings.clip_fps,
-    //     bitrate = settings.video_bitrate
-    // ));
-
-    // GPT-5 MINI
-    pipeline_parts.clear();
-    // Removed  streamable=true
-    pipeline_parts
-        .push("matroskamux name=mux ! queue max-size-buffers=2 ! appsink name=sink".to_string());
-
-    let (width, height) = {
-        let parts: Vec<&str> = settings.clip_resolution.split('x').collect();
-        if parts.len() == 2 {
-            let w = parts[0].parse::<i32>().unwrap_or(1920);
-            let h = parts[1].parse::<i32>().unwrap_or(1080);
-            (w, h)
-        } else {
-            log_to!(logger, Warn, [DAEMON] => "Invalid video_resolution format '{}'. Using default 1920x1080.", settings.clip_resolution);
-            (1920, 1080)
-        }
+struct SaveJob {
+    job_id: usize,
+    chunks: Vec<Chunk>,
+    settings: Settings,
+    reply: oneshot::Sender<Response>,
+}
+
+struct Recording {
+    pipeline: gst::Element,
+    config: PipelineConfig,
+    bus_task: JoinHandle<Option<String>>,
+    ring_task: JoinHandle<()>,
+    cursor: Option<CursorOverlay>,
+}
+
+async fn fill_ring_buffer(mut frames: Receiver<gst::Buffer>, ring_buffer: Arc<Mutex<RingBuffer>>) {
+    while let Some(buffer) = frames.recv().await {
+        ring_buffer.lock().unwrap().push(buffer);
+    }
+}
+
+// Settings the portal session was opened with, changing them means picking a new stream.
+fn capture_changed(old: &Settings, new: &Settings) -> bool {
+    old.capture_source != new.capture_source
+        || old.capture_monitor != new.capture_monitor
+        || old.cursor_mode != new.cursor_mode
+}
+
+// Settings that only live in the launch string, changing any of them means a new pipeline.
+fn needs_rebuild(old: &Settings, new: &Settings) -> bool {
+    capture_changed(old, new)
+        || old.clip_fps != new.clip_fps
+        || old.clip_resolution != new.clip_resolution
+        || old.video_bitrate != new.video_bitrate
+        || old.video_codec != new.video_codec
+        || old.encoder_preference != new.encoder_preference
+        || old.audio_codec != new.audio_codec
+        || old.mic_node_name != new.mic_node_name
+        || old.bg_node_name != new.bg_node_name
+        || old.follow_default_devices != new.follow_default_devices
+        || old.include_mic_audio != new.include_mic_audio
+        || old.mic_noise_suppression != new.mic_noise_suppression
+        || old.mic_noise_gate != new.mic_noise_gate
+        || old.mic_compressor != new.mic_compressor
+        || old.include_bg_audio != new.include_bg_audio
+        || old.separate_audio_tracks != new.separate_audio_tracks
+        || old.include_mixed_track != new.include_mixed_track
+}
+
+fn audio_level(settings: &Settings, source: AudioSourceKind) -> AudioLevel {
+    let (volume, muted) = match source {
+        AudioSourceKind::Mic => (settings.mic_volume, settings.mic_muted),
+        AudioSourceKind::Bg => (settings.bg_volume, settings.bg_muted),
     };
+    AudioLevel {
+        source,
+        volume,
+        muted,
+    }
+}
 
-    log_to!(logger, Info, [GST] => "Setting output resolution to {}x{}", width, height);
-
-    // Before resolution update
-    // pipeline_parts.push(format!(
-    //     "pipewiresrc do-timestamp=true fd={fd} path={path} ! \
-    //     queue max-size-buffers=8 leaky=downstream ! \
-    //     videoconvert ! videoscale ! \
-    //     video/x-raw,format=(string)NV12 ! \
-    //     videorate ! video/x-raw,framerate={fps}/1 ! \
-    //     queue max-size-buffers=8 leaky=downstream ! \
-    //     cudaupload ! nvh264enc bitrate={bitrate} ! \
-    //     h264parse ! queue ! mux.video_0",
-    //     fd = pipewire_fd.as_raw_fd(),
-    //     path = node_id,
-    //     fps = settings.clip_fps,
-    //     bitrate = settings.video_bitrate,
-    // ));
-
-    pipeline_parts.push(format!(
-        "pipewiresrc do-timestamp=true fd={fd} path={path} ! \
-        queue max-size-buffers=8 leaky=downstream ! \
-        videoconvert ! videoscale ! \
-        video/x-raw,width={width},height={height},format=(string)NV12 ! \
-        videorate ! video/x-raw,framerate={fps}/1 ! \
-        queue max-size-buffers=8 leaky=downstream ! \
-        cudaupload ! nvh264enc bitrate={bitrate} ! \
-        h264parse  config-interval=-1 ! queue ! mux.video_0",
-        fd = pipewire_fd.as_raw_fd(),
-        path = node_id,
-        fps = settings.clip_fps,
-        width = width,
-        height = height,
-        bitrate = settings.video_bitrate,
-    ));
+// Sets the volume element of the source's branch, which feeds both the mix and its own track.
+fn apply_audio_level(pipeline_bin: &gst::Bin, level: AudioLevel, logger: &Logger) {
+    let Some(volume) = pipeline_bin.by_name(level.source.element_name()) else {
+        return;
+    };
+    volume.set_property("volume", level.volume as f64 / 100.0);
+    volume.set_property("mute", level.muted);
+    log_to!(logger, Info,
+        [GST] => "Set {} audio to {}%{}",
+        level.source,
+        level.volume,
+        if level.muted { " (muted)" } else { "" }
+    );
+}
 
-    if has_audio {
-        pipeline_parts
-            .push("audiomixer name=mix ! audioconvert ! audio/x-raw,channels=2 ! opusenc ! opusparse ! queue ! mux.audio_0".to_string());
+// Changes one source's level on the running pipeline, `persist` also writes it to
+// settings.json so it survives a reload.
+async fn set_audio(
+    pipeline: &gst::Element,
+    settings: &mut Settings,
+    source: AudioSourceKind,
+    volume: Option<u8>,
+    muted: Option<bool>,
+    persist: bool,
+    logger: &Logger,
+) -> Response {
+    let (current_volume, current_muted) = match source {
+        AudioSourceKind::Mic => (&mut settings.mic_volume, &mut settings.mic_muted),
+        AudioSourceKind::Bg => (&mut settings.bg_volume, &mut settings.bg_muted),
+    };
+    if let Some(volume) = volume {
+        *current_volume = volume;
+    }
+    if let Some(muted) = muted {
+        *current_muted = muted;
+    }
 
-        if settings.include_bg_audio {
-            log_to!(logger, Info,
-                [GST] => "Enabling DESKTOP audio recording for device {}",
-                settings.bg_node_name
-            );
-            match get_pipewire_node_id(&settings.bg_node_name, &logger).await {
-                Ok(bg_node_id) => {
-                    pipeline_parts.push(format!(
-                        "pipewiresrc do-timestamp=true path={bg_node_id} ! \
-                        queue ! \
-                        audio/x-raw,rate=48000,channels=2 ! \
-                        audioconvert ! audioresample ! mix.sink_0",
-                    ));
-                    //queue max-size-buffers=8 ! audioconvert ! audioresample ! mix.sink_1",
-                }
-                Err(e) => {
-                    log_to!(logger, Error, [GST] => "Could not find monitor source '{}': {}. Background audio will not be recorded.", settings.bg_node_name, e);
-                }
-            }
+    let level = audio_level(settings, source);
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
+    apply_audio_level(&pipeline_bin, level, logger);
+
+    if persist {
+        // One write, so the saved volume and mute state can't drift apart.
+        let changes = [
+            (format!("{source}_volume"), json!(level.volume)),
+            (format!("{source}_muted"), json!(level.muted)),
+        ];
+        if let Err(message) = Settings::update_keys(changes).await {
+            log_to!(logger, Error, [DAEMON] => "Failed to persist the {} level: {}", source, message);
+            return DaemonError::PersistFailed { message }.into();
         }
+    }
+    Response::Audio(level)
+}
 
-        if settings.include_mic_audio {
-            log_to!(logger, Info,
-                [GST] => "Enabling MICROPHONE audio recording for device {}",
-                settings.mic_node_name
-            );
-            match get_pipewire_node_id(&settings.mic_node_name, &logger).await {
-                Ok(mic_node_id) => {
-                    pipeline_parts.push(format!(
-                        "pipewiresrc do-timestamp=true path={mic_node_id} ! \
-                        queue ! \
-                        audio/x-raw,rate=48000,channels=2 ! \
-                        audioconvert ! audioresample ! mix.sink_1",
-                    ));
-                    //queue max-size-buffers=8 ! audioconvert ! audioresample ! mix.sink_1",
-                }
-                Err(e) => {
-                    log_to!(logger, Error, [GST] => "Could not find microphone source '{}': {}. Mic audio will not be recorded.", settings.mic_node_name, e);
+// Volumes and the buffer length can be changed on the running pipeline.
+fn apply_in_place(
+    pipeline: &gst::Element,
+    ring_buffer: &Arc<Mutex<RingBuffer>>,
+    old: &Settings,
+    new: &Settings,
+    logger: &Logger,
+) {
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
+
+    for source in [AudioSourceKind::Bg, AudioSourceKind::Mic] {
+        let level = audio_level(new, source);
+        if audio_level(old, source) != level {
+            apply_audio_level(&pipeline_bin, level, logger);
+        }
+    }
+
+    if old.clip_length_s != new.clip_length_s
+        || old.max_buffer_memory_mb != new.max_buffer_memory_mb
+        || old.spill_buffer_to_disk != new.spill_buffer_to_disk
+    {
+        ring_buffer.lock().unwrap().apply_settings(new);
+    }
+}
+
+// Desktop audio records a sink (through its monitor), the mic a source.
+fn audio_device(settings: &Settings, kind: AudioSourceKind) -> (bool, MediaClass, &str) {
+    match kind {
+        AudioSourceKind::Bg => (
+            settings.include_bg_audio,
+            MediaClass::Sink,
+            settings.bg_node_name.as_str(),
+        ),
+        AudioSourceKind::Mic => (
+            settings.include_mic_audio,
+            MediaClass::Source,
+            settings.mic_node_name.as_str(),
+        ),
+    }
+}
+
+// Points the audio branches at the nodes they should record from now, a restarted
+// source keeps its volume element. Both sources are resolved from one snapshot of the
+// graph. Returns whether a source left out of the pipeline because its node was
+// missing can be added back, which takes a rebuild.
+async fn relink_audio(pipeline: &gst::Element, settings: &Settings, logger: &Logger) -> bool {
+    let graph = match audio::query().await {
+        Ok(graph) => graph,
+        Err(e) => {
+            log_to!(logger, Warn, [GST] => "Failed to list audio devices, {:#}", e);
+            return false;
+        }
+    };
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
+    let mut needs_rebuild = false;
+
+    for kind in [AudioSourceKind::Bg, AudioSourceKind::Mic] {
+        let (enabled, class, configured) = audio_device(settings, kind);
+        if !enabled {
+            continue;
+        }
+        let src = pipeline_bin.by_name(&format!("{kind}_src"));
+        // Branches are built pointing at a node id, see AudioSourceConfig::origin.
+        let current = src.as_ref().map(|src| {
+            src.property::<Option<String>>("path")
+                .and_then(|path| path.parse().ok())
+                .unwrap_or_default()
+        });
+
+        match graph.relink(class, configured, settings.follow_default_devices, current) {
+            Relink::Keep => {}
+            Relink::Rebuild { node_id } => {
+                log_to!(logger, Info, [GST] => "Audio device for {} is available again (node {}), rebuilding pipeline.", kind, node_id);
+                needs_rebuild = true;
+            }
+            Relink::Move { node_id } => {
+                let Some(src) = src else { continue };
+                log_to!(logger, Info, [GST] => "Re-linking {} audio to node {}", kind, node_id);
+                let relinked = src.set_state(gst::State::Null).and_then(|_| {
+                    src.set_property("path", node_id.to_string());
+                    src.sync_state_with_parent()
+                        .map_err(|_| gst::StateChangeError)
+                });
+                if let Err(e) = relinked {
+                    log_to!(logger, Error, [GST] => "Failed to re-link {} audio, {:?}", kind, e);
                 }
             }
         }
     }
-    // pipeline_parts.push(
-    //     "audiomixer name=mix ! \
-    //       opusenc ! opusparse ! queue ! mux.audio_0"
-    //         .to_string(),
-    // );
-
-    // if settings.include_desktop_audio {
-    //     log_to!(logger, Info,
-    //         [GST] => "Enabling DESKTOP audio recording for device {}",
-    //         DESKTOP_AUDIO_DEVICE_ID
-    //     );
-    //     pipeline_parts.push(format!(
-    //         "pipewiresrc do-timestamp=true path={DESKTOP_AUDIO_DEVICE_ID} ! \
-    //           audio/x-raw ! queue ! audioconvert ! audioresample ! mix.sink_0"
-    //     ));
-    // }
-
-    // if settings.include_mic_audio {
-    //     log_to!(logger, Info,
-    //         [GST] => "Enabling MICROPHONE audio recording for device {}",
-    //         MIC_AUDIO_DEVICE_ID
-    //     );
-    //     pipeline_parts.push(format!(
-    //         "pipewiresrc do-timestamp=true path={MIC_AUDIO_DEVICE_ID} ! \
-    //           audio/x-raw ! queue ! audioconvert ! audioresample ! mix.sink_1"
-    //     ));
-    // }
-
-    // pipeline_parts.push("matroskamux name=mux streamable=true ! appsink name=sink".to_string());
-
-    // pipeline_parts.push(format!(
-    //     "pipewiresrc do-timestamp=true fd={fd} path={path} ! "
-    //     // REMOVED rigid format request. Let videoconvert negotiate automatically.
-    //     // This is the key to fixing the "no more input formats" error.
-    //     "videoconvert ! "
-    //     "videoscale ! "
-    //     "video/x-raw,format=NV12,framerate={fps}/1 ! "
-    //     // Queues for synchronization and buffering.
-    //     "queue max-size-time=3000000000 leaky=2 ! "
-    //     "cudaupload ! nvh264enc bitrate={bitrate} ! h264parse ! "
-    //     "queue max-size-time=3000000000 leaky=2 ! "
-    //     "mux.video_0",
-    //     fd = pipewire_fd.as_raw_fd(),
-    //     path = node_id,
-    //     fps = settings.clip_fps,
-    //     bitrate = settings.video_bitrate
-    // ));
-
-    // if has_audio {
-    //     let mut audio_pipeline_parts = Vec::new();
-    //     audio_pipeline_parts.push(
-    //         "audiomixer name=mix ! opusenc ! opusparse ! queue max-size-time=3000000000 ! mux.audio_0"
-    //             .to_string(),
-    //     );
-    //     if settings.include_desktop_audio {
-    //         log_to!(logger, Info, [GST] => "Enabling DESKTOP audio recording for device {}", DESKTOP_AUDIO_DEVICE_ID);
-    //         audio_pipeline_parts.push(format!(
-    //             "pipewiresrc do-timestamp=true path={DESKTOP_AUDIO_DEVICE_ID} ! audio/x-raw ! queue ! audioconvert ! audioresample ! mix.sink_0"
-    //         ));
-    //     }
-    //     if settings.include_mic_audio {
-    //         log_to!(logger, Info, [GST] => "Enabling MICROPHONE audio recording for device {}", MIC_AUDIO_DEVICE_ID);
-    //         audio_pipeline_parts.push(format!(
-    //             "pipewiresrc do-timestamp=true path={MIC_AUDIO_DEVICE_ID} ! audio/x-raw ! queue ! audioconvert ! audioresample ! mix.sink_1"
-    //         ));
-    //     }
-    //     pipeline_parts.push(audio_pipeline_parts.join(" "));
-    // }
-
-    let pipeline_str = pipeline_parts.join(" ");
+    needs_rebuild
+}
 
-    log_to!(logger, Info, [GST] => "Parsing pipeline: {}", pipeline_str);
-    let pipeline = gst::parse::launch(&pipeline_str).expect("Failed to parse pipeline");
+// pw-dump only runs while there is something to pick up: a source following the default
+// device, or one left out of the pipeline because its node was missing.
+fn audio_needs_polling(pipeline: &gst::Element, settings: &Settings) -> bool {
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
+    [AudioSourceKind::Bg, AudioSourceKind::Mic]
+        .into_iter()
+        .any(|kind| {
+            let (enabled, _, configured) = audio_device(settings, kind);
+            enabled
+                && (settings.follow_default_devices
+                    || configured.is_empty()
+                    || pipeline_bin.by_name(&format!("{kind}_src")).is_none())
+        })
+}
+
+// What the pipeline records from right now, audio nodes as last re-linked.
+fn active_sources(
+    pipeline: &gst::Element,
+    capture: &Capture<'_>,
+    settings: &Settings,
+) -> Vec<SourceStatus> {
+    let video_nodes: Vec<Option<u32>> = if capture.is_test() {
+        vec![None]
+    } else {
+        capture.streams.iter().map(|s| Some(s.node_id)).collect()
+    };
+    let mut sources: Vec<SourceStatus> = video_nodes
+        .into_iter()
+        .map(|node_id| SourceStatus {
+            name: String::from("video"),
+            node_id,
+            muted: false,
+        })
+        .collect();
 
     let pipeline_bin = pipeline
         .clone()
         .dynamic_cast::<gst::Bin>()
         .expect("Pipeline should be a Bin");
+    for source in [AudioSourceKind::Bg, AudioSourceKind::Mic] {
+        let Some(src) = pipeline_bin.by_name(&format!("{source}_src")) else {
+            continue;
+        };
+        // Test tones have no node to point at.
+        let node_id = src
+            .find_property("path")
+            .and_then(|_| src.property::<Option<String>>("path"))
+            .and_then(|path| path.parse().ok());
+        sources.push(SourceStatus {
+            name: source.to_string(),
+            node_id,
+            muted: audio_level(settings, source).muted,
+        });
+    }
+    sources
+}
 
-    if settings.include_bg_audio {
-        let desktop_vol = settings.bg_volume as f64 / 100.0;
-        let sink_0_pad = pipeline_bin
-            .by_name("mix")
-            .expect("Failed to get mixer")
-            .static_pad("sink_0")
-            .expect("Failed to get mixer sink_0");
-        sink_0_pad.set_property("volume", desktop_vol);
-        log_to!(logger, Info, [GST] => "Set desktop audio volume to {}", desktop_vol);
+// The enabled audio sources whose node could be found, missing ones are left out.
+async fn audio_inputs(
+    settings: &Settings,
+    capture: &Capture<'_>,
+    logger: &Logger,
+) -> Vec<(AudioSourceKind, AudioInput)> {
+    let enabled: Vec<AudioSourceKind> = [AudioSourceKind::Bg, AudioSourceKind::Mic]
+        .into_iter()
+        .filter(|&kind| audio_device(settings, kind).0)
+        .collect();
+    // Test captures record tones, there are no devices to look up.
+    if capture.is_test() {
+        return enabled
+            .into_iter()
+            .map(|kind| (kind, AudioInput::Test))
+            .collect();
+    }
+    if enabled.is_empty() {
+        return Vec::new();
     }
 
-    if settings.include_mic_audio {
-        let mic_vol = settings.mic_volume as f64 / 100.0;
-        let sink_1_pad = pipeline_bin
-            .by_name("mix")
-            .expect("Failed to get mixer")
-            .static_pad("sink_1")
-            .expect("Failed to get mixer sink_1");
-        sink_1_pad.set_property("volume", mic_vol);
-        log_to!(logger, Info, [GST] => "Set mic audio volume to {}", mic_vol);
+    let graph = match audio::query().await {
+        Ok(graph) => graph,
+        Err(e) => {
+            log_to!(logger, Error, [GST] => "Failed to list audio devices, {:#}. No audio will be recorded.", e);
+            return Vec::new();
+        }
+    };
+    let mut inputs = Vec::new();
+    for kind in enabled {
+        let (_, class, configured) = audio_device(settings, kind);
+        match graph.resolve(class, configured, settings.follow_default_devices) {
+            Some(node) => {
+                log_to!(logger, Info, [GST] => "Enabling {} audio recording for device {} (node {})", kind, node.node_name, node.id);
+                inputs.push((kind, AudioInput::PipeWire { node_id: node.id }));
+            }
+            None => {
+                log_to!(logger, Error, [GST] => "Could not find {} audio source '{}'. It will not be recorded.", kind, configured);
+            }
+        }
+    }
+    inputs
+}
+
+async fn build_pipeline(
+    settings: &Settings,
+    capture: &Capture<'_>,
+    ring_buffer: &Arc<Mutex<RingBuffer>>,
+    dropped_frames: &Arc<AtomicU64>,
+    logger: &Logger,
+) -> Result<Recording, Box<dyn Error>> {
+    let streams = &capture.streams;
+    if streams.len() > 1 {
+        log_to!(logger, Info, [GST] => "Compositing {} streams: {:?}", streams.len(), streams);
+    }
+    let video_input = match &capture.pipewire_fd {
+        Some(fd) => VideoInput::PipeWire {
+            fd: fd.as_raw_fd(),
+            streams: streams.clone(),
+            draw_cursor: capture.cursor_mode == CursorMode::Metadata,
+        },
+        None => VideoInput::Test,
+    };
+
+    let inputs = audio_inputs(settings, capture, logger).await;
+    let config = PipelineConfig::from_settings(settings, video_input, &inputs, logger)?;
+    if capture.cursor_mode == CursorMode::Metadata && !config.draws_cursor() {
+        log_to!(logger, Warn, [GST] => "Cursor metadata can't be drawn on composited monitors, recording without a cursor.");
+    }
+    if let Some(audio) = config.audio.as_ref().filter(|a| a.separate_tracks) {
+        let titles = audio.track_titles();
+        log_to!(logger, Info, [GST] => "Recording {} separate audio tracks: {:?}", titles.len(), titles);
     }
+    let pipeline_str = config.launch_string();
+
+    log_to!(logger, Info, [GST] => "Parsing pipeline: {}", pipeline_str);
+    let pipeline = gst::parse::launch(&pipeline_str)?;
+
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
 
     let appsink = pipeline_bin
         .by_name("sink")
@@ -385,23 +518,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     appsink.set_property("drop", true);
     appsink.set_property("max-buffers", 5_u32);
 
-    let rb_clone = ring_buffer.clone();
+    // The streaming thread only hands the buffer over, the ring is filled on the runtime
+    // so a save holding the lock can never make capture drop a chunk.
+    let (frame_tx, frame_rx) = channel::<gst::Buffer>(FRAME_QUEUE_SIZE);
+    let dropped_for_callback = dropped_frames.clone();
     let logger_clone_for_callback = logger.clone();
     appsink.set_callbacks(
         gstreamer_app::AppSinkCallbacks::builder()
             .new_sample(move |sink| {
                 let sample = sink.pull_sample().map_err(|_| gst::FlowError::Eos)?;
-                let buffer = sample.buffer().ok_or(gst::FlowError::Error)?;
-                let pts = buffer.pts();
-                let is_header = buffer.flags().contains(gst::BufferFlags::HEADER);
-                let map = buffer.map_readable().map_err(|_| gst::FlowError::Error)?;
-                let data = map.as_slice().to_vec();
-
-                log_to!(logger_clone_for_callback, Warn, [DEBUG] => "Writing chunk to file. PTS: {:?}, Size: {}, IsHeader: {}", pts, data.len(), is_header);
-                if let Ok(mut rb) = rb_clone.try_lock() {
-                    rb.push(data, is_header, pts);
-                } else {
-                    log_to!(logger_clone_for_callback, Warn, [RING] => "Failed to acquire lock on ring buffer, frame dropped.");
+                let buffer = sample.buffer_owned().ok_or(gst::FlowError::Error)?;
+
+                if frame_tx.try_send(buffer).is_err() {
+                    let dropped = dropped_for_callback.fetch_add(1, Ordering::Relaxed) + 1;
+                    log_to!(logger_clone_for_callback, Warn, [RING] => "Frame queue full, chunk dropped ({} total).", dropped);
                 }
 
                 Ok(gst::FlowSuccess::Ok)
@@ -409,15 +539,540 @@ async fn main() -> Result<(), Box<dyn Error>> {
             .build(),
     );
 
-    tokio::spawn(handle_bus_messages(
+    let cursor = match (
+        pipeline_bin.by_name("cursor"),
+        pipeline_bin.by_name("screen"),
+        &capture.pipewire_fd,
+    ) {
+        (Some(overlay), Some(screen), Some(fd)) => {
+            log_to!(logger, Info, [GST] => "Drawing the cursor from PipeWire metadata");
+            let appsrc = screen
+                .dynamic_cast::<AppSrc>()
+                .expect("Failed to cast to appsrc");
+            let fd = fd.try_clone()?;
+            Some(CursorOverlay::attach(
+                &overlay,
+                appsrc,
+                fd,
+                streams[0].node_id,
+                logger,
+            ))
+        }
+        _ => None,
+    };
+
+    let ring_task = tokio::spawn(fill_ring_buffer(frame_rx, ring_buffer.clone()));
+
+    let bus_task = tokio::spawn(handle_bus_messages(
         pipeline.clone().dynamic_cast::<gst::Pipeline>().unwrap(),
         logger.clone(),
     ));
 
+    Ok(Recording {
+        pipeline,
+        config,
+        bus_task,
+        ring_task,
+        cursor,
+    })
+}
+
+// Shifts the running time of everything reaching the muxer, so its clusters carry on
+// from `start` instead of zero.
+fn offset_muxer(pipeline: &gst::Element, start: gst::ClockTime) {
+    let pipeline_bin = pipeline
+        .clone()
+        .dynamic_cast::<gst::Bin>()
+        .expect("Pipeline should be a Bin");
+    let Some(mux) = pipeline_bin.by_name("mux") else {
+        return;
+    };
+    for pad in mux.sink_pads() {
+        pad.set_offset(start.nseconds() as i64);
+    }
+}
+
+// Stops the current pipeline and starts `next` in its place. The buffer carries over
+// when `next` writes the same tracks with the same caps, otherwise it starts empty.
+async fn swap_pipeline(
+    recording: &mut Recording,
+    next: Recording,
+    ring_buffer: &Arc<Mutex<RingBuffer>>,
+    settings: &Settings,
+    logger: &Logger,
+) {
+    recording.bus_task.abort();
+    if let Err(e) = recording.pipeline.set_state(gst::State::Null) {
+        log_to!(logger, Error, [GST] => "Failed to set old pipeline to null, {:?}", e);
+    }
+    // Stops the old screen stream before the new one connects to the same node.
+    drop(recording.cursor.take());
+    // Wait for the old writer so none of its queued chunks land after the reset.
+    recording.ring_task.abort();
+    let _ = (&mut recording.ring_task).await;
+
+    {
+        let mut rb = ring_buffer.lock().unwrap();
+        let resume_at = rb
+            .resume_point()
+            .filter(|_| recording.config.same_output(&next.config));
+        match resume_at {
+            Some(start) => {
+                offset_muxer(&next.pipeline, start);
+                rb.resume();
+            }
+            // Chunks from the old muxer can't be joined with the new one's header.
+            None => rb.reset(),
+        }
+        rb.apply_settings(settings);
+    }
+
+    if let Err(err) = next.pipeline.set_state(gst::State::Playing) {
+        log_to!(logger, Error, [GST] => "Failed to set new pipeline to playing: {:?}", err);
+    }
+
+    *recording = next;
+}
+
+// Builds a fresh pipeline after the current one failed, with unchanged settings.
+async fn restart_pipeline(
+    recording: &mut Recording,
+    settings: &Settings,
+    capture: &Capture<'_>,
+    ring_buffer: &Arc<Mutex<RingBuffer>>,
+    dropped_frames: &Arc<AtomicU64>,
+    logger: &Logger,
+) -> Result<(), String> {
+    let next = build_pipeline(settings, capture, ring_buffer, dropped_frames, logger)
+        .await
+        .map_err(|e| e.to_string())?;
+    swap_pipeline(recording, next, ring_buffer, settings, logger).await;
+    Ok(())
+}
+
+// Starts a screencast of the configured source, the portal may ask the user to pick it.
+// Without a portal proxy (the test backend) this hands out the synthetic source.
+async fn open_capture<'a>(
+    proxy: Option<&Screencast<'a>>,
+    settings: &Settings,
+    logger: &Logger,
+) -> anyhow::Result<Capture<'a>> {
+    let Some(proxy) = proxy else {
+        return Ok(Capture::test());
+    };
+    let source: CaptureSource = settings.capture_source.parse()?;
+    let wanted_cursor = parse_cursor_mode(&settings.cursor_mode)?;
+    let available_cursors = proxy
+        .available_cursor_modes()
+        .await
+        .context("Failed to query cursor modes")?;
+    let cursor_mode = pick_cursor_mode(wanted_cursor, available_cursors);
+    if cursor_mode != wanted_cursor {
+        log_to!(logger, Warn,
+            [ASH] => "Cursor mode {:?} is not supported by the portal (available: {:?}), using {:?}.",
+            wanted_cursor,
+            available_cursors,
+            cursor_mode
+        );
+    }
+    let restore_token = load_restore_token().await;
+    log_to!(logger, Info,
+        [ASH] => "Requesting {} capture (monitor: {:?}, restoring: {})",
+        source,
+        settings.capture_monitor,
+        restore_token.is_some()
+    );
+    let session = proxy
+        .create_session()
+        .await
+        .context("Failed to create screencast session")?;
+    proxy
+        .select_sources(
+            &session,
+            cursor_mode,
+            enumflags2::BitFlags::from(source.source_type()),
+            source.multiple(&settings.capture_monitor),
+            restore_token.as_deref(),
+            PersistMode::ExplicitlyRevoked,
+        )
+        .await
+        .context("Failed to select sources")?;
+
+    log_to!(logger, Info, [ASH] => "Starting screencast session");
+    let response = proxy
+        .start(&session, None)
+        .await
+        .context("Failed to start screencast session")?
+        .response()
+        .context("Failed to get screencast response")?;
+    log_to!(logger, Info, [ASH] => "Streams: {:?}", response.streams());
+    // Tokens are single use, the portal hands out a new one on every start.
+    if let Some(token) = response.restore_token() {
+        if let Err(e) = save_restore_token(token).await {
+            log_to!(logger, Warn, [ASH] => "{:#}", e);
+        }
+    }
+    let streams = pick_streams(
+        source,
+        &settings.capture_monitor,
+        response.streams(),
+        logger,
+    )?;
+
+    let pipewire_fd = proxy
+        .open_pipe_wire_remote(&session)
+        .await
+        .context("Failed to open pipewire remote")?;
+    log_to!(logger, Info, [ASH] => "Pipewire fd: {:?}", pipewire_fd.as_raw_fd());
+
+    Ok(Capture {
+        session: Some(session),
+        pipewire_fd: Some(pipewire_fd),
+        streams,
+        cursor_mode,
+    })
+}
+
+// settings.json as it is now, checked as a whole before any of it is applied.
+async fn load_checked_settings(logger: &Logger) -> Result<Settings, String> {
+    let new = Settings::load().await.map_err(|e| {
+        log_to!(logger, Error, [DAEMON] => "Failed to load settings for reload: {}", e);
+        e.to_string()
+    })?;
+    check_settings(&new).map_err(|e| {
+        log_to!(logger, Error, [DAEMON] => "Not reloading invalid settings: {:#}", e);
+        format!("{e:#}")
+    })?;
+    Ok(new)
+}
+
+// Closes the screencast and asks the portal for a new one. When that fails the old
+// session is gone too, so the pipeline counts as failed and the supervisor brings it
+// back with a new session.
+async fn reopen_capture<'a>(
+    capture: &mut Capture<'a>,
+    proxy: Option<&Screencast<'a>>,
+    settings: &Settings,
+    supervisor: &mut Supervisor,
+    logger: &Logger,
+) -> Result<(), String> {
+    capture.close(logger).await;
+    match open_capture(proxy, settings, logger).await {
+        Ok(next) => {
+            *capture = next;
+            supervisor.capture_lost = false;
+            Ok(())
+        }
+        Err(e) => {
+            let message = format!("{e:#}");
+            log_to!(logger, Error, [ASH] => "{}", message);
+            supervisor.capture_lost = true;
+            if supervisor.is_healthy() {
+                supervisor.failed(message.clone());
+                send_status_to_gui(
+                    settings.gui_socket_path.clone(),
+                    String::from("Degraded"),
+                    logger,
+                );
+            } else {
+                supervisor.schedule_retry();
+            }
+            Err(message)
+        }
+    }
+}
+
+// Swaps in a pipeline built from `new` while keeping the portal session and fd.
+async fn reload_pipeline(
+    recording: &mut Recording,
+    settings: &mut Settings,
+    new: Settings,
+    capture: &Capture<'_>,
+    ring_buffer: &Arc<Mutex<RingBuffer>>,
+    dropped_frames: &Arc<AtomicU64>,
+    logger: &Logger,
+) -> Result<(), String> {
+    if !needs_rebuild(settings, &new) {
+        apply_in_place(&recording.pipeline, ring_buffer, settings, &new, logger);
+        *settings = new;
+        log_to!(logger, Info, [DAEMON] => "Settings reloaded without rebuilding the pipeline.");
+        return Ok(());
+    }
+
+    log_to!(logger, Info, [DAEMON] => "Rebuilding pipeline with new settings...");
+    let next = match build_pipeline(&new, capture, ring_buffer, dropped_frames, logger).await {
+        Ok(next) => next,
+        Err(e) => {
+            log_to!(logger, Error, [GST] => "Failed to build new pipeline, keeping the old one: {}", e);
+            return Err(e.to_string());
+        }
+    };
+
+    swap_pipeline(recording, next, ring_buffer, &new, logger).await;
+    *settings = new;
+    log_to!(logger, Info, [DAEMON] => "Pipeline rebuilt.");
+    Ok(())
+}
+
+// Reads requests line by line and answers each one once the main loop is done with it.
+async fn handle_client(stream: UnixStream, tx: Sender<DaemonCommand>, logger: Logger) {
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let mut buf = String::new();
+    loop {
+        buf.clear();
+        match reader.read_line(&mut buf).await {
+            Ok(0) => break,
+            Ok(_) => {
+                let msg = buf.trim();
+                if msg.is_empty() {
+                    continue;
+                }
+                log_to!(logger, Info, [UNIX] => "Message received: {}", msg);
+
+                let response = match parse_request(msg) {
+                    Ok(request) => {
+                        let (reply_tx, reply_rx) = oneshot::channel();
+                        if tx.send((request, reply_tx)).await.is_err() {
+                            log_to!(logger, Error, [UNIX] => "Receiver dropped, cannot send message.");
+                            break;
+                        }
+                        match reply_rx.await {
+                            Ok(response) => response,
+                            Err(_) => break,
+                        }
+                    }
+                    Err(error) => {
+                        log_to!(logger, Warn, [UNIX] => "Rejected message: {}", error);
+                        error.into()
+                    }
+                };
+
+                if let Err(e) = write_half
+                    .write_all(encode_response(response).as_bytes())
+                    .await
+                {
+                    log_to!(logger, Warn, [UNIX] => "Failed to reply to client: {}", e);
+                    break;
+                }
+            }
+            Err(e) => {
+                log_to!(logger, Error, [UNIX] => "Failed to read from socket: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+// Saves run one after another, each on the snapshot taken when it was requested.
+async fn run_save_queue(
+    mut jobs: Receiver<SaveJob>,
+    pending: Arc<AtomicUsize>,
+    last_save: Arc<Mutex<Option<SaveResult>>>,
+    logger: Logger,
+) {
+    while let Some(job) = jobs.recv().await {
+        let result = save_clip(job.job_id, job.chunks, &job.settings, &logger).await;
+        *last_save.lock().unwrap() = Some(SaveResult {
+            finished_at: chrono::Local::now(),
+            path: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+        let response = match result {
+            Ok(path) => Response::Saved { path },
+            Err(error) => error.into(),
+        };
+        let _ = job.reply.send(response);
+        pending.fetch_sub(1, Ordering::SeqCst);
+        log_to!(logger, Info, [FFMPEG] => "[JOB {}] Task finished.", job.job_id);
+    }
+}
+
+// Remuxes the buffered Matroska chunks into the output container.
+async fn save_clip(
+    job_id: usize,
+    saved_chunks: Vec<Chunk>,
+    settings: &Settings,
+    logger: &Logger,
+) -> Result<PathBuf, DaemonError> {
+    let container: Container = settings
+        .output_container
+        .parse()
+        .map_err(|e: anyhow::Error| DaemonError::SaveFailed {
+            message: e.to_string(),
+        })?;
+    log_to!(logger, Info, [FFMPEG] => "[JOB {}] Remuxing {} Matroska chunks into {}.", job_id, saved_chunks.len(), container);
+
+    let home_dir = env::var("HOME").expect("HOME not set");
+    let output_dir = std::path::Path::new(&home_dir).join(&settings.save_path_from_home_string);
+    create_dir_all(&output_dir).map_err(|e| DaemonError::SaveFailed {
+        message: format!("Failed to create output directory: {e}"),
+    })?;
+    let output_filename = output_dir.join(format!(
+        "{}.{}",
+        chrono::Local::now().format(&settings.clip_name_formatting),
+        container.extension()
+    ));
+
+    let output = output_filename.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        remux_matroska(
+            saved_chunks.into_iter().map(Chunk::into_buffer),
+            &output,
+            container,
+        )
+    })
+    .await
+    .unwrap_or_else(|e| Err(RemuxError::Pipeline(e.to_string())));
+
+    match result {
+        Ok(()) => {
+            log_to!(logger, Info, [FFMPEG] => "[JOB {}] Done! Saved to {:?}", job_id, output_filename);
+            send_status_to_gui(
+                settings.gui_socket_path.clone(),
+                String::from("Saved!"),
+                logger,
+            );
+            let gui_path = settings.gui_socket_path.clone();
+            let preview_logger = logger.clone();
+            let preview_source = output_filename.clone();
+            tokio::spawn(async move {
+                if let Err(e) = generate_preview_clip(
+                    &preview_source,
+                    &Settings::config_path().join("wayclip").join("previews"),
+                )
+                .await
+                {
+                    log_to!(&preview_logger, Error, [FFMPEG] => "Failed to generate preview, {}", e)
+                };
+                send_status_to_gui(gui_path, String::from("Saved!"), &preview_logger);
+            });
+            Ok(output_filename)
+        }
+        Err(e) => {
+            log_to!(logger, Error, [FFMPEG] => "[JOB {}] Remux failed: {}", job_id, e);
+            send_status_to_gui(
+                settings.gui_socket_path.clone(),
+                String::from("Error during saving"),
+                logger,
+            );
+            Err(DaemonError::SaveFailed {
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let started_at = Instant::now();
+    let mut settings = Settings::load().await?;
+    let log_dir = "/tmp/wayclip";
+    create_dir_all(log_dir).expect("Failed to create log directory");
+    let timestamp = chrono::Local::now().format("%Y-%m-%d_%H-%M-%S").to_string();
+
+    let logger = Logger::new(format!("{log_dir}/wayclip-{timestamp}.log"))
+        .expect("Failed to create daemon logger");
+
+    log_to!(logger, Info, [DAEMON] => "Starting...");
+    log_to!(logger, Debug, [DAEMON] => "Settings loaded: {:?}", settings);
+
+    env::set_var(
+        "GST_DEBUG",
+        "pipewiresrc:4,audiomixer:4,audioconvert:4,audioresample:4,matroskamux:4,3",
+    );
+    gst::init().expect("Failed to init gstreamer");
+
+    // Taken before touching the socket, which is then known to be left over from a
+    // daemon that is gone. Released when main returns.
+    let _pid_lock = match PidLock::acquire(&settings.daemon_pid_path) {
+        Ok(lock) => {
+            if let Some(pid) = lock.stale_pid {
+                log_to!(logger, Warn, [DAEMON] => "Previous daemon (PID {}) did not shut down cleanly, taking over its PID file.", pid);
+            }
+            lock
+        }
+        Err(e) => {
+            log_to!(logger, Error, [DAEMON] => "{:#}", e);
+            eprintln!("{e:#}");
+            exit(1);
+        }
+    };
+
+    if metadata(&settings.daemon_socket_path).is_ok() {
+        if let Err(e) = remove_file(&settings.daemon_socket_path) {
+            log_to!(logger, Error, [UNIX] => "Failed to remove existing daemon socket file: {}", e);
+            exit(1);
+        }
+    }
+
+    send_status_to_gui(
+        settings.gui_socket_path.clone(),
+        String::from("Starting"),
+        &logger,
+    );
+
+    // The PID lock already created the runtime dir when both are in it.
+    if let Err(e) = prepare_runtime_dir(&settings.daemon_socket_path) {
+        log_to!(logger, Error, [UNIX] => "Failed to prepare the daemon socket directory, {}", e);
+        exit(1);
+    }
+    let listener =
+        UnixListener::bind(&settings.daemon_socket_path).expect("Failed to bind unix socket");
+
+    if std::env::var("DESKTOP_SESSION") == Ok("hyprland".to_string()) {
+        setup_hyprland(&logger).await;
+    } else {
+        log_to!(logger, Info, [HYPR] => "Not using hyprland. Please bind Alt+C to trigger save.");
+    }
+
+    let proxy = match CaptureBackend::from_settings(&settings)? {
+        CaptureBackend::Portal => Some(
+            Screencast::new()
+                .await
+                .expect("Failed to create screencast proxy"),
+        ),
+        CaptureBackend::Test => {
+            log_to!(logger, Info, [DAEMON] => "Using the test capture backend, no screencast or audio devices are recorded.");
+            None
+        }
+    };
+    let mut capture = match open_capture(proxy.as_ref(), &settings, &logger).await {
+        Ok(capture) => capture,
+        Err(e) => {
+            log_to!(logger, Error, [ASH] => "{:#}", e);
+            exit(1);
+        }
+    };
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let ring_buffer = Arc::new(Mutex::new(RingBuffer::new(&settings, &logger)));
+    let dropped_frames = Arc::new(AtomicU64::new(0));
+    let pending_saves = Arc::new(AtomicUsize::new(0));
+    let last_save = Arc::new(Mutex::new(None));
+    let (save_tx, save_rx) = channel::<SaveJob>(SAVE_QUEUE_SIZE);
+    tokio::spawn(run_save_queue(
+        save_rx,
+        pending_saves.clone(),
+        last_save.clone(),
+        logger.clone(),
+    ));
+
+    let mut recording =
+        match build_pipeline(&settings, &capture, &ring_buffer, &dropped_frames, &logger).await {
+            Ok(recording) => recording,
+            Err(e) => {
+                log_to!(logger, Error, [GST] => "Failed to build pipeline: {}", e);
+                exit(1);
+            }
+        };
+
     log_to!(logger, Info, [GST] => "Setting pipeline to playing for constant recording");
-    if let Err(err) = pipeline.set_state(gst::State::Playing) {
+    if let Err(err) = recording.pipeline.set_state(gst::State::Playing) {
         log_to!(logger, Error, [GST] => "Failed to set pipeline to playing: {:?}", err);
-        pipeline
+        recording
+            .pipeline
             .set_state(gst::State::Null)
             .expect("Failed to set pipeline to null after error");
         exit(1);
@@ -428,40 +1083,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
         &logger,
     );
 
-    let (tx, mut rx): (Sender<String>, Receiver<String>) = channel(32);
+    let (tx, mut rx): (Sender<DaemonCommand>, Receiver<DaemonCommand>) = channel(32);
 
     let listener_logger = logger.clone();
     tokio::spawn(async move {
         loop {
-            if let Ok((stream, _)) = listener.accept().await {
-                let mut reader = BufReader::new(stream);
-                let mut buf = String::new();
-                loop {
-                    buf.clear();
-                    match reader.read_line(&mut buf).await {
-                        Ok(0) => break,
-                        Ok(_) => {
-                            let msg = buf.trim().to_string();
-                            log_to!(listener_logger, Info, [UNIX] => "Message received: {}", msg);
-                            if tx.send(msg).await.is_err() {
-                                log_to!(listener_logger, Error, [UNIX] => "Receiver dropped, cannot send message.");
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            log_to!(listener_logger, Error, [UNIX] => "Failed to read from socket: {}", e);
-                            break;
-                        }
+            match listener.accept().await {
+                Ok((stream, _)) => match is_same_user(&stream) {
+                    Ok(true) => {
+                        tokio::spawn(handle_client(stream, tx.clone(), listener_logger.clone()));
+                    }
+                    Ok(false) => {
+                        log_to!(listener_logger, Warn, [UNIX] => "Rejected connection from another user.");
+                    }
+                    Err(e) => {
+                        log_to!(listener_logger, Warn, [UNIX] => "Rejected connection, failed to read peer credentials: {}", e);
                     }
+                },
+                Err(e) => {
+                    log_to!(listener_logger, Error, [UNIX] => "Failed to accept connection: {}", e);
                 }
             }
         }
     });
 
+    let mut supervisor = Supervisor::new();
     let job_id_counter = Arc::new(AtomicUsize::new(1));
-    let mut last_save_time = Instant::now() - SAVE_COOLDOWN;
     let mut term_signal =
         signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut audio_poll = tokio::time::interval(AUDIO_POLL_INTERVAL);
+    audio_poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
     loop {
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
@@ -473,126 +1124,213 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 break;
             },
 
-            Some(msg) = rx.recv() => {
-                match msg.as_str() {
-                    "save" => {
-                        if last_save_time.elapsed() < SAVE_COOLDOWN {
-                            log_to!(logger, Warn, [UNIX] => "Ignoring save request: Cooldown active.");
+            result = &mut recording.bus_task, if supervisor.is_healthy() => {
+                let reason = result.ok().flatten().unwrap_or_else(|| String::from("Bus handler stopped"));
+                supervisor.failed(reason.clone());
+                log_to!(logger, Error, [DAEMON] => "Pipeline failed ({}), restarting in {:?}.", reason, supervisor.retry_at - Instant::now());
+                send_status_to_gui(settings.gui_socket_path.clone(), String::from("Degraded"), &logger);
+            },
+            _ = tokio::time::sleep_until(supervisor.retry_at.into()), if !supervisor.is_healthy() => {
+                log_to!(logger, Info, [DAEMON] => "Restarting pipeline (attempt {}).", supervisor.attempts);
+                if supervisor.should_reopen_portal() {
+                    log_to!(logger, Warn, [ASH] => "Stream still failing, asking the portal for a new one.");
+                    if reopen_capture(&mut capture, proxy.as_ref(), &settings, &mut supervisor, &logger).await.is_err() {
+                        continue;
+                    }
+                }
+                match restart_pipeline(&mut recording, &settings, &capture, &ring_buffer, &dropped_frames, &logger).await {
+                    Ok(()) => {
+                        supervisor.recovered();
+                        log_to!(logger, Info, [DAEMON] => "Pipeline restarted ({} restarts so far).", supervisor.restarts);
+                        send_status_to_gui(settings.gui_socket_path.clone(), String::from("Recording"), &logger);
+                    }
+                    Err(e) => {
+                        supervisor.schedule_retry();
+                        log_to!(logger, Error, [DAEMON] => "Failed to restart pipeline: {}", e);
+                    }
+                }
+            },
+
+            _ = audio_poll.tick(), if supervisor.is_healthy()
+                && !capture.is_test()
+                && audio_needs_polling(&recording.pipeline, &settings) => {
+                if relink_audio(&recording.pipeline, &settings, &logger).await {
+                    if let Err(e) = restart_pipeline(&mut recording, &settings, &capture, &ring_buffer, &dropped_frames, &logger).await {
+                        log_to!(logger, Error, [DAEMON] => "Failed to rebuild pipeline for returning audio device: {}", e);
+                    }
+                }
+            },
+
+            Some((request, reply)) = rx.recv() => {
+                match request {
+                    Request::Status => {
+                        let state = if supervisor.is_healthy() {
+                            format!("{:?}", recording.pipeline.current_state())
+                        } else {
+                            String::from("Degraded")
+                        };
+                        let (buffered_ms, buffered_bytes, bitrate_kbps) = {
+                            let rb = ring_buffer.lock().unwrap();
+                            (
+                                rb.buffered_duration().mseconds(),
+                                rb.buffered_bytes() as u64,
+                                rb.recent_bitrate_kbps(STATUS_BITRATE_WINDOW),
+                            )
+                        };
+                        // The save being remuxed counts as pending but not as queued.
+                        let pending = pending_saves.load(Ordering::SeqCst);
+                        let _ = reply.send(Response::Status(DaemonStatus {
+                            state,
+                            is_saving: pending > 0,
+                            queued_saves: pending.saturating_sub(1),
+                            dropped_frames: dropped_frames.load(Ordering::Relaxed),
+                            restarts: supervisor.restarts,
+                            error: supervisor.failure.clone(),
+                            uptime_ms: started_at.elapsed().as_millis() as u64,
+                            buffered_ms,
+                            buffered_bytes,
+                            bitrate_kbps,
+                            sources: active_sources(&recording.pipeline, &capture, &settings),
+                            last_save: last_save.lock().unwrap().clone(),
+                        }));
+                    }
+                    Request::Save { last_ms } => {
+                        if last_ms == Some(0) {
+                            let _ = reply.send(DaemonError::InvalidRequest { message: String::from("save duration must be above zero") }.into());
                             continue;
                         }
+                        let last = last_ms.map(gst::ClockTime::from_mseconds);
+
                         send_status_to_gui(settings.gui_socket_path.clone(), String::from("Saving clip..."), &logger);
+                        let job_id = job_id_counter.fetch_add(1, Ordering::SeqCst);
+                        match last {
+                            Some(last) => log_to!(logger, Info, [UNIX] => "[JOB {}] Save command received for the last {}, taking snapshot.", job_id, last),
+                            None => log_to!(logger, Info, [UNIX] => "[JOB {}] Save command received, taking snapshot.", job_id),
+                        }
 
-                        if is_saving.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
-                            last_save_time = Instant::now();
-                            let job_id = job_id_counter.fetch_add(1, Ordering::SeqCst);
-                            log_to!(logger, Info, [UNIX] => "[JOB {}] Save command received, starting process.", job_id);
-
-                            let wait_ms = 1000u64;
-                            let mut waited = 0u64;
-                            let saved_chunks = loop {
-                                let chunks = {
-                                    let mut rb = ring_buffer.lock().unwrap();
-                                    rb.get_and_clear()
-                                };
-                                if !chunks.is_empty() {
-                                    break chunks;
-                                }
-                                if waited >= wait_ms {
-                                    break Vec::new();
-                                }
-                                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                                waited += 50;
+                        let wait_ms = 1000u64;
+                        let mut waited = 0u64;
+                        let saved_chunks = loop {
+                            let chunks = {
+                                let rb = ring_buffer.lock().unwrap();
+                                rb.snapshot(last)
                             };
+                            if !chunks.is_empty() {
+                                break chunks;
+                            }
+                            if waited >= wait_ms {
+                                break Vec::new();
+                            }
+                            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                            waited += 50;
+                        };
 
-                            let is_saving_clone = is_saving.clone();
-                            let settings_clone = settings.clone();
-                            let ffmpeg_logger = logger.clone();
-                            tokio::spawn(async move {
-                                log_to!(ffmpeg_logger, Info, [FFMPEG] => "[JOB {}] Spawning to save {} Matroska chunks.", job_id, saved_chunks.len());
-
+                        if saved_chunks.is_empty() {
+                            log_to!(logger, Warn, [FFMPEG] => "[JOB {}] No chunks in buffer after waiting {}ms. Aborting.", job_id, wait_ms);
+                            let _ = reply.send(DaemonError::BufferEmpty.into());
+                            continue;
+                        }
 
-                                if saved_chunks.is_empty() {
-                                    log_to!(ffmpeg_logger, Warn, [FFMPEG] => "[JOB {}] No chunks in buffer after waiting {}ms. Aborting.", job_id, wait_ms);
-                                    is_saving_clone.store(false, Ordering::SeqCst);
-                                    return;
+                        let job = SaveJob {
+                            job_id,
+                            chunks: saved_chunks,
+                            settings: settings.clone(),
+                            reply,
+                        };
+                        pending_saves.fetch_add(1, Ordering::SeqCst);
+                        if let Err(err) = save_tx.try_send(job) {
+                            pending_saves.fetch_sub(1, Ordering::SeqCst);
+                            log_to!(logger, Warn, [UNIX] => "[JOB {}] Save queue is full, dropping request.", job_id);
+                            let job = match err {
+                                TrySendError::Full(job) | TrySendError::Closed(job) => job,
+                            };
+                            let _ = job.reply.send(DaemonError::SaveQueueFull.into());
+                        }
+                    }
+                    Request::Reload => {
+                        log_to!(logger, Info, [UNIX] => "Reload command received, re-reading settings.");
+                        let result = match load_checked_settings(&logger).await {
+                            Ok(new_settings) => {
+                                if settings.capture_backend != new_settings.capture_backend {
+                                    log_to!(logger, Warn, [DAEMON] => "capture_backend changes only apply after restarting the daemon.");
                                 }
-
-                                let home_dir = env::var("HOME").expect("HOME not set");
-                                let output_dir = std::path::Path::new(&home_dir).join(&settings_clone.save_path_from_home_string);
-                                create_dir_all(&output_dir).expect("Failed to create output directory");
-                                let output_filename = output_dir.join(format!("{}.mp4", chrono::Local::now().format(&settings_clone.clip_name_formatting)));
-
-                                let mut ffmpeg_child = Command::new("ffmpeg").args([
-                                    "-y",
-                                    "-i",
-                                    "-",
-                                    "-c:v",
-                                    "copy",
-                                    "-c:a",
-                                    "copy",
-                                    output_filename.to_str().unwrap()
-                                ])
-                                    .stdin(Stdio::piped())
-                                    .stdout(Stdio::null())
-                                    .stderr(Stdio::piped())
-                                    .spawn()
-                                    .expect("Failed to spawn ffmpeg");
-                                let mut stdin = ffmpeg_child.stdin.take().expect("Failed to get ffmpeg stdin");
-                                let mut stderr = BufReader::new(ffmpeg_child.stderr.take().unwrap());
-
-                                let logger_for_stderr = ffmpeg_logger.clone();
-                                tokio::spawn(async move {
-                                    let mut error_output = String::new();
-                                    use tokio::io::AsyncReadExt;
-                                    stderr.read_to_string(&mut error_output).await.unwrap();
-                                    if !error_output.is_empty() {
-                                        log_to!(logger_for_stderr, Warn, [FFMPEG] => "[JOB {}] {}", job_id, error_output.trim());
+                                let reopen = capture_changed(&settings, &new_settings);
+                                if reopen {
+                                    log_to!(logger, Info, [ASH] => "Capture source changed, asking the portal for a new stream.");
+                                    // The saved source still applies when only the cursor mode changed.
+                                    if settings.capture_source != new_settings.capture_source
+                                        || settings.capture_monitor != new_settings.capture_monitor
+                                    {
+                                        if let Err(e) = forget_restore_token().await {
+                                            log_to!(logger, Warn, [ASH] => "{:#}", e);
+                                        }
                                     }
-                                });
-
-                                for chunk in saved_chunks {
-                                    if let Err(e) = stdin.write_all(&chunk).await {
-                                        log_to!(ffmpeg_logger, Error, [FFMPEG] => "[JOB {}] Process failed while writing chunks: {}", job_id, e);
-                                        break;
+                                    if let Err(message) = reopen_capture(&mut capture, proxy.as_ref(), &new_settings, &mut supervisor, &logger).await {
+                                        // Already in settings.json, the supervisor rebuilds with them.
+                                        settings = new_settings;
+                                        let _ = reply.send(DaemonError::ReloadFailed { message }.into());
+                                        continue;
                                     }
                                 }
-                                drop(stdin);
-
-                                match ffmpeg_child.wait().await {
-                                    Ok(status) if status.success() => {
-                                        log_to!(ffmpeg_logger, Info, [FFMPEG] => "[JOB {}] Done! Saved to {:?}", job_id, output_filename);
-                                        send_status_to_gui(settings_clone.gui_socket_path.clone(), String::from("Saved!"), &ffmpeg_logger);
-                                        let gui_path = settings_clone.gui_socket_path.clone();
-                                        let ffmpeg_logger_clone = ffmpeg_logger.clone();
-                                        tokio::spawn(async move {
-                                            if let Err(e) = generate_preview_clip(&output_filename, &Settings::config_path().join("wayclip").join("previews")).await {
-                                                log_to!(&ffmpeg_logger_clone, Error, [FFMPEG] => "Failed to generate preview, {}", e)
-                                            };
-                                            send_status_to_gui(gui_path, String::from("Saved!"), &ffmpeg_logger_clone);
-                                        });
-                                    },
-                                    Ok(status) => {
-                                        log_to!(ffmpeg_logger, Error, [FFMPEG] => "[JOB {}] Exited with error: {}", job_id, status);
-                                        send_status_to_gui(settings_clone.gui_socket_path.clone(), String::from("Error during saving"), &ffmpeg_logger);
-                                    },
-                                    Err(e) => {
-                                        log_to!(ffmpeg_logger, Error, [FFMPEG] => "[JOB {}] Process failed: {}", job_id, e);
+                                let result = reload_pipeline(
+                                    &mut recording,
+                                    &mut settings,
+                                    new_settings,
+                                    &capture,
+                                    &ring_buffer,
+                                    &dropped_frames,
+                                    &logger,
+                                ).await;
+                                // The pipeline that was kept still reads from the closed session.
+                                if reopen {
+                                    if let Err(message) = &result {
+                                        supervisor.failed(message.clone());
                                     }
                                 }
-                                is_saving_clone.store(false, Ordering::SeqCst);
-                                log_to!(ffmpeg_logger, Info, [FFMPEG] => "[JOB {}] Task finished and save lock released.", job_id);
-                            });
-                        } else {
-                            log_to!(logger, Warn, [UNIX] => "Ignoring save request: A save is already in progress.");
+                                result
+                            }
+                            Err(message) => Err(message),
+                        };
+                        let _ = reply.send(match result {
+                            Ok(()) => Response::Ok,
+                            Err(message) => DaemonError::ReloadFailed { message }.into(),
+                        });
+                    }
+                    Request::ResetSource => {
+                        log_to!(logger, Info, [UNIX] => "Reset source command received, asking the portal for a new stream.");
+                        if let Err(e) = forget_restore_token().await {
+                            log_to!(logger, Warn, [ASH] => "{:#}", e);
                         }
+                        let result = match reopen_capture(&mut capture, proxy.as_ref(), &settings, &mut supervisor, &logger).await {
+                            Ok(()) => restart_pipeline(&mut recording, &settings, &capture, &ring_buffer, &dropped_frames, &logger)
+                                .await
+                                .inspect_err(|message| supervisor.failed(message.clone())),
+                            Err(message) => Err(message),
+                        };
+                        let _ = reply.send(match result {
+                            Ok(()) => Response::Ok,
+                            Err(message) => {
+                                log_to!(logger, Error, [ASH] => "Failed to reset source: {}", message);
+                                DaemonError::CaptureFailed { message }.into()
+                            }
+                        });
+                    }
+                    Request::SetAudio { source, volume, muted, persist } => {
+                        log_to!(logger, Info, [UNIX] => "Set audio command received for {}.", source);
+                        let response = set_audio(&recording.pipeline, &mut settings, source, volume, muted, persist, &logger).await;
+                        let _ = reply.send(response);
                     }
-                    "exit" => {
+                    Request::ToggleMute { source, persist } => {
+                        let muted = !audio_level(&settings, source).muted;
+                        log_to!(logger, Info, [UNIX] => "Toggle mute command received, {} {}.", if muted { "muting" } else { "unmuting" }, source);
+                        let response = set_audio(&recording.pipeline, &mut settings, source, None, Some(muted), persist, &logger).await;
+                        let _ = reply.send(response);
+                    }
+                    Request::Exit => {
                         log_to!(logger, Info, [UNIX] => "Exit command received, initiating shutdown.");
+                        let _ = reply.send(Response::Ok);
                         break;
                     }
-                    _ => {
-                        log_to!(logger, Warn, [UNIX] => "Unknown message received: {}", msg);
-                    }
                 }
             },
             else => {
@@ -602,6 +1340,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    cleanup(&pipeline, &session, settings, logger).await;
+    cleanup(
+        &recording.pipeline,
+        capture.session.as_ref(),
+        settings,
+        logger,
+    )
+    .await;
     Ok(())
 }
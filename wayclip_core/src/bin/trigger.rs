@@ -1,9 +1,7 @@
 use rodio::{Decoder, OutputStreamBuilder, Sink};
 use std::env;
 use std::io::Cursor;
-use tokio::io::AsyncWriteExt;
-use tokio::net::UnixStream;
-use wayclip_core::{log, settings::Settings};
+use wayclip_core::{log, protocol::DaemonClient, settings::Settings};
 
 static SOUND_BYTES: &[u8] = include_bytes!("../../assets/save.oga");
 
@@ -26,16 +24,19 @@ async fn main() -> Result<(), anyhow::Error> {
     } else {
         log!([UNIX] => "Couldn't open default audio stream, no audio output available");
     }
-    if let Ok(mut stream) = UnixStream::connect(settings.daemon_socket_path).await {
-        stream
-            .write_all(b"save\n")
-            .await
-            .expect("Failed to write to socket");
-        stream.flush().await.expect("Failed to flush socket");
-        log!([UNIX] => "saved the clip!");
-    } else {
-        log!([UNIX] => "failed to connect to socket, likely the daemon is not running");
-        std::process::exit(1);
+    let mut client = match DaemonClient::connect(&settings.daemon_socket_path).await {
+        Ok(client) => client,
+        Err(_) => {
+            log!([UNIX] => "failed to connect to socket, likely the daemon is not running");
+            std::process::exit(1);
+        }
+    };
+    match client.save(None).await {
+        Ok(path) => log!([UNIX] => "saved the clip to {}", path.display()),
+        Err(e) => {
+            log!([UNIX] => "failed to save the clip: {}", e);
+            std::process::exit(1);
+        }
     }
     Ok(())
 }
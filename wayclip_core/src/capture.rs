@@ -0,0 +1,551 @@
+use crate::{log_to, logging::Logger, settings::Settings};
+use anyhow::{bail, Context, Result};
+use ashpd::desktop::screencast::{SourceType, Stream};
+use std::env;
+use std::fmt;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokio::fs;
+use wayland_client::protocol::{wl_output, wl_registry};
+use wayland_client::{Connection, Dispatch, QueueHandle, WEnum};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureSource {
+    Monitor,
+    Window,
+    // Every monitor picked in the portal, composited by their desktop positions.
+    AllMonitors,
+}
+
+impl FromStr for CaptureSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "monitor" => Ok(CaptureSource::Monitor),
+            "window" => Ok(CaptureSource::Window),
+            "all_monitors" | "all" => Ok(CaptureSource::AllMonitors),
+            other => {
+                bail!("Unknown capture source '{other}' (expected monitor, window or all_monitors)")
+            }
+        }
+    }
+}
+
+impl fmt::Display for CaptureSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureSource::Monitor => write!(f, "monitor"),
+            CaptureSource::Window => write!(f, "window"),
+            CaptureSource::AllMonitors => write!(f, "all_monitors"),
+        }
+    }
+}
+
+impl CaptureSource {
+    pub fn source_type(&self) -> SourceType {
+        match self {
+            CaptureSource::Monitor | CaptureSource::AllMonitors => SourceType::Monitor,
+            CaptureSource::Window => SourceType::Window,
+        }
+    }
+
+    // A named monitor is looked up among the picked streams, so the picker has to
+    // allow several of them.
+    pub fn multiple(&self, monitor: &str) -> bool {
+        match self {
+            CaptureSource::Monitor => !monitor.is_empty(),
+            CaptureSource::Window => false,
+            CaptureSource::AllMonitors => true,
+        }
+    }
+}
+
+// Overrides the capture_backend setting, e.g. WAYCLIP_CAPTURE_BACKEND=test in CI.
+pub const CAPTURE_BACKEND_ENV: &str = "WAYCLIP_CAPTURE_BACKEND";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureBackend {
+    // Screencast through xdg-desktop-portal, audio from PipeWire nodes.
+    Portal,
+    // Test patterns and tones, needs neither a Wayland session nor PipeWire.
+    Test,
+}
+
+impl FromStr for CaptureBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "portal" => Ok(CaptureBackend::Portal),
+            "test" => Ok(CaptureBackend::Test),
+            other => bail!("Unknown capture backend '{other}' (expected portal or test)"),
+        }
+    }
+}
+
+impl fmt::Display for CaptureBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureBackend::Portal => write!(f, "portal"),
+            CaptureBackend::Test => write!(f, "test"),
+        }
+    }
+}
+
+impl CaptureBackend {
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        match env::var(CAPTURE_BACKEND_ENV) {
+            Ok(backend) if !backend.is_empty() => backend
+                .parse()
+                .with_context(|| format!("Invalid {CAPTURE_BACKEND_ENV}")),
+            _ => settings.capture_backend.parse(),
+        }
+    }
+}
+
+// Lets the portal skip the picker and hand back the source chosen last time.
+pub fn restore_token_path() -> PathBuf {
+    Settings::config_path()
+        .join("wayclip")
+        .join("restore_token")
+}
+
+pub async fn load_restore_token() -> Option<String> {
+    let token = fs::read_to_string(restore_token_path()).await.ok()?;
+    let token = token.trim();
+    (!token.is_empty()).then(|| token.to_string())
+}
+
+pub async fn save_restore_token(token: &str) -> Result<()> {
+    let path = restore_token_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(&path, token)
+        .await
+        .with_context(|| format!("Failed to write restore token to {}", path.display()))
+}
+
+// Returns whether there was a token to forget.
+pub async fn forget_restore_token() -> Result<bool> {
+    match fs::remove_file(restore_token_path()).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context("Failed to remove restore token"),
+    }
+}
+
+// Position and size are in logical desktop coordinates, as the portal reports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VideoStream {
+    pub node_id: u32,
+    pub position: Option<(i32, i32)>,
+    pub size: Option<(i32, i32)>,
+    // The frames in physical pixels, known for monitors with an output at `position`.
+    pub pixels: Option<(i32, i32)>,
+}
+
+impl From<&Stream> for VideoStream {
+    fn from(stream: &Stream) -> Self {
+        Self {
+            node_id: stream.pipe_wire_node_id(),
+            position: stream.position(),
+            size: stream.size(),
+            pixels: None,
+        }
+    }
+}
+
+impl VideoStream {
+    // Takes the physical size from the output at the same desktop position.
+    fn on_outputs(self, outputs: &[Output]) -> Self {
+        let pixels = outputs
+            .iter()
+            .find(|output| Some(output.position) == self.position)
+            .and_then(Output::pixels);
+        Self { pixels, ..self }
+    }
+
+    // Physical pixels per logical one, fractional on e.g. 1.5x outputs.
+    fn scale(&self) -> Option<f64> {
+        let ((width, _), (pixels, _)) = (self.size?, self.pixels?);
+        (width > 0 && pixels > 0).then(|| f64::from(pixels) / f64::from(width))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    pub name: String,
+    pub position: (i32, i32),
+    // Current mode, before the output's rotation.
+    mode: Option<(i32, i32)>,
+    rotated: bool,
+}
+
+impl Output {
+    // The current mode as it ends up on the desktop, what a screencast of it is made of.
+    pub fn pixels(&self) -> Option<(i32, i32)> {
+        self.mode.map(|(width, height)| {
+            if self.rotated {
+                (height, width)
+            } else {
+                (width, height)
+            }
+        })
+    }
+}
+
+#[derive(Default)]
+struct OutputState {
+    outputs: Vec<Output>,
+}
+
+impl Dispatch<wl_registry::WlRegistry, ()> for OutputState {
+    fn event(
+        state: &mut Self,
+        registry: &wl_registry::WlRegistry,
+        event: wl_registry::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        if let wl_registry::Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            // The name event (the connector) only exists from version 4 on.
+            if interface == "wl_output" && version >= 4 {
+                registry.bind::<wl_output::WlOutput, _, _>(name, 4, qh, state.outputs.len());
+                state.outputs.push(Output::default());
+            }
+        }
+    }
+}
+
+impl Dispatch<wl_output::WlOutput, usize> for OutputState {
+    fn event(
+        state: &mut Self,
+        _: &wl_output::WlOutput,
+        event: wl_output::Event,
+        index: &usize,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        let output = &mut state.outputs[*index];
+        match event {
+            wl_output::Event::Geometry {
+                x, y, transform, ..
+            } => {
+                output.position = (x, y);
+                output.rotated = matches!(
+                    transform,
+                    WEnum::Value(
+                        wl_output::Transform::_90
+                            | wl_output::Transform::_270
+                            | wl_output::Transform::Flipped90
+                            | wl_output::Transform::Flipped270
+                    )
+                );
+            }
+            wl_output::Event::Mode {
+                flags: WEnum::Value(flags),
+                width,
+                height,
+                ..
+            } if flags.contains(wl_output::Mode::Current) => output.mode = Some((width, height)),
+            wl_output::Event::Name { name } => output.name = name,
+            _ => {}
+        }
+    }
+}
+
+// Connector names, desktop positions and modes of every output, as the compositor
+// reports them.
+pub fn list_outputs() -> Result<Vec<Output>> {
+    let connection = Connection::connect_to_env().context("Failed to connect to Wayland")?;
+    let mut queue = connection.new_event_queue();
+    let qh = queue.handle();
+    connection.display().get_registry(&qh, ());
+
+    let mut state = OutputState::default();
+    // Once for the globals, once more for the events of the bound outputs.
+    queue.roundtrip(&mut state)?;
+    queue.roundtrip(&mut state)?;
+    Ok(state.outputs)
+}
+
+// Narrows the streams the portal returned down to the configured source.
+pub fn pick_streams(
+    source: CaptureSource,
+    monitor: &str,
+    streams: &[Stream],
+    logger: &Logger,
+) -> Result<Vec<VideoStream>> {
+    let streams: Vec<VideoStream> = streams.iter().map(VideoStream::from).collect();
+    // Needed to find a named monitor, or to lay several out in physical pixels.
+    let needs_outputs = match source {
+        CaptureSource::AllMonitors => streams.len() > 1,
+        CaptureSource::Monitor => !monitor.is_empty(),
+        CaptureSource::Window => false,
+    };
+    let outputs = if needs_outputs {
+        list_outputs().unwrap_or_else(|e| {
+            log_to!(logger, Warn, [ASH] => "Failed to list outputs, {:#}", e);
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    select_streams(source, monitor, streams, &outputs, logger)
+}
+
+fn select_streams(
+    source: CaptureSource,
+    monitor: &str,
+    streams: Vec<VideoStream>,
+    outputs: &[Output],
+    logger: &Logger,
+) -> Result<Vec<VideoStream>> {
+    let Some(&first) = streams.first() else {
+        bail!("No streams found in response");
+    };
+
+    match source {
+        CaptureSource::AllMonitors => Ok(streams
+            .into_iter()
+            .map(|stream| stream.on_outputs(outputs))
+            .collect()),
+        CaptureSource::Window => Ok(vec![first]),
+        CaptureSource::Monitor if monitor.is_empty() => Ok(vec![first]),
+        CaptureSource::Monitor => {
+            let position = outputs
+                .iter()
+                .find(|output| output.name == monitor)
+                .map(|output| output.position);
+            match streams
+                .iter()
+                .find(|s| position.is_some() && s.position == position)
+            {
+                Some(&stream) => Ok(vec![stream]),
+                None => {
+                    log_to!(logger, Warn,
+                        [ASH] => "Monitor {} was not among the picked streams (outputs: {:?}), using the first one.",
+                        monitor,
+                        outputs.iter().map(|o| o.name.as_str()).collect::<Vec<_>>()
+                    );
+                    Ok(vec![first])
+                }
+            }
+        }
+    }
+}
+
+// Everything up to raw frames of the whole capture, to be followed by the scaling chain.
+// With several streams the extra branches feeding the compositor are pushed to `branches`.
+pub fn video_source_segment(
+    pipewire_fd: i32,
+    streams: &[VideoStream],
+    branches: &mut Vec<String>,
+) -> String {
+    let source = |node_id: u32| {
+        format!(
+            "pipewiresrc do-timestamp=true fd={pipewire_fd} path={node_id} ! \
+            queue max-size-buffers=8 leaky=downstream"
+        )
+    };
+
+    if let [stream] = streams {
+        return format!("{} ! ", source(stream.node_id));
+    }
+
+    // The layout is in logical coordinates and the frames in physical pixels. Everything
+    // is placed at the densest output's scale, so it keeps its full resolution and the
+    // others are scaled up to meet it without gaps or overlaps.
+    let scale = streams
+        .iter()
+        .filter_map(VideoStream::scale)
+        .fold(1.0, f64::max);
+    let to_pixels = |logical: i32| (f64::from(logical) * scale).round() as i32;
+
+    // Monitors without a position from the portal are lined up to the right.
+    let origin_x = streams
+        .iter()
+        .filter_map(|s| s.position.map(|(x, _)| x))
+        .min()
+        .unwrap_or(0);
+    let origin_y = streams
+        .iter()
+        .filter_map(|s| s.position.map(|(_, y)| y))
+        .min()
+        .unwrap_or(0);
+    let mut next_x = 0;
+    let mut compositor = String::from("compositor name=capture background=black");
+    for (index, stream) in streams.iter().enumerate() {
+        let (x, y) = match stream.position {
+            Some((x, y)) => (x - origin_x, y - origin_y),
+            None => (next_x, 0),
+        };
+        next_x = next_x.max(x + stream.size.map_or(1920, |(width, _)| width));
+        compositor.push_str(&format!(
+            " sink_{index}::xpos={} sink_{index}::ypos={}",
+            to_pixels(x),
+            to_pixels(y)
+        ));
+        if let Some((width, height)) = stream.size {
+            compositor.push_str(&format!(
+                " sink_{index}::width={} sink_{index}::height={}",
+                to_pixels(width),
+                to_pixels(height)
+            ));
+        }
+        branches.push(format!(
+            "{} ! videoconvert ! capture.sink_{index}",
+            source(stream.node_id)
+        ));
+    }
+    format!("{compositor} ! ")
+}
+
+// Stands in for the screencast with the test backend, raw frames like video_source_segment.
+pub fn test_video_segment() -> String {
+    String::from("videotestsrc is-live=true pattern=ball ! ")
+}
+
+// Stands in for the PipeWire node of audio source `name` with the test backend.
+pub fn test_audio_segment(name: &str) -> String {
+    let wave = if name == "mic" { "ticks" } else { "sine" };
+    format!("audiotestsrc name={name}_src is-live=true wave={wave} volume=0.2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn logger() -> Logger {
+        let log = env::temp_dir().join(format!("wayclip-capture-test-{}.log", process::id()));
+        Logger::new(log).unwrap()
+    }
+
+    fn output(name: &str, position: (i32, i32), mode: (i32, i32), rotated: bool) -> Output {
+        Output {
+            name: name.to_string(),
+            position,
+            mode: Some(mode),
+            rotated,
+        }
+    }
+
+    fn stream(node_id: u32, position: (i32, i32), size: (i32, i32)) -> VideoStream {
+        VideoStream {
+            node_id,
+            position: Some(position),
+            size: Some(size),
+            pixels: None,
+        }
+    }
+
+    // A 4K panel at 2x left of a 1080p one at 1x, and a 1080p one turned upright below.
+    fn desk() -> (Vec<Output>, Vec<VideoStream>) {
+        let outputs = vec![
+            output("DP-1", (0, 0), (3840, 2160), false),
+            output("HDMI-A-1", (1920, 0), (1920, 1080), false),
+            output("DP-2", (0, 1080), (1920, 1080), true),
+        ];
+        let streams = vec![
+            stream(51, (0, 0), (1920, 1080)),
+            stream(52, (1920, 0), (1920, 1080)),
+            stream(53, (0, 1080), (1080, 1920)),
+        ];
+        (outputs, streams)
+    }
+
+    fn select(source: CaptureSource, monitor: &str) -> Vec<VideoStream> {
+        let (outputs, streams) = desk();
+        select_streams(source, monitor, streams, &outputs, &logger()).unwrap()
+    }
+
+    #[test]
+    fn all_monitors_take_the_physical_size_of_their_output() {
+        let picked = select(CaptureSource::AllMonitors, "");
+        let pixels: Vec<_> = picked.iter().map(|s| s.pixels).collect();
+        assert_eq!(
+            pixels,
+            [Some((3840, 2160)), Some((1920, 1080)), Some((1080, 1920))]
+        );
+        assert_eq!(picked[0].scale(), Some(2.0));
+        assert_eq!(picked[2].scale(), Some(1.0));
+    }
+
+    #[test]
+    fn picks_the_named_monitor_or_the_first_stream() {
+        let node_ids =
+            |picked: Vec<VideoStream>| -> Vec<u32> { picked.iter().map(|s| s.node_id).collect() };
+        assert_eq!(node_ids(select(CaptureSource::Monitor, "HDMI-A-1")), [52]);
+        assert_eq!(node_ids(select(CaptureSource::Monitor, "DP-2")), [53]);
+        assert_eq!(node_ids(select(CaptureSource::Monitor, "eDP-1")), [51]);
+        assert_eq!(node_ids(select(CaptureSource::Monitor, "")), [51]);
+        assert_eq!(node_ids(select(CaptureSource::Window, "")), [51]);
+        assert!(select_streams(CaptureSource::AllMonitors, "", vec![], &[], &logger()).is_err());
+    }
+
+    #[test]
+    fn mixed_scales_are_laid_out_at_the_densest_scale() {
+        let streams = select(CaptureSource::AllMonitors, "");
+        let mut branches = Vec::new();
+        let segment = video_source_segment(7, &streams, &mut branches);
+
+        // Everything doubled to match the 2x panel, so the outputs still touch edge to edge.
+        assert_eq!(
+            segment,
+            "compositor name=capture background=black \
+            sink_0::xpos=0 sink_0::ypos=0 sink_0::width=3840 sink_0::height=2160 \
+            sink_1::xpos=3840 sink_1::ypos=0 sink_1::width=3840 sink_1::height=2160 \
+            sink_2::xpos=0 sink_2::ypos=2160 sink_2::width=2160 sink_2::height=3840 ! "
+        );
+        assert_eq!(branches.len(), 3);
+        assert!(branches[2].starts_with("pipewiresrc do-timestamp=true fd=7 path=53 ! "));
+    }
+
+    #[test]
+    fn fractional_scales_keep_outputs_adjacent() {
+        // 2560x1440 at 1.5x next to a 1080p panel at 1x.
+        let outputs = vec![
+            output("eDP-1", (0, 0), (2560, 1440), false),
+            output("DP-1", (1707, 0), (1920, 1080), false),
+        ];
+        let streams = vec![
+            stream(51, (0, 0), (1707, 960)),
+            stream(52, (1707, 0), (1920, 1080)),
+        ];
+        let streams =
+            select_streams(CaptureSource::AllMonitors, "", streams, &outputs, &logger()).unwrap();
+        let segment = video_source_segment(7, &streams, &mut Vec::new());
+
+        assert!(
+            segment.contains("sink_0::xpos=0 sink_0::ypos=0 sink_0::width=2560"),
+            "{segment}"
+        );
+        assert!(
+            segment.contains("sink_1::xpos=2560 sink_1::ypos=0"),
+            "{segment}"
+        );
+    }
+
+    #[test]
+    fn unknown_outputs_keep_the_logical_layout() {
+        let (_, streams) = desk();
+        let segment = video_source_segment(7, &streams, &mut Vec::new());
+        assert!(
+            segment.contains(
+                "sink_0::xpos=0 sink_0::ypos=0 sink_0::width=1920 sink_0::height=1080 \
+                sink_1::xpos=1920 sink_1::ypos=0"
+            ),
+            "{segment}"
+        );
+    }
+}
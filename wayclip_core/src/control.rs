@@ -1,7 +1,16 @@
+use crate::capture::forget_restore_token;
+use crate::pidfile::running_pid;
+use crate::protocol::{DaemonClient, DaemonStatus};
+use crate::settings::Settings;
 use anyhow::{bail, Context, Result};
 use colored::*;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 use tokio::process::Command;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
+
+// How long a daemon started outside systemd gets to shut down after SIGTERM.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct DaemonManager;
 
@@ -16,7 +25,7 @@ impl DaemonManager {
         Self {}
     }
 
-    pub async fn is_running(&self) -> bool {
+    async fn is_managed(&self) -> bool {
         Command::new("systemctl")
             .args(["--user", "is-active", "--quiet", "wayclip-daemon.service"])
             .status()
@@ -25,8 +34,43 @@ impl DaemonManager {
             .unwrap_or(false)
     }
 
+    // The PID of a daemon systemd doesn't know about, e.g. one started by hand.
+    async fn unmanaged_pid(&self) -> Result<Option<u32>> {
+        if self.is_managed().await {
+            return Ok(None);
+        }
+        let settings = Settings::load().await?;
+        running_pid(&settings.daemon_pid_path)
+    }
+
+    // Only a daemon with a PID counts, a PID file that can't be read is an error rather
+    // than a running daemon.
+    pub async fn is_running(&self) -> Result<bool> {
+        Ok(self.is_managed().await || self.unmanaged_pid().await?.is_some())
+    }
+
+    // SIGTERM runs the same graceful shutdown as under systemd, the lock on the PID
+    // file is gone once the process is.
+    async fn stop_unmanaged(&self, pid: u32) -> Result<()> {
+        println!("Stopping daemon (PID {pid}), it is not managed by systemd...");
+        kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
+            .with_context(|| format!("Failed to send SIGTERM to PID {pid}"))?;
+
+        let deadline = Instant::now() + STOP_TIMEOUT;
+        while Instant::now() < deadline {
+            if self.unmanaged_pid().await? != Some(pid) {
+                return Ok(());
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+        bail!("Daemon (PID {pid}) did not exit within {STOP_TIMEOUT:?}.");
+    }
+
     pub async fn start(&self) -> Result<()> {
-        if self.is_running().await {
+        if let Some(pid) = self.unmanaged_pid().await? {
+            bail!("Daemon is already running outside systemd (PID {pid}).");
+        }
+        if self.is_managed().await {
             bail!("Daemon is already running.");
         }
         println!("Starting daemon via systemd...");
@@ -43,7 +87,7 @@ impl DaemonManager {
 
         sleep(Duration::from_millis(500)).await;
 
-        if self.is_running().await {
+        if self.is_running().await? {
             println!("{} Daemon started successfully.", "✔".green());
             Ok(())
         } else {
@@ -52,7 +96,12 @@ impl DaemonManager {
     }
 
     pub async fn stop(&self) -> Result<()> {
-        if !self.is_running().await {
+        if let Some(pid) = self.unmanaged_pid().await? {
+            self.stop_unmanaged(pid).await?;
+            println!("{} Daemon stopped.", "✔".green());
+            return Ok(());
+        }
+        if !self.is_managed().await {
             println!("Daemon is not running.");
             return Ok(());
         }
@@ -72,6 +121,10 @@ impl DaemonManager {
     }
 
     pub async fn restart(&self) -> Result<()> {
+        // Comes back under systemd, like a fresh start.
+        if let Some(pid) = self.unmanaged_pid().await? {
+            self.stop_unmanaged(pid).await?;
+        }
         println!("Restarting daemon via systemd...");
         let output = Command::new("systemctl")
             .args(["--user", "restart", "wayclip-daemon.service"])
@@ -86,7 +139,7 @@ impl DaemonManager {
 
         sleep(Duration::from_millis(500)).await;
 
-        if self.is_running().await {
+        if self.is_running().await? {
             println!("{} Daemon restarted successfully.", "✔".green());
             Ok(())
         } else {
@@ -94,23 +147,21 @@ impl DaemonManager {
         }
     }
 
-    pub async fn status(&self) -> Result<()> {
-        println!("Querying daemon status from systemd...");
-        let mut cmd = Command::new("systemctl");
-        cmd.args(["--user", "--no-pager", "status", "wayclip-daemon.service"]);
-
-        let status = cmd
-            .status()
-            .await
-            .context("Failed to execute systemctl command. Is systemd running?")?;
+    // Asks a running daemon to re-read settings.json and apply what changed.
+    pub async fn reload(&self) -> Result<()> {
+        DaemonClient::connect_default().await?.reload().await
+    }
 
-        if !status.success() {
-            println!(
-                "{}",
-                "\nDaemon is not running or is in a failed state.".yellow()
-            );
+    // Forgets the saved capture source, a running daemon also asks the portal for a new one.
+    pub async fn reset_source(&self) -> Result<()> {
+        if self.is_running().await? {
+            return DaemonClient::connect_default().await?.reset_source().await;
         }
+        forget_restore_token().await.map(|_| ())
+    }
 
-        Ok(())
+    // Asks the running daemon for its pipeline, buffer and save state.
+    pub async fn status(&self) -> Result<DaemonStatus> {
+        DaemonClient::connect_default().await?.status().await
     }
 }
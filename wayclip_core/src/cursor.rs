@@ -0,0 +1,689 @@
+use crate::{log_to, logging::Logger};
+use anyhow::{bail, Result};
+use ashpd::desktop::screencast::CursorMode;
+use enumflags2::BitFlags;
+use gstreamer::{self as gst, prelude::*};
+use gstreamer_app::AppSrc;
+use gstreamer_video::{
+    self as gst_video, VideoCapsBuilder, VideoFormat, VideoFrameFlags, VideoOverlayComposition,
+    VideoOverlayFormatFlags, VideoOverlayRectangle,
+};
+use pipewire::{
+    self as pw,
+    properties::properties,
+    spa::{
+        self,
+        param::{
+            format::{FormatProperties, MediaSubtype, MediaType},
+            format_utils,
+            video::{VideoFormat as SpaVideoFormat, VideoInfoRaw},
+            ParamType,
+        },
+        pod::{serialize::PodSerializer, ChoiceValue, Object, Pod, Property, PropertyFlags, Value},
+        sys::{spa_buffer, spa_chunk, spa_meta_bitmap, spa_meta_cursor},
+        utils::{Choice, ChoiceEnum, ChoiceFlags, Direction, Fraction, Id, Rectangle, SpaTypes},
+    },
+    stream::{Stream, StreamFlags, StreamState},
+};
+use std::io::Cursor;
+use std::mem::size_of;
+use std::os::fd::OwnedFd;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Room for the cursor header plus a 256x256 32-bit bitmap, compositors send smaller ones.
+const CURSOR_META_SIZE: usize =
+    size_of::<spa_meta_cursor>() + size_of::<spa_meta_bitmap>() + 256 * 256 * 4;
+
+pub fn parse_cursor_mode(mode: &str) -> Result<CursorMode> {
+    match mode.trim().to_lowercase().as_str() {
+        "hidden" => Ok(CursorMode::Hidden),
+        "embedded" => Ok(CursorMode::Embedded),
+        "metadata" => Ok(CursorMode::Metadata),
+        other => bail!("Unknown cursor mode '{other}' (expected hidden, embedded or metadata)"),
+    }
+}
+
+// Falls back to the other way of getting a cursor before giving up on it.
+pub fn pick_cursor_mode(wanted: CursorMode, available: BitFlags<CursorMode>) -> CursorMode {
+    let fallback = match wanted {
+        CursorMode::Embedded => CursorMode::Metadata,
+        CursorMode::Metadata => CursorMode::Embedded,
+        _ => CursorMode::Hidden,
+    };
+    [wanted, fallback]
+        .into_iter()
+        .find(|mode| available.contains(*mode))
+        .unwrap_or(CursorMode::Hidden)
+}
+
+#[derive(Default)]
+struct CursorState {
+    visible: bool,
+    position: (i32, i32),
+    hotspot: (i32, i32),
+    // BGRA with a video meta, ready to be wrapped in an overlay rectangle.
+    bitmap: Option<(gst::Buffer, u32, u32)>,
+}
+
+impl CursorState {
+    fn composition(&self) -> Option<VideoOverlayComposition> {
+        let (buffer, width, height) = self.bitmap.as_ref().filter(|_| self.visible)?;
+        let rectangle = VideoOverlayRectangle::new_raw(
+            buffer,
+            self.position.0 - self.hotspot.0,
+            self.position.1 - self.hotspot.1,
+            *width,
+            *height,
+            VideoOverlayFormatFlags::empty(),
+        );
+        VideoOverlayComposition::new([&rectangle]).ok()
+    }
+
+    // Safety: `meta` must point at `size` readable bytes. The offsets and sizes in it come
+    // from the producer and are checked against `size` before anything past the header
+    // is read.
+    unsafe fn update(&mut self, meta: *const spa_meta_cursor, size: usize) {
+        let cursor = &*meta;
+        // Id 0 means the cursor left the stream or is hidden.
+        self.visible = cursor.id != 0;
+        if !self.visible {
+            return;
+        }
+        self.position = (cursor.position.x, cursor.position.y);
+        self.hotspot = (cursor.hotspot.x, cursor.hotspot.y);
+
+        // The bitmap is only sent when the cursor image changes.
+        let offset = cursor.bitmap_offset as usize;
+        if offset < size_of::<spa_meta_cursor>()
+            || offset
+                .checked_add(size_of::<spa_meta_bitmap>())
+                .is_none_or(|end| end > size)
+        {
+            return;
+        }
+        let bytes = meta.cast::<u8>();
+        let bitmap = bytes.add(offset).cast::<spa_meta_bitmap>().read_unaligned();
+        let Some(pixels_len) = pixels_len(&bitmap) else {
+            return;
+        };
+        let Some(pixels_start) = offset.checked_add(bitmap.offset as usize) else {
+            return;
+        };
+        if pixels_start
+            .checked_add(pixels_len)
+            .is_none_or(|end| end > size)
+        {
+            return;
+        }
+        let pixels = slice::from_raw_parts(bytes.add(pixels_start), pixels_len);
+        if let Some(converted) = to_bgra(&bitmap, pixels) {
+            self.bitmap = Some(converted);
+        }
+    }
+}
+
+// Bytes from the first pixel to the end of the last row, None for an empty bitmap or one
+// whose rows overlap.
+fn pixels_len(bitmap: &spa_meta_bitmap) -> Option<usize> {
+    let (width, height) = (bitmap.size.width as usize, bitmap.size.height as usize);
+    let row = width.checked_mul(4)?;
+    let stride = usize::try_from(bitmap.stride).ok()?;
+    if width == 0 || height == 0 || stride < row {
+        return None;
+    }
+    stride.checked_mul(height - 1)?.checked_add(row)
+}
+
+// `pixels` has to be at least `pixels_len(bitmap)` long.
+fn to_bgra(bitmap: &spa_meta_bitmap, pixels: &[u8]) -> Option<(gst::Buffer, u32, u32)> {
+    let (width, height) = (bitmap.size.width, bitmap.size.height);
+    let stride = bitmap.stride as usize;
+    let row = width as usize * 4;
+    let mut data = Vec::with_capacity(row * height as usize);
+    for line in (0..height as usize).map(|i| &pixels[i * stride..i * stride + row]) {
+        for px in line.chunks_exact(4) {
+            let (r, g, b, a) = match bitmap.format {
+                spa::sys::SPA_VIDEO_FORMAT_RGBA => (px[0], px[1], px[2], px[3]),
+                spa::sys::SPA_VIDEO_FORMAT_BGRA => (px[2], px[1], px[0], px[3]),
+                spa::sys::SPA_VIDEO_FORMAT_ARGB => (px[1], px[2], px[3], px[0]),
+                spa::sys::SPA_VIDEO_FORMAT_ABGR => (px[3], px[2], px[1], px[0]),
+                _ => return None,
+            };
+            data.extend_from_slice(&[b, g, r, a]);
+        }
+    }
+
+    let mut buffer = gst::Buffer::from_mut_slice(data);
+    gst_video::VideoMeta::add(
+        buffer.get_mut()?,
+        VideoFrameFlags::empty(),
+        VideoFormat::Bgra,
+        width,
+        height,
+    )
+    .ok()?;
+    Some((buffer, width, height))
+}
+
+// Safety: `buffer` must be a valid buffer dequeued from a stream. Returns the meta with
+// its negotiated size.
+unsafe fn find_cursor_meta(buffer: *const spa_buffer) -> Option<(*const spa_meta_cursor, usize)> {
+    if (*buffer).n_metas == 0 {
+        return None;
+    }
+    slice::from_raw_parts((*buffer).metas, (*buffer).n_metas as usize)
+        .iter()
+        .find(|meta| {
+            meta.type_ == spa::sys::SPA_META_Cursor
+                && meta.size as usize >= size_of::<spa_meta_cursor>()
+                && !meta.data.is_null()
+        })
+        .map(|meta| (meta.data as *const spa_meta_cursor, meta.size as usize))
+}
+
+// The negotiated layout of the frames on the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameFormat {
+    format: VideoFormat,
+    width: u32,
+    height: u32,
+}
+
+impl FrameFormat {
+    fn parse(param: &Pod) -> Option<Self> {
+        let (media_type, media_subtype) = format_utils::parse_format(param).ok()?;
+        if media_type != MediaType::Video || media_subtype != MediaSubtype::Raw {
+            return None;
+        }
+        let mut info = VideoInfoRaw::new();
+        info.parse(param).ok()?;
+        let format = match info.format() {
+            SpaVideoFormat::BGRx => VideoFormat::Bgrx,
+            SpaVideoFormat::RGBx => VideoFormat::Rgbx,
+            SpaVideoFormat::BGRA => VideoFormat::Bgra,
+            SpaVideoFormat::RGBA => VideoFormat::Rgba,
+            _ => return None,
+        };
+        Some(Self {
+            format,
+            width: info.size().width,
+            height: info.size().height,
+        })
+    }
+
+    // The framerate is whatever the compositor sends, videorate evens it out later.
+    fn caps(&self) -> gst::Caps {
+        VideoCapsBuilder::new()
+            .format(self.format)
+            .width(self.width as i32)
+            .height(self.height as i32)
+            .framerate(gst::Fraction::new(0, 1))
+            .build()
+    }
+}
+
+// Copies the frame described by `chunk` out of the mapped buffer memory. None for chunks
+// that carry no frame (cursor-only updates, corrupted ones) or don't fit in `memory`.
+fn frame_buffer(memory: &[u8], chunk: &spa_chunk, format: &FrameFormat) -> Option<gst::Buffer> {
+    if chunk.size == 0 || chunk.flags & spa::sys::SPA_CHUNK_FLAG_CORRUPTED as i32 != 0 {
+        return None;
+    }
+    let row = (format.width as usize).checked_mul(4)?;
+    // A stride of 0 means tightly packed rows.
+    let stride = match usize::try_from(chunk.stride).ok()? {
+        0 => row,
+        stride => stride,
+    };
+    if row == 0 || format.height == 0 || stride < row {
+        return None;
+    }
+    let len = stride
+        .checked_mul(format.height as usize - 1)?
+        .checked_add(row)?;
+    if len > chunk.size as usize {
+        return None;
+    }
+    let start = chunk.offset as usize;
+    let frame = memory.get(start..start.checked_add(len)?)?;
+
+    let mut buffer = gst::Buffer::from_mut_slice(frame.to_vec());
+    gst_video::VideoMeta::add_full(
+        buffer.get_mut()?,
+        VideoFrameFlags::empty(),
+        format.format,
+        format.width,
+        format.height,
+        &[0],
+        &[stride as i32],
+    )
+    .ok()?;
+    Some(buffer)
+}
+
+// Safety: `buffer` must be a valid buffer dequeued from a stream with mapped buffers.
+unsafe fn read_frame(buffer: *const spa_buffer, format: &FrameFormat) -> Option<gst::Buffer> {
+    if (*buffer).n_datas == 0 {
+        return None;
+    }
+    let data = &*(*buffer).datas;
+    if data.data.is_null() || data.chunk.is_null() {
+        return None;
+    }
+    let memory = slice::from_raw_parts(data.data as *const u8, data.maxsize as usize);
+    frame_buffer(memory, &*data.chunk, format)
+}
+
+fn serialize(object: Object) -> Vec<u8> {
+    PodSerializer::serialize(Cursor::new(Vec::new()), &Value::Object(object))
+        .expect("Failed to serialize pod")
+        .0
+        .into_inner()
+}
+
+fn property(key: u32, value: Value) -> Property {
+    Property {
+        key,
+        flags: PropertyFlags::empty(),
+        value,
+    }
+}
+
+// Packed 32-bit formats only, without modifiers so the frames come in mappable memory.
+fn format_param() -> Vec<u8> {
+    let formats = [
+        SpaVideoFormat::BGRx,
+        SpaVideoFormat::RGBx,
+        SpaVideoFormat::BGRA,
+        SpaVideoFormat::RGBA,
+    ];
+    serialize(Object {
+        type_: SpaTypes::ObjectParamFormat.as_raw(),
+        id: ParamType::EnumFormat.as_raw(),
+        properties: vec![
+            property(
+                FormatProperties::MediaType.as_raw(),
+                Value::Id(Id(MediaType::Video.as_raw())),
+            ),
+            property(
+                FormatProperties::MediaSubtype.as_raw(),
+                Value::Id(Id(MediaSubtype::Raw.as_raw())),
+            ),
+            property(
+                FormatProperties::VideoFormat.as_raw(),
+                Value::Choice(ChoiceValue::Id(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Enum {
+                        default: Id(formats[0].as_raw()),
+                        alternatives: formats.iter().map(|f| Id(f.as_raw())).collect(),
+                    },
+                ))),
+            ),
+            property(
+                FormatProperties::VideoSize.as_raw(),
+                Value::Choice(ChoiceValue::Rectangle(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Range {
+                        default: Rectangle {
+                            width: 1920,
+                            height: 1080,
+                        },
+                        min: Rectangle {
+                            width: 1,
+                            height: 1,
+                        },
+                        max: Rectangle {
+                            width: 16384,
+                            height: 16384,
+                        },
+                    },
+                ))),
+            ),
+            property(
+                FormatProperties::VideoFramerate.as_raw(),
+                Value::Choice(ChoiceValue::Fraction(Choice(
+                    ChoiceFlags::empty(),
+                    ChoiceEnum::Range {
+                        default: Fraction { num: 60, denom: 1 },
+                        min: Fraction { num: 0, denom: 1 },
+                        max: Fraction {
+                            num: 1000,
+                            denom: 1,
+                        },
+                    },
+                ))),
+            ),
+        ],
+    })
+}
+
+fn cursor_meta_param() -> Vec<u8> {
+    serialize(Object {
+        type_: SpaTypes::ObjectParamMeta.as_raw(),
+        id: ParamType::Meta.as_raw(),
+        properties: vec![
+            property(
+                spa::sys::SPA_PARAM_META_type,
+                Value::Id(Id(spa::sys::SPA_META_Cursor)),
+            ),
+            property(
+                spa::sys::SPA_PARAM_META_size,
+                Value::Int(CURSOR_META_SIZE as i32),
+            ),
+        ],
+    })
+}
+
+// Frames are copied out, so any memory PipeWire can map for us works.
+fn buffers_param() -> Vec<u8> {
+    let data_types = (1 << spa::sys::SPA_DATA_MemFd) | (1 << spa::sys::SPA_DATA_MemPtr);
+    serialize(Object {
+        type_: SpaTypes::ObjectParamBuffers.as_raw(),
+        id: ParamType::Buffers.as_raw(),
+        properties: vec![property(
+            spa::sys::SPA_PARAM_BUFFERS_dataType,
+            Value::Choice(ChoiceValue::Int(Choice(
+                ChoiceFlags::empty(),
+                ChoiceEnum::Flags {
+                    default: data_types,
+                    flags: vec![data_types],
+                },
+            ))),
+        )],
+    })
+}
+
+// Replaces pipewiresrc for the screencast when the cursor is drawn, pipewiresrc never
+// negotiates the cursor meta.
+pub fn screen_source_segment() -> String {
+    String::from(
+        "appsrc name=screen is-live=true do-timestamp=true format=time ! \
+        queue max-size-buffers=8 leaky=downstream ! ",
+    )
+}
+
+struct ScreenStream {
+    appsrc: AppSrc,
+    cursor: Arc<Mutex<CursorState>>,
+    format: Option<FrameFormat>,
+}
+
+// Pushes the frames of `node_id` to the appsrc and keeps the cursor meta riding along on
+// them, on its own PipeWire connection until told to quit.
+fn run_screen_stream(
+    fd: OwnedFd,
+    node_id: u32,
+    screen: ScreenStream,
+    quit: pw::channel::Receiver<()>,
+) -> Result<(), pw::Error> {
+    pw::init();
+    let mainloop = pw::main_loop::MainLoop::new(None)?;
+    let _quit = quit.attach(mainloop.loop_(), {
+        let mainloop = mainloop.clone();
+        move |_| mainloop.quit()
+    });
+    let context = pw::context::Context::new(&mainloop)?;
+    let core = context.connect_fd(fd, None)?;
+
+    let stream = Stream::new(
+        &core,
+        "wayclip-screen",
+        properties! {
+            *pw::keys::MEDIA_TYPE => "Video",
+            *pw::keys::MEDIA_CATEGORY => "Capture",
+            *pw::keys::MEDIA_ROLE => "Screen",
+        },
+    )?;
+    let _listener = stream
+        .add_local_listener_with_user_data(screen)
+        .state_changed(|_, screen, _, state| {
+            if let StreamState::Error(message) = state {
+                gst::element_error!(
+                    screen.appsrc,
+                    gst::ResourceError::Read,
+                    ("Screencast stream failed: {}", message)
+                );
+            }
+        })
+        .param_changed(|stream, screen, id, param| {
+            let Some(param) = param.filter(|_| id == ParamType::Format.as_raw()) else {
+                return;
+            };
+            let Some(format) = FrameFormat::parse(param) else {
+                return;
+            };
+            screen.appsrc.set_caps(Some(&format.caps()));
+            screen.format = Some(format);
+
+            let buffers = buffers_param();
+            let meta = cursor_meta_param();
+            let _ = stream.update_params(&mut [
+                Pod::from_bytes(&buffers).unwrap(),
+                Pod::from_bytes(&meta).unwrap(),
+            ]);
+        })
+        .process(|stream, screen| unsafe {
+            let buffer = stream.dequeue_raw_buffer();
+            if buffer.is_null() {
+                return;
+            }
+            let spa_buffer = (*buffer).buffer;
+            if let Some((meta, size)) = find_cursor_meta(spa_buffer) {
+                screen.cursor.lock().unwrap().update(meta, size);
+            }
+            if let Some(frame) = screen
+                .format
+                .as_ref()
+                .and_then(|format| read_frame(spa_buffer, format))
+            {
+                // Fails while the pipeline is flushing or shutting down.
+                let _ = screen.appsrc.push_buffer(frame);
+            }
+            stream.queue_raw_buffer(buffer);
+        })
+        .register()?;
+
+    let format = format_param();
+    stream.connect(
+        Direction::Input,
+        Some(node_id),
+        StreamFlags::AUTOCONNECT | StreamFlags::MAP_BUFFERS,
+        &mut [Pod::from_bytes(&format).unwrap()],
+    )?;
+
+    mainloop.run();
+    Ok(())
+}
+
+// Feeds the screencast to `appsrc name=screen` and draws the pointer from the cursor meta
+// on the same buffers through an `overlaycomposition` element, for compositors that
+// can't embed it. One PipeWire consumer, so the compositor sends each frame once. Stops
+// when dropped.
+pub struct CursorOverlay {
+    quit: pw::channel::Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CursorOverlay {
+    pub fn attach(
+        overlay: &gst::Element,
+        appsrc: AppSrc,
+        fd: OwnedFd,
+        node_id: u32,
+        logger: &Logger,
+    ) -> CursorOverlay {
+        let cursor = Arc::new(Mutex::new(CursorState::default()));
+
+        let draw_state = cursor.clone();
+        overlay.connect("draw", false, move |_| {
+            let composition = draw_state.lock().unwrap().composition();
+            Some(composition.to_value())
+        });
+
+        let (quit, quit_rx) = pw::channel::channel::<()>();
+        let logger = logger.clone();
+        let thread = thread::spawn(move || {
+            let screen = ScreenStream {
+                appsrc: appsrc.clone(),
+                cursor,
+                format: None,
+            };
+            if let Err(e) = run_screen_stream(fd, node_id, screen, quit_rx) {
+                log_to!(logger, Error, [GST] => "Screencast stream failed, {}", e);
+                // Lets the supervisor restart the pipeline instead of waiting for frames.
+                gst::element_error!(
+                    appsrc,
+                    gst::ResourceError::OpenRead,
+                    ("Failed to open the screencast stream: {}", e)
+                );
+            }
+        });
+
+        CursorOverlay {
+            quit,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for CursorOverlay {
+    fn drop(&mut self) {
+        let _ = self.quit.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spa::sys::spa_rectangle;
+
+    const HEADER: usize = size_of::<spa_meta_cursor>();
+    const BITMAP: usize = size_of::<spa_meta_bitmap>();
+
+    fn bitmap(width: u32, height: u32, stride: i32) -> spa_meta_bitmap {
+        spa_meta_bitmap {
+            format: spa::sys::SPA_VIDEO_FORMAT_RGBA,
+            size: spa_rectangle { width, height },
+            stride,
+            offset: BITMAP as u32,
+        }
+    }
+
+    // A cursor meta of `size` bytes with the bitmap header at `bitmap_offset`, backed by
+    // u64s to keep it aligned like a real one.
+    fn meta(size: usize, bitmap_offset: u32, bitmap: spa_meta_bitmap) -> Vec<u64> {
+        let mut data = vec![0u64; size.div_ceil(8)];
+        let bytes = data.as_mut_ptr().cast::<u8>();
+        unsafe {
+            let mut cursor: spa_meta_cursor = std::mem::zeroed();
+            cursor.id = 1;
+            cursor.bitmap_offset = bitmap_offset;
+            bytes.cast::<spa_meta_cursor>().write(cursor);
+            if bitmap_offset as usize + BITMAP <= size {
+                bytes
+                    .add(bitmap_offset as usize)
+                    .cast::<spa_meta_bitmap>()
+                    .write_unaligned(bitmap);
+            }
+        }
+        data
+    }
+
+    fn update(size: usize, bitmap_offset: u32, bitmap: spa_meta_bitmap) -> CursorState {
+        gst::init().unwrap();
+        let data = meta(size, bitmap_offset, bitmap);
+        let mut state = CursorState::default();
+        unsafe { state.update(data.as_ptr().cast(), size) };
+        state
+    }
+
+    #[test]
+    fn reads_bitmap_within_the_meta() {
+        let state = update(HEADER + BITMAP + 8 * 8 * 4, HEADER as u32, bitmap(8, 8, 32));
+        assert!(state.visible);
+        let (_, width, height) = state.bitmap.unwrap();
+        assert_eq!((width, height), (8, 8));
+    }
+
+    #[test]
+    fn skips_bitmap_header_past_the_meta() {
+        let state = update(HEADER + BITMAP - 1, HEADER as u32, bitmap(1, 1, 4));
+        assert!(state.visible);
+        assert!(state.bitmap.is_none());
+        assert!(update(HEADER, u32::MAX, bitmap(1, 1, 4)).bitmap.is_none());
+    }
+
+    #[test]
+    fn skips_pixels_past_the_meta() {
+        // One byte short of the last row.
+        let size = HEADER + BITMAP + 32 * 7 + 8 * 4 - 1;
+        assert!(update(size, HEADER as u32, bitmap(8, 8, 32))
+            .bitmap
+            .is_none());
+
+        let mut far = bitmap(8, 8, 32);
+        far.offset = u32::MAX;
+        assert!(update(4096, HEADER as u32, far).bitmap.is_none());
+    }
+
+    const FRAME: FrameFormat = FrameFormat {
+        format: VideoFormat::Bgrx,
+        width: 4,
+        height: 2,
+    };
+
+    fn chunk(offset: u32, size: u32, stride: i32, flags: i32) -> spa_chunk {
+        spa_chunk {
+            offset,
+            size,
+            stride,
+            flags,
+        }
+    }
+
+    #[test]
+    fn copies_frames_with_their_stride() {
+        gst::init().unwrap();
+        let memory = vec![0u8; 256];
+
+        let frame = frame_buffer(&memory, &chunk(16, 40, 24, 0), &FRAME).unwrap();
+        assert_eq!(frame.size(), 24 + 16);
+        let meta = frame.meta::<gst_video::VideoMeta>().unwrap();
+        assert_eq!(meta.stride(), &[24]);
+
+        let packed = frame_buffer(&memory, &chunk(0, 32, 0, 0), &FRAME).unwrap();
+        assert_eq!(packed.size(), 32);
+    }
+
+    #[test]
+    fn skips_chunks_without_a_whole_frame() {
+        gst::init().unwrap();
+        let memory = vec![0u8; 64];
+        // Cursor-only update.
+        assert!(frame_buffer(&memory, &chunk(0, 0, 16, 0), &FRAME).is_none());
+        let corrupted = spa::sys::SPA_CHUNK_FLAG_CORRUPTED as i32;
+        assert!(frame_buffer(&memory, &chunk(0, 32, 16, corrupted), &FRAME).is_none());
+        assert!(frame_buffer(&memory, &chunk(0, 31, 16, 0), &FRAME).is_none());
+        assert!(frame_buffer(&memory, &chunk(0, 32, 12, 0), &FRAME).is_none());
+        assert!(frame_buffer(&memory, &chunk(40, 32, 16, 0), &FRAME).is_none());
+        assert!(frame_buffer(&memory, &chunk(0, 32, -16, 0), &FRAME).is_none());
+    }
+
+    #[test]
+    fn skips_inconsistent_strides() {
+        let size = HEADER + BITMAP + 64 * 64 * 4;
+        assert!(update(size, HEADER as u32, bitmap(8, 8, 31))
+            .bitmap
+            .is_none());
+        assert!(update(size, HEADER as u32, bitmap(8, 8, -32))
+            .bitmap
+            .is_none());
+        assert!(update(size, HEADER as u32, bitmap(0, 8, 32))
+            .bitmap
+            .is_none());
+    }
+}
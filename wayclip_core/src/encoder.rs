@@ -0,0 +1,470 @@
+use crate::settings::Settings;
+use crate::{log_to, logging::Logger};
+use anyhow::{bail, Result};
+use gstreamer::ElementFactory;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    H264,
+    H265,
+    Vp9,
+    Av1,
+}
+
+impl FromStr for VideoCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "h264" | "avc" => Ok(VideoCodec::H264),
+            "h265" | "hevc" => Ok(VideoCodec::H265),
+            "vp9" => Ok(VideoCodec::Vp9),
+            "av1" => Ok(VideoCodec::Av1),
+            other => bail!("Unknown video codec '{other}' (expected h264, h265, vp9 or av1)"),
+        }
+    }
+}
+
+impl fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VideoCodec::H264 => write!(f, "h264"),
+            VideoCodec::H265 => write!(f, "h265"),
+            VideoCodec::Vp9 => write!(f, "vp9"),
+            VideoCodec::Av1 => write!(f, "av1"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderBackend {
+    Nvenc,
+    Vaapi,
+    Software,
+}
+
+impl fmt::Display for EncoderBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncoderBackend::Nvenc => write!(f, "nvenc"),
+            EncoderBackend::Vaapi => write!(f, "vaapi"),
+            EncoderBackend::Software => write!(f, "software"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncoderPreference {
+    Auto,
+    Only(EncoderBackend),
+}
+
+impl FromStr for EncoderPreference {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "" | "auto" => Ok(EncoderPreference::Auto),
+            "nvenc" | "nvidia" => Ok(EncoderPreference::Only(EncoderBackend::Nvenc)),
+            "vaapi" | "va" => Ok(EncoderPreference::Only(EncoderBackend::Vaapi)),
+            "software" | "cpu" => Ok(EncoderPreference::Only(EncoderBackend::Software)),
+            other => {
+                bail!("Unknown encoder preference '{other}' (expected auto, nvenc, vaapi or software)")
+            }
+        }
+    }
+}
+
+impl EncoderPreference {
+    // Order in which backends are probed. The preferred one goes first, the rest
+    // follow so a missing plugin never leaves us without an encoder.
+    fn probe_order(&self) -> Vec<EncoderBackend> {
+        let mut order = vec![
+            EncoderBackend::Nvenc,
+            EncoderBackend::Vaapi,
+            EncoderBackend::Software,
+        ];
+        if let EncoderPreference::Only(preferred) = self {
+            order.retain(|b| b != preferred);
+            order.insert(0, *preferred);
+        }
+        order
+    }
+}
+
+// One way of encoding a codec on a backend. `factory` is the element we probe for,
+// `upload` is whatever has to happen to NV12 system memory frames before it.
+struct Candidate {
+    backend: EncoderBackend,
+    factory: &'static str,
+    upload: Option<&'static str>,
+}
+
+const fn candidate(
+    backend: EncoderBackend,
+    factory: &'static str,
+    upload: Option<&'static str>,
+) -> Candidate {
+    Candidate {
+        backend,
+        factory,
+        upload,
+    }
+}
+
+const I420: Option<&str> = Some("videoconvert ! video/x-raw,format=I420");
+
+use EncoderBackend::{Nvenc, Software, Vaapi};
+
+static H264_CANDIDATES: &[Candidate] = &[
+    candidate(Nvenc, "nvh264enc", Some("cudaupload")),
+    candidate(Vaapi, "vah264enc", None),
+    candidate(Vaapi, "vaapih264enc", None),
+    candidate(Software, "x264enc", None),
+];
+
+static H265_CANDIDATES: &[Candidate] = &[
+    candidate(Nvenc, "nvh265enc", Some("cudaupload")),
+    candidate(Vaapi, "vah265enc", None),
+    candidate(Vaapi, "vaapih265enc", None),
+    candidate(Software, "x265enc", I420),
+];
+
+static VP9_CANDIDATES: &[Candidate] = &[
+    candidate(Vaapi, "vavp9enc", None),
+    candidate(Vaapi, "vaapivp9enc", None),
+    candidate(Software, "vp9enc", I420),
+];
+
+static AV1_CANDIDATES: &[Candidate] = &[
+    candidate(Nvenc, "nvav1enc", Some("cudaupload")),
+    candidate(Vaapi, "vaav1enc", None),
+    candidate(Software, "av1enc", I420),
+];
+
+fn candidates(codec: VideoCodec) -> &'static [Candidate] {
+    match codec {
+        VideoCodec::H264 => H264_CANDIDATES,
+        VideoCodec::H265 => H265_CANDIDATES,
+        VideoCodec::Vp9 => VP9_CANDIDATES,
+        VideoCodec::Av1 => AV1_CANDIDATES,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoEncoder {
+    pub codec: VideoCodec,
+    pub backend: EncoderBackend,
+    pub factory: &'static str,
+    upload: Option<&'static str>,
+}
+
+impl VideoEncoder {
+    // Picks the first encoder that is actually installed, honoring the preference.
+    // gstreamer has to be initialized before calling this.
+    pub fn select(settings: &Settings, logger: &Logger) -> Result<Self> {
+        let codec: VideoCodec = settings.video_codec.parse()?;
+        let preference: EncoderPreference = settings.encoder_preference.parse()?;
+        Self::select_with(codec, preference, logger, |name| {
+            ElementFactory::find(name).is_some()
+        })
+    }
+
+    pub fn select_with(
+        codec: VideoCodec,
+        preference: EncoderPreference,
+        logger: &Logger,
+        is_available: impl Fn(&str) -> bool,
+    ) -> Result<Self> {
+        let options = candidates(codec);
+
+        for backend in preference.probe_order() {
+            for option in options.iter().filter(|c| c.backend == backend) {
+                if is_available(option.factory) {
+                    if let EncoderPreference::Only(preferred) = preference {
+                        if preferred != backend {
+                            log_to!(logger, Warn, [GST] => "No {} encoder available for {}, falling back to {}", preferred, codec, backend);
+                        }
+                    }
+                    log_to!(logger, Info, [GST] => "Using {} encoder '{}' for {}", backend, option.factory, codec);
+                    return Ok(Self {
+                        codec,
+                        backend,
+                        factory: option.factory,
+                        upload: option.upload,
+                    });
+                }
+                log_to!(logger, Debug, [GST] => "Encoder element '{}' not found, skipping", option.factory);
+            }
+        }
+
+        let tried: Vec<&str> = options.iter().map(|c| c.factory).collect();
+        bail!(
+            "No GStreamer encoder found for {codec}. Tried: {}. Install the matching plugin or pick another video_codec.",
+            tried.join(", ")
+        )
+    }
+
+    // Everything between the NV12 raw caps and the muxer pad.
+    // `bitrate` is in kbit/s, `keyframe_interval` in frames.
+    pub fn launch_segment(&self, bitrate: u32, keyframe_interval: u32) -> String {
+        let encoder = match self.factory {
+            "nvh264enc" | "nvh265enc" | "nvav1enc" => {
+                format!("{} bitrate={bitrate} gop-size={keyframe_interval}", self.factory)
+            }
+            "vah264enc" | "vah265enc" | "vavp9enc" | "vaav1enc" => format!(
+                "{} rate-control=cbr bitrate={bitrate} key-int-max={keyframe_interval}",
+                self.factory
+            ),
+            "vaapih264enc" | "vaapih265enc" | "vaapivp9enc" => format!(
+                "{} rate-control=cbr bitrate={bitrate} keyframe-period={keyframe_interval}",
+                self.factory
+            ),
+            "x264enc" => format!(
+                "x264enc bitrate={bitrate} speed-preset=ultrafast tune=zerolatency key-int-max={keyframe_interval}"
+            ),
+            "x265enc" => format!(
+                "x265enc bitrate={bitrate} speed-preset=ultrafast tune=zerolatency key-int-max={keyframe_interval}"
+            ),
+            "vp9enc" => format!(
+                "vp9enc target-bitrate={} end-usage=cbr deadline=1 cpu-used=8 keyframe-max-dist={keyframe_interval}",
+                bitrate * 1000
+            ),
+            "av1enc" => format!(
+                "av1enc target-bitrate={bitrate} end-usage=cbr usage-profile=realtime cpu-used=8 keyframe-max-dist={keyframe_interval}"
+            ),
+            other => other.to_string(),
+        };
+
+        let parser = match self.codec {
+            VideoCodec::H264 => Some("h264parse config-interval=-1"),
+            VideoCodec::H265 => Some("h265parse config-interval=-1"),
+            VideoCodec::Av1 => Some("av1parse"),
+            VideoCodec::Vp9 => None,
+        };
+
+        [self.upload, Some(encoder.as_str()), parser]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(" ! ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Aac,
+    Opus,
+    Flac,
+}
+
+impl FromStr for AudioCodec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "aac" => Ok(AudioCodec::Aac),
+            "opus" => Ok(AudioCodec::Opus),
+            "flac" => Ok(AudioCodec::Flac),
+            other => bail!("Unknown audio codec '{other}' (expected aac, opus or flac)"),
+        }
+    }
+}
+
+impl fmt::Display for AudioCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioCodec::Aac => write!(f, "aac"),
+            AudioCodec::Opus => write!(f, "opus"),
+            AudioCodec::Flac => write!(f, "flac"),
+        }
+    }
+}
+
+// bit/s, only used by the lossy codecs
+const AUDIO_BITRATE: u32 = 192_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioEncoder {
+    pub codec: AudioCodec,
+    pub factory: &'static str,
+}
+
+impl AudioEncoder {
+    pub fn select(settings: &Settings, logger: &Logger) -> Result<Self> {
+        let codec: AudioCodec = settings.audio_codec.parse()?;
+        Self::select_with(codec, logger, |name| ElementFactory::find(name).is_some())
+    }
+
+    pub fn select_with(
+        codec: AudioCodec,
+        logger: &Logger,
+        is_available: impl Fn(&str) -> bool,
+    ) -> Result<Self> {
+        let options: &[&'static str] = match codec {
+            AudioCodec::Aac => &["avenc_aac", "fdkaacenc", "voaacenc"],
+            AudioCodec::Opus => &["opusenc"],
+            AudioCodec::Flac => &["flacenc"],
+        };
+
+        for factory in options {
+            if is_available(factory) {
+                log_to!(logger, Info, [GST] => "Using audio encoder '{}' for {}", factory, codec);
+                return Ok(Self { codec, factory });
+            }
+            log_to!(logger, Debug, [GST] => "Encoder element '{}' not found, skipping", factory);
+        }
+
+        bail!(
+            "No GStreamer encoder found for {codec}. Tried: {}. Install the matching plugin or pick another audio_codec.",
+            options.join(", ")
+        )
+    }
+
+    // Everything between the mixed raw audio and the muxer pad.
+    pub fn launch_segment(&self) -> String {
+        match self.codec {
+            AudioCodec::Aac => format!("{} bitrate={AUDIO_BITRATE} ! aacparse", self.factory),
+            AudioCodec::Opus => format!("{} bitrate={AUDIO_BITRATE} ! opusparse", self.factory),
+            AudioCodec::Flac => format!("{} ! flacparse", self.factory),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+    WebM,
+    Mov,
+}
+
+impl FromStr for Container {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "mp4" => Ok(Container::Mp4),
+            "mkv" | "matroska" => Ok(Container::Matroska),
+            "webm" => Ok(Container::WebM),
+            "mov" | "quicktime" => Ok(Container::Mov),
+            other => bail!("Unknown container '{other}' (expected mp4, mkv, webm or mov)"),
+        }
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.extension())
+    }
+}
+
+impl Container {
+    pub const ALL: [Container; 4] = [
+        Container::Mp4,
+        Container::Matroska,
+        Container::WebM,
+        Container::Mov,
+    ];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::Matroska => "mkv",
+            Container::WebM => "webm",
+            Container::Mov => "mov",
+        }
+    }
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|container| container.extension().eq_ignore_ascii_case(extension))
+    }
+
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Self::from_extension)
+    }
+
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "video/mp4",
+            Container::Matroska => "video/x-matroska",
+            Container::WebM => "video/webm",
+            Container::Mov => "video/quicktime",
+        }
+    }
+
+    // The gstreamer muxer the saved clip is remuxed with.
+    pub fn muxer(&self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4mux",
+            Container::Matroska => "matroskamux",
+            Container::WebM => "webmmux",
+            Container::Mov => "qtmux",
+        }
+    }
+
+    // MP4 and MOV put the moov atom at the end unless asked, players then can't start
+    // before the whole file is loaded.
+    pub fn supports_faststart(&self) -> bool {
+        matches!(self, Container::Mp4 | Container::Mov)
+    }
+
+    pub fn supports_video(&self, codec: VideoCodec) -> bool {
+        match self {
+            Container::Mp4 | Container::Matroska => matches!(
+                codec,
+                VideoCodec::H264 | VideoCodec::H265 | VideoCodec::Vp9 | VideoCodec::Av1
+            ),
+            Container::WebM => matches!(codec, VideoCodec::Vp9 | VideoCodec::Av1),
+            Container::Mov => matches!(codec, VideoCodec::H264 | VideoCodec::H265),
+        }
+    }
+
+    // Opus and FLAC in MP4 are still flagged experimental by ffmpeg and plenty of
+    // editors and browsers refuse to play them, so MP4 only takes AAC.
+    pub fn supports_audio(&self, codec: AudioCodec) -> bool {
+        match self {
+            Container::Mp4 | Container::Mov => codec == AudioCodec::Aac,
+            Container::Matroska => true,
+            Container::WebM => codec == AudioCodec::Opus,
+        }
+    }
+
+    pub fn check(&self, video: Option<VideoCodec>, audio: Option<AudioCodec>) -> Result<()> {
+        if let Some(video) = video {
+            if !self.supports_video(video) {
+                bail!(
+                    "Video codec {video} can't be stored in a .{} file",
+                    self.extension()
+                );
+            }
+        }
+        if let Some(audio) = audio {
+            if !self.supports_audio(audio) {
+                bail!(
+                    "Audio codec {audio} can't be stored in a .{} file",
+                    self.extension()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Validates the configured codecs, encoder preference and output container without
+// touching gstreamer.
+pub fn check_settings(settings: &Settings) -> Result<()> {
+    settings.encoder_preference.parse::<EncoderPreference>()?;
+    let video: VideoCodec = settings.video_codec.parse()?;
+    let audio: AudioCodec = settings.audio_codec.parse()?;
+    let container: Container = settings.output_container.parse()?;
+    let has_audio = settings.include_bg_audio || settings.include_mic_audio;
+    container.check(Some(video), has_audio.then_some(audio))
+}
@@ -1,3 +1,5 @@
+use crate::audio::{AudioNode, MediaClass};
+use crate::encoder::Container;
 use crate::logging::Logger;
 use crate::models::UnifiedClipData;
 use crate::settings::Settings;
@@ -14,14 +16,12 @@ use ffmpeg_next::util::frame::video::Video;
 use futures::stream::{FuturesUnordered, StreamExt};
 use gstreamer::prelude::{Cast, ElementExt, GstObjectExt};
 use image::{ImageFormat, RgbImage};
-use mp4::Mp4Reader;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt;
-use std::fs::{remove_file, File};
-use std::io::BufReader;
+use std::fs::remove_file;
 use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::process::Stdio;
@@ -47,10 +47,19 @@ pub const DEBUG: &str = "\x1b[93m[debug]\x1b[0m"; // idk
 pub const AUTH: &str = "\x1b[94m[auth]\x1b[0m"; // idk
 
 pub mod api;
+pub mod audio;
+pub mod capture;
 pub mod control;
+pub mod cursor;
+pub mod encoder;
 pub mod logging;
 pub mod models;
+pub mod pidfile;
+pub mod pipeline;
+pub mod protocol;
+pub mod remux;
 pub mod ring;
+pub mod runtime;
 pub mod settings;
 
 pub const WAYCLIP_TRIGGER_PATH: &str = "/home/kony/Documents/GitHub/wayclip/target/debug/trigger";
@@ -88,6 +97,22 @@ pub struct AudioDevice {
     pub id: u32,
     pub name: String,
     pub node_name: String,
+    pub media_class: MediaClass,
+    pub channels: Vec<String>,
+    pub is_default: bool,
+}
+
+impl From<&AudioNode> for AudioDevice {
+    fn from(node: &AudioNode) -> Self {
+        Self {
+            id: node.id,
+            name: node.description.clone(),
+            node_name: node.node_name.clone(),
+            media_class: node.media_class,
+            channels: node.channels.clone(),
+            is_default: node.is_default,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -169,7 +194,9 @@ pub fn send_status_to_gui(socket_path: String, message: String, logger: &Logger)
     });
 }
 
-pub async fn handle_bus_messages(pipeline: gstreamer::Pipeline, logger: Logger) {
+// Watches the pipeline bus until it errors or ends, returning why so the caller can
+// rebuild it. `None` means the bus itself went away.
+pub async fn handle_bus_messages(pipeline: gstreamer::Pipeline, logger: Logger) -> Option<String> {
     let bus = pipeline.bus().unwrap();
     let mut bus_stream = bus.stream();
 
@@ -194,7 +221,7 @@ pub async fn handle_bus_messages(pipeline: gstreamer::Pipeline, logger: Logger)
                 {
                     log_to!(logger, Warn, [GSTBUS] => "Detected format negotiation failure (PipeWire -> GStreamer). Consider allowing automatic format negotiation (remove rigid caps on pipewiresrc) or recreating the pipeline.");
                 }
-                break;
+                return Some(format!("{src_name}: {error_msg}"));
             }
             MessageView::Warning(warning) => {
                 let src_name = warning
@@ -209,7 +236,7 @@ pub async fn handle_bus_messages(pipeline: gstreamer::Pipeline, logger: Logger)
             }
             MessageView::Eos(_) => {
                 log_to!(logger, Info, [GSTBUS] => "Received End-Of-Stream");
-                break;
+                return Some(String::from("End of stream"));
             }
             MessageView::StateChanged(state) => {
                 if state
@@ -224,6 +251,7 @@ pub async fn handle_bus_messages(pipeline: gstreamer::Pipeline, logger: Logger)
         }
     }
     log_to!(logger, Info, [GSTBUS] => "Stopped bus message handler.");
+    None
 }
 
 pub async fn setup_hyprland(logger: &Logger) {
@@ -253,7 +281,7 @@ pub async fn setup_hyprland(logger: &Logger) {
 
 pub async fn cleanup(
     pipeline: &gstreamer::Element,
-    session: &Session<'_, Screencast<'_>>,
+    session: Option<&Session<'_, Screencast<'_>>>,
     settings: Settings,
     logger: Logger,
 ) {
@@ -289,10 +317,12 @@ pub async fn cleanup(
         log_to!(logger, Info, [GST] => "Pipeline set to null");
     }
 
-    if let Err(e) = session.close().await {
-        log_to!(logger, Error, [ASH] => "Failed to close screencast session, {}", e);
-    } else {
-        log_to!(logger, Info, [ASH] => "Screencast session closed successfully");
+    if let Some(session) = session {
+        if let Err(e) = session.close().await {
+            log_to!(logger, Error, [ASH] => "Failed to close screencast session, {}", e);
+        } else {
+            log_to!(logger, Info, [ASH] => "Screencast session closed successfully");
+        }
     }
 
     if let Err(e) = remove_file(settings.daemon_socket_path.clone()) {
@@ -301,12 +331,6 @@ pub async fn cleanup(
         log_to!(logger, Info, [UNIX] => "Daemon socket file removed");
     }
 
-    if let Err(e) = remove_file(&settings.daemon_pid_path) {
-        log_to!(logger, Warn, [UNIX] => "Failed to remove daemon PID file, {}", e);
-    } else {
-        log_to!(logger, Info, [UNIX] => "Daemon PID file removed");
-    }
-
     send_status_to_gui(
         settings.gui_socket_path.clone(),
         String::from("Inactive"),
@@ -356,7 +380,7 @@ pub async fn gather_clip_data(level: Collect, args: PullClipsArgs) -> Result<Pag
     let mut all_file_paths = Vec::new();
     while let Some(entry) = dir.next_entry().await? {
         let path = entry.path();
-        if path.is_file() && path.extension().and_then(|s| s.to_str()) == Some("mp4") {
+        if is_clip_file(&path) {
             all_file_paths.push(path);
         }
     }
@@ -441,7 +465,7 @@ pub async fn gather_clip_data(level: Collect, args: PullClipsArgs) -> Result<Pag
                 .unwrap_or_else(|_| Local::now());
 
             Ok(Some(ClipData {
-                name: name.strip_suffix(".mp4").unwrap_or(&name).to_string(),
+                name: clip_stem(&name).to_string(),
                 path: path_clone.to_str().unwrap_or_default().to_owned(),
                 length,
                 size: metadata.len(),
@@ -487,22 +511,17 @@ pub async fn gather_clip_data(level: Collect, args: PullClipsArgs) -> Result<Pag
 }
 
 pub async fn get_video_duration(path: &Path) -> Result<f64> {
+    ffmpeg_next::init().context("Failed to initialize ffmpeg")?;
     let path_buf = path.to_path_buf();
 
+    // Read from the container header, works the same for every supported container.
     let result = tokio::task::spawn_blocking(move || -> Result<f64> {
-        let file = File::open(&path_buf)
+        let ictx = input(&path_buf)
             .with_context(|| format!("Failed to open file for duration check: {path_buf:?}"))?;
-        let size = file.metadata()?.len();
-        let reader = BufReader::new(file);
-
-        let mp4 = Mp4Reader::read_header(reader, size)
-            .with_context(|| format!("Failed to read MP4 header for: {path_buf:?}"))?;
-
-        let duration = mp4.moov.mvhd.duration;
-        let timescale = mp4.moov.mvhd.timescale;
+        let duration = ictx.duration();
 
-        if timescale > 0 {
-            Ok(duration as f64 / timescale as f64)
+        if duration > 0 {
+            Ok(duration as f64 / f64::from(ffmpeg_next::ffi::AV_TIME_BASE))
         } else {
             Ok(0.0)
         }
@@ -519,6 +538,19 @@ pub async fn get_video_duration(path: &Path) -> Result<f64> {
     }
 }
 
+// Clips are any file in the clips directory with one of the output container extensions.
+pub fn is_clip_file(path: &Path) -> bool {
+    path.is_file() && Container::from_path(path).is_some()
+}
+
+// Strips a known container extension, other dots are part of the name.
+pub fn clip_stem(file_name: &str) -> &str {
+    match file_name.rsplit_once('.') {
+        Some((stem, ext)) if Container::from_extension(ext).is_some() => stem,
+        _ => file_name,
+    }
+}
+
 async fn write_json_data(path: &Path, data: &Value) -> Result<()> {
     let content = serde_json::to_string_pretty(data)?;
     let mut file = fs::OpenOptions::new()
@@ -679,7 +711,7 @@ pub async fn generate_all_previews() -> Result<()> {
         let path = entry.path();
         let name = entry.file_name().to_string_lossy().into_owned();
 
-        if !path.is_file() || !name.to_lowercase().ends_with(".mp4") {
+        if !is_clip_file(&path) {
             continue;
         }
 
@@ -702,7 +734,11 @@ pub async fn rename_all_entries(path_str: &str, new_name: &str) -> Result<(), St
     let previews_path = Settings::config_path().join("wayclip").join("previews");
 
     let original_path = Path::new(path_str);
-    let new_path = original_path.with_file_name(new_name);
+    // A renamed clip keeps its container, whatever extension the new name came with.
+    let new_path = match original_path.extension().and_then(|s| s.to_str()) {
+        Some(ext) => original_path.with_file_name(format!("{}.{ext}", clip_stem(new_name))),
+        None => original_path.with_file_name(new_name),
+    };
 
     // Main
     if let Err(e) = fs::rename(&original_path, &new_path).await {
@@ -759,166 +795,21 @@ pub async fn rename_all_entries(path_str: &str, new_name: &str) -> Result<(), St
 }
 
 pub async fn get_all_audio_devices() -> Result<Vec<AudioDevice>, String> {
-    let output = Command::new("pw-cli")
-        .args(["ls", "Node"])
-        .output()
-        .await
-        .map_err(|e| format!("Failed to run pw-cli: {e}"))?;
-
-    if !output.status.success() {
-        return Err(format!(
-            "pw-cli error: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ));
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-
-    let mut devices = Vec::new();
-    let mut current_id: Option<u32> = None;
-    let mut current_name: Option<String> = None;
-    let mut current_class: Option<String> = None;
-    let mut current_node_name: Option<String> = None;
-
-    for line in stdout.lines() {
-        let trimmed_line = line.trim();
-
-        if trimmed_line.starts_with("id ") {
-            if let (Some(id), Some(name), Some(class), Some(node_name)) = (
-                current_id,
-                &current_name,
-                &current_class,
-                &current_node_name,
-            ) {
-                if class == "Audio/Source" || class == "Audio/Sink" {
-                    devices.push(AudioDevice {
-                        id,
-                        name: name.clone(),
-                        node_name: node_name.clone(),
-                    });
-                }
-            }
-
-            current_id = None;
-            current_name = None;
-            current_class = None;
-            current_node_name = None;
-
-            if let Some(rest) = trimmed_line.strip_prefix("id ") {
-                if let Some((id_str, _)) = rest.split_once(',') {
-                    if let Ok(id) = id_str.trim().parse::<u32>() {
-                        current_id = Some(id);
-                    }
-                }
-            }
-        } else if trimmed_line.contains("media.class") {
-            if let Some((_, class)) = trimmed_line.split_once('=') {
-                current_class.get_or_insert_with(|| class.trim().trim_matches('"').to_string());
-            }
-        } else if trimmed_line.contains("node.description")
-            || trimmed_line.contains("device.description")
-        {
-            if let Some((_, name)) = trimmed_line.split_once('=') {
-                current_name.get_or_insert_with(|| name.trim().trim_matches('"').to_string());
-            }
-        } else if trimmed_line.contains("node.name") {
-            if let Some((_, node_name)) = trimmed_line.split_once('=') {
-                current_node_name
-                    .get_or_insert_with(|| node_name.trim().trim_matches('"').to_string());
-            }
-        }
-    }
-
-    if let (Some(id), Some(name), Some(class), Some(node_name)) = (
-        current_id,
-        &current_name,
-        &current_class,
-        &current_node_name,
-    ) {
-        if class == "Audio/Source" || class == "Audio/Sink" {
-            devices.push(AudioDevice {
-                id,
-                name: name.clone(),
-                node_name: node_name.clone(),
-            });
-        }
-    }
-
-    Ok(devices)
-}
-
-async fn get_default_audio_devices() -> Result<(Option<String>, Option<String>), String> {
-    let output = Command::new("pactl")
-        .arg("info")
-        .output()
-        .await
-        .map_err(|e| format!("Failed to run pactl: {e}. Is it installed?"))?;
-
-    if !output.status.success() {
-        return Err(format!(
-            "pactl error: {}",
-            String::from_utf8_lossy(&output.stderr)
-        ));
-    }
-
-    let stdout = String::from_utf8_lossy(&output.stdout);
-    let mut default_source = None;
-    let mut default_sink = None;
-
-    for line in stdout.lines() {
-        if let Some(name) = line.strip_prefix("Default Source: ") {
-            default_source = Some(name.trim().to_string());
-        } else if let Some(name) = line.strip_prefix("Default Sink: ") {
-            default_sink = Some(name.trim().to_string());
-        }
-    }
-
-    Ok((default_source, default_sink))
+    let graph = audio::query().await.map_err(|e| format!("{e:#}"))?;
+    Ok(graph.nodes.iter().map(AudioDevice::from).collect())
 }
 
 pub async fn get_pipewire_node_id(
     node_name: &String,
     logger: &Logger,
 ) -> Result<u32, Box<dyn Error>> {
-    let output = Command::new("pw-cli")
-        .arg("ls")
-        .arg("Node")
-        .output()
-        .await?;
-
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        let err_msg = format!("'pw-cli ls Node' command failed: {stderr}");
-        log_to!(logger, Error, [DAEMON] => "{}", err_msg);
-        return Err(err_msg.into());
-    }
-
-    let stdout = String::from_utf8(output.stdout)?;
-
-    let mut current_id: Option<u32> = None;
-    for line in stdout.lines() {
-        let trimmed_line = line.trim();
-
-        if trimmed_line.starts_with("id ") {
-            current_id = trimmed_line
-                .split(|c: char| c.is_whitespace() || c == ',')
-                .nth(1)
-                .and_then(|id_str| id_str.parse::<u32>().ok());
-        } else if let Some(id) = current_id {
-            if trimmed_line.starts_with("node.name =") {
-                let name_value = trimmed_line.split_once("=").map(|x| x.1);
-
-                if let Some(name) = name_value {
-                    let extracted_name = name.trim().trim_matches('"');
-                    if extracted_name == node_name {
-                        return Ok(id);
-                    }
-                }
-            }
-        }
-    }
-
-    let err_msg = format!("PipeWire node with name '{node_name}' not found");
+    let err_msg = match audio::query().await {
+        Ok(graph) => match graph.find(node_name) {
+            Some(node) => return Ok(node.id),
+            None => format!("PipeWire node with name '{node_name}' not found"),
+        },
+        Err(e) => format!("{e:#}"),
+    };
     log_to!(logger, Error, [DAEMON] => "{}", err_msg);
     Err(err_msg.into())
 }
@@ -1060,10 +951,7 @@ pub async fn gather_unified_clips() -> Result<Vec<UnifiedClipData>> {
             existing_clip.is_hosted = true;
             existing_clip.hosted_id = Some(hosted_id);
         } else {
-            let name_without_ext = filename
-                .strip_suffix(".mp4")
-                .unwrap_or(&filename)
-                .to_string();
+            let name_without_ext = clip_stem(&filename).to_string();
             unified_map.insert(
                 filename.clone(),
                 UnifiedClipData {
@@ -0,0 +1,92 @@
+use crate::runtime::prepare_runtime_dir;
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process;
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+// Held by the running daemon. The kernel drops a flock with the process, so a PID left
+// behind by a crash never keeps the next daemon from starting.
+pub struct PidLock {
+    file: Flock<File>,
+    // What a daemon that died without clearing the file left in it.
+    pub stale_pid: Option<u32>,
+}
+
+impl PidLock {
+    pub fn acquire(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        prepare_runtime_dir(path)
+            .with_context(|| format!("Failed to prepare the directory of {}", path.display()))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to open PID file {}", path.display()))?;
+
+        let mut file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+            Ok(file) => file,
+            Err((mut file, Errno::EWOULDBLOCK)) => match read_pid(&mut file) {
+                Some(pid) => bail!("Another wayclip daemon is already running (PID {pid})"),
+                None => bail!(
+                    "Another wayclip daemon is already running (it holds {})",
+                    path.display()
+                ),
+            },
+            Err((_, e)) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to lock PID file {}", path.display()))
+            }
+        };
+
+        let stale_pid = read_pid(&mut file).filter(|&pid| pid != process::id());
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}", process::id())?;
+        file.sync_all()?;
+        Ok(Self { file, stale_pid })
+    }
+}
+
+impl Drop for PidLock {
+    // Emptied instead of removed, unlinking a locked file would let a second daemon lock
+    // a new one at the same path while the first is still shutting down.
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+}
+
+// The PID of the daemon holding the lock on `path`, None when no daemon is running.
+pub fn running_pid(path: impl AsRef<Path>) -> Result<Option<u32>> {
+    let path = path.as_ref();
+    let Ok(file) = File::open(path) else {
+        return Ok(None);
+    };
+    match Flock::lock(file, FlockArg::LockSharedNonblock) {
+        // Nobody holds it, whatever PID is in there is stale. Unlocked again on drop.
+        Ok(_) => Ok(None),
+        Err((mut file, Errno::EWOULDBLOCK)) => match read_pid(&mut file) {
+            Some(pid) => Ok(Some(pid)),
+            None => bail!(
+                "A daemon holds {} but has not written its PID yet",
+                path.display()
+            ),
+        },
+        Err((_, e)) => {
+            Err(e).with_context(|| format!("Failed to check PID file {}", path.display()))
+        }
+    }
+}
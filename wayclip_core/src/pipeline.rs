@@ -0,0 +1,801 @@
+use crate::audio::MicProcessing;
+use crate::capture::{test_audio_segment, test_video_segment, video_source_segment, VideoStream};
+use crate::cursor::screen_source_segment;
+use crate::encoder::{AudioEncoder, Container, VideoEncoder};
+use crate::protocol::AudioSourceKind;
+use crate::settings::Settings;
+use crate::{log_to, logging::Logger};
+use anyhow::{bail, Context, Result};
+use std::os::fd::RawFd;
+
+// NV12 needs even sizes, and no encoder we pick goes past 8K.
+const MAX_WIDTH: u32 = 7680;
+const MAX_HEIGHT: u32 = 4320;
+const MAX_FPS: u16 = 240;
+// Below this in kbit/s even 720p turns to mush.
+const MIN_VIDEO_BITRATE: u16 = 500;
+
+// Parses `WIDTHxHEIGHT`, e.g. 1920x1080.
+pub fn parse_resolution(resolution: &str) -> Result<(u32, u32)> {
+    let Some((width, height)) = resolution.trim().split_once('x') else {
+        bail!("Invalid resolution '{resolution}' (expected WIDTHxHEIGHT, e.g. 1920x1080)");
+    };
+    let width: u32 = width
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid width in resolution '{resolution}'"))?;
+    let height: u32 = height
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid height in resolution '{resolution}'"))?;
+
+    if width == 0 || height == 0 || width > MAX_WIDTH || height > MAX_HEIGHT {
+        bail!("Resolution {width}x{height} is out of range (up to {MAX_WIDTH}x{MAX_HEIGHT})");
+    }
+    if width % 2 != 0 || height % 2 != 0 {
+        bail!("Resolution {width}x{height} must have an even width and height");
+    }
+    Ok((width, height))
+}
+
+pub fn check_fps(fps: u16) -> Result<()> {
+    if fps == 0 || fps > MAX_FPS {
+        bail!("Clip FPS must be between 1 and {MAX_FPS}, got {fps}");
+    }
+    Ok(())
+}
+
+pub fn check_video_bitrate(bitrate: u16) -> Result<()> {
+    if bitrate < MIN_VIDEO_BITRATE {
+        bail!("Video bitrate must be at least {MIN_VIDEO_BITRATE} kbps, got {bitrate}");
+    }
+    Ok(())
+}
+
+// Where the frames come from, resolved by the daemon from its capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VideoInput {
+    PipeWire {
+        fd: RawFd,
+        streams: Vec<VideoStream>,
+        // Leaves room for an `overlaycomposition name=cursor` to draw the pointer on.
+        draw_cursor: bool,
+    },
+    Test,
+}
+
+// Where a source's audio comes from, its PipeWire node or a test tone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioInput {
+    PipeWire { node_id: u32 },
+    Test,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioSourceConfig {
+    pub kind: AudioSourceKind,
+    pub input: AudioInput,
+    pub volume: u8,
+    pub muted: bool,
+    // Processing between the resampler and the volume element, empty for none.
+    pub filters: String,
+}
+
+impl AudioSourceConfig {
+    pub fn title(&self) -> &'static str {
+        match self.kind {
+            AudioSourceKind::Bg => "Desktop",
+            AudioSourceKind::Mic => "Microphone",
+        }
+    }
+
+    fn mix_pad(&self) -> &'static str {
+        match self.kind {
+            AudioSourceKind::Bg => "sink_0",
+            AudioSourceKind::Mic => "sink_1",
+        }
+    }
+
+    // Source element up to the raw audio, named `{kind}_src` so it can be re-linked.
+    fn origin(&self) -> String {
+        let name = self.kind.to_string();
+        match self.input {
+            AudioInput::PipeWire { node_id } => {
+                format!("pipewiresrc name={name}_src do-timestamp=true path={node_id}")
+            }
+            AudioInput::Test => test_audio_segment(&name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    pub encoder: AudioEncoder,
+    pub sources: Vec<AudioSourceConfig>,
+    pub separate_tracks: bool,
+    // Only read with separate tracks, a single track is always the mix.
+    pub mixed_track: bool,
+}
+
+impl AudioConfig {
+    fn with_mix(&self) -> bool {
+        !self.separate_tracks || self.mixed_track
+    }
+
+    // Track titles in muxer pad order.
+    pub fn track_titles(&self) -> Vec<&'static str> {
+        let mut titles = Vec::new();
+        if self.with_mix() {
+            titles.push("Mixed");
+        }
+        if self.separate_tracks {
+            titles.extend(self.sources.iter().map(AudioSourceConfig::title));
+        }
+        titles
+    }
+
+    fn push_launch_parts(&self, parts: &mut Vec<String>) {
+        let mut track = 0;
+
+        if self.with_mix() {
+            let title = if self.separate_tracks {
+                track_title("Mixed")
+            } else {
+                String::new()
+            };
+            parts.push(format!(
+                "audiomixer name=mix ! audioconvert ! audio/x-raw,channels=2 ! {title}{} ! queue ! mux.audio_{track}",
+                self.encoder.launch_segment(),
+            ));
+            track += 1;
+        }
+
+        for source in &self.sources {
+            let name = source.kind;
+            parts.push(format!(
+                "{origin} ! \
+                queue ! \
+                audio/x-raw,rate=48000,channels=2 ! \
+                audioconvert ! audioresample ! {filters}\
+                volume name={name}_volume volume={volume} mute={muted} ! \
+                tee name={name}_tee",
+                origin = source.origin(),
+                filters = source.filters,
+                volume = source.volume as f64 / 100.0,
+                muted = source.muted,
+            ));
+
+            if self.with_mix() {
+                parts.push(format!("{name}_tee. ! queue ! mix.{}", source.mix_pad()));
+            }
+
+            if self.separate_tracks {
+                parts.push(format!(
+                    "{name}_tee. ! queue ! audioconvert ! audio/x-raw,channels=2 ! {}{} ! queue ! mux.audio_{track}",
+                    track_title(source.title()),
+                    self.encoder.launch_segment(),
+                ));
+                track += 1;
+            }
+        }
+    }
+}
+
+// Stream scoped title tag, matroskamux turns it into the track name.
+fn track_title(title: &str) -> String {
+    format!("taginject tags=\"title={title}\" scope=stream ! ")
+}
+
+// Everything the recording pipeline is made of, checked up front so building the
+// launch string can't go wrong halfway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipelineConfig {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u16,
+    // kbit/s
+    pub video_bitrate: u16,
+    pub video_encoder: VideoEncoder,
+    pub video_input: VideoInput,
+    // None when neither desktop nor mic audio is recorded.
+    pub audio: Option<AudioConfig>,
+}
+
+impl PipelineConfig {
+    // Picks the encoders and mic processing for `settings`. `audio_inputs` are the
+    // sources that could be resolved, enabled ones missing from it are left out.
+    // gstreamer has to be initialized before calling this.
+    pub fn from_settings(
+        settings: &Settings,
+        video_input: VideoInput,
+        audio_inputs: &[(AudioSourceKind, AudioInput)],
+        logger: &Logger,
+    ) -> Result<Self> {
+        let (width, height) = parse_resolution(&settings.clip_resolution)?;
+        log_to!(logger, Info, [GST] => "Setting output resolution to {}x{}", width, height);
+        let video_encoder = VideoEncoder::select(settings, logger)?;
+
+        let has_audio = settings.include_bg_audio || settings.include_mic_audio;
+        let audio = if has_audio {
+            let mut sources = Vec::new();
+            for &(kind, input) in audio_inputs {
+                let (enabled, volume, muted) = match kind {
+                    AudioSourceKind::Bg => (
+                        settings.include_bg_audio,
+                        settings.bg_volume,
+                        settings.bg_muted,
+                    ),
+                    AudioSourceKind::Mic => (
+                        settings.include_mic_audio,
+                        settings.mic_volume,
+                        settings.mic_muted,
+                    ),
+                };
+                if !enabled {
+                    continue;
+                }
+                let filters = match kind {
+                    AudioSourceKind::Mic => {
+                        MicProcessing::select(settings, logger)?.launch_segment()
+                    }
+                    AudioSourceKind::Bg => String::new(),
+                };
+                sources.push(AudioSourceConfig {
+                    kind,
+                    input,
+                    volume,
+                    muted,
+                    filters,
+                });
+            }
+            Some(AudioConfig {
+                encoder: AudioEncoder::select(settings, logger)?,
+                sources,
+                separate_tracks: settings.separate_audio_tracks,
+                mixed_track: settings.include_mixed_track,
+            })
+        } else {
+            None
+        };
+
+        let config = Self {
+            width,
+            height,
+            fps: settings.clip_fps,
+            video_bitrate: settings.video_bitrate,
+            video_encoder,
+            video_input,
+            audio,
+        };
+        config.validate()?;
+
+        let container: Container = settings.output_container.parse()?;
+        container.check(
+            Some(config.video_encoder.codec),
+            config.audio.as_ref().map(|a| a.encoder.codec),
+        )?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        parse_resolution(&format!("{}x{}", self.width, self.height))?;
+        check_fps(self.fps)?;
+        check_video_bitrate(self.video_bitrate)?;
+
+        if let VideoInput::PipeWire { streams, .. } = &self.video_input {
+            if streams.is_empty() {
+                bail!("No video streams to record");
+            }
+        }
+        if let Some(audio) = &self.audio {
+            for (index, source) in audio.sources.iter().enumerate() {
+                if audio.sources[..index].iter().any(|s| s.kind == source.kind) {
+                    bail!("Audio source {} is listed twice", source.kind);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Whether `other` writes the same tracks with the same caps, so its clusters can
+    // follow ours behind our header. Where frames and audio come from doesn't matter.
+    pub fn same_output(&self, other: &Self) -> bool {
+        let audio_tracks = |config: &Self| {
+            config
+                .audio
+                .as_ref()
+                .map(|audio| (audio.encoder.clone(), audio.track_titles()))
+        };
+        self.width == other.width
+            && self.height == other.height
+            && self.fps == other.fps
+            && self.video_bitrate == other.video_bitrate
+            && self.video_encoder == other.video_encoder
+            && audio_tracks(self) == audio_tracks(other)
+    }
+
+    // The cursor can only be drawn on a single stream, where its positions apply.
+    pub fn draws_cursor(&self) -> bool {
+        matches!(&self.video_input, VideoInput::PipeWire { streams, draw_cursor: true, .. } if streams.len() == 1)
+    }
+
+    // Ends in an `appsink name=sink` getting matroska chunks. With a cursor the frames come
+    // from `appsrc name=screen` and it is drawn by `overlaycomposition name=cursor`.
+    pub fn launch_string(&self) -> String {
+        let mut parts =
+            vec!["matroskamux name=mux ! queue max-size-buffers=2 ! appsink name=sink".to_string()];
+
+        let video_source = match &self.video_input {
+            VideoInput::PipeWire { .. } if self.draws_cursor() => screen_source_segment(),
+            VideoInput::PipeWire { fd, streams, .. } => {
+                video_source_segment(*fd, streams, &mut parts)
+            }
+            VideoInput::Test => test_video_segment(),
+        };
+        let cursor_overlay = if self.draws_cursor() {
+            "videoconvert ! overlaycomposition name=cursor ! "
+        } else {
+            ""
+        };
+        parts.push(format!(
+            "{video_source}{cursor_overlay}videoconvert ! videoscale add-borders=true ! \
+            video/x-raw,width={width},height={height},pixel-aspect-ratio=1/1,format=(string)NV12 ! \
+            videorate ! video/x-raw,framerate={fps}/1 ! \
+            queue max-size-buffers=8 leaky=downstream ! \
+            {encoder} ! queue ! mux.video_0",
+            width = self.width,
+            height = self.height,
+            fps = self.fps,
+            encoder = self
+                .video_encoder
+                .launch_segment(self.video_bitrate as u32, self.fps as u32 * 2),
+        ));
+
+        if let Some(audio) = &self.audio {
+            audio.push_launch_parts(&mut parts);
+        }
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::{AudioCodec, EncoderPreference, VideoCodec};
+    use std::env;
+    use std::process;
+
+    const SINK: &str = "matroskamux name=mux ! queue max-size-buffers=2 ! appsink name=sink";
+
+    fn logger() -> Logger {
+        let log = env::temp_dir().join(format!("wayclip-pipeline-test-{}.log", process::id()));
+        Logger::new(log).unwrap()
+    }
+
+    // The encoder picked for `codec` when `factory` is the only one installed.
+    fn video_encoder(codec: VideoCodec, factory: &str) -> VideoEncoder {
+        VideoEncoder::select_with(codec, EncoderPreference::Auto, &logger(), |name| {
+            name == factory
+        })
+        .unwrap()
+    }
+
+    fn source(kind: AudioSourceKind) -> AudioSourceConfig {
+        let node_id = match kind {
+            AudioSourceKind::Bg => 40,
+            AudioSourceKind::Mic => 41,
+        };
+        AudioSourceConfig {
+            kind,
+            input: AudioInput::PipeWire { node_id },
+            volume: 100,
+            muted: false,
+            filters: String::new(),
+        }
+    }
+
+    fn audio(sources: &[AudioSourceKind], separate_tracks: bool, mixed_track: bool) -> AudioConfig {
+        AudioConfig {
+            encoder: AudioEncoder {
+                codec: AudioCodec::Aac,
+                factory: "avenc_aac",
+            },
+            sources: sources.iter().copied().map(source).collect(),
+            separate_tracks,
+            mixed_track,
+        }
+    }
+
+    fn config(video_input: VideoInput, audio: Option<AudioConfig>) -> PipelineConfig {
+        PipelineConfig {
+            width: 1280,
+            height: 720,
+            fps: 30,
+            video_bitrate: 8000,
+            video_encoder: video_encoder(VideoCodec::H264, "x264enc"),
+            video_input,
+            audio,
+        }
+    }
+
+    fn stream(node_id: u32, position: Option<(i32, i32)>, size: (i32, i32)) -> VideoStream {
+        VideoStream {
+            node_id,
+            position,
+            size: Some(size),
+            pixels: None,
+        }
+    }
+
+    // Muxer pads the audio tracks are linked to, in launch string order.
+    fn audio_pads(launch: &str) -> Vec<&str> {
+        launch
+            .match_indices("mux.audio_")
+            .map(|(index, _)| launch[index..].split_whitespace().next().unwrap())
+            .collect()
+    }
+
+    fn track_titles(launch: &str) -> Vec<&str> {
+        launch
+            .split("title=")
+            .skip(1)
+            .map(|rest| rest.split('"').next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn audio_layouts() {
+        use AudioSourceKind::{Bg, Mic};
+
+        struct Case {
+            name: &'static str,
+            sources: &'static [AudioSourceKind],
+            separate_tracks: bool,
+            mixed_track: bool,
+            pads: &'static [&'static str],
+            tracks: &'static [&'static str],
+            mixer: bool,
+        }
+        let cases = [
+            Case {
+                name: "desktop only",
+                sources: &[Bg],
+                separate_tracks: false,
+                mixed_track: true,
+                pads: &["mux.audio_0"],
+                tracks: &["Mixed"],
+                mixer: true,
+            },
+            Case {
+                name: "mic only",
+                sources: &[Mic],
+                separate_tracks: false,
+                mixed_track: true,
+                pads: &["mux.audio_0"],
+                tracks: &["Mixed"],
+                mixer: true,
+            },
+            Case {
+                name: "mixed",
+                sources: &[Bg, Mic],
+                separate_tracks: false,
+                // Ignored without separate tracks, the single track is the mix.
+                mixed_track: false,
+                pads: &["mux.audio_0"],
+                tracks: &["Mixed"],
+                mixer: true,
+            },
+            Case {
+                name: "separate tracks and the mix",
+                sources: &[Bg, Mic],
+                separate_tracks: true,
+                mixed_track: true,
+                pads: &["mux.audio_0", "mux.audio_1", "mux.audio_2"],
+                tracks: &["Mixed", "Desktop", "Microphone"],
+                mixer: true,
+            },
+            Case {
+                name: "separate tracks only",
+                sources: &[Bg, Mic],
+                separate_tracks: true,
+                mixed_track: false,
+                pads: &["mux.audio_0", "mux.audio_1"],
+                tracks: &["Desktop", "Microphone"],
+                mixer: false,
+            },
+        ];
+
+        for case in cases {
+            let audio = audio(case.sources, case.separate_tracks, case.mixed_track);
+            assert_eq!(audio.track_titles(), case.tracks, "{}", case.name);
+            let launch = config(VideoInput::Test, Some(audio)).launch_string();
+
+            assert!(launch.starts_with(SINK), "{}: {launch}", case.name);
+            assert_eq!(audio_pads(&launch), case.pads, "{}: {launch}", case.name);
+            // Only separate tracks are told apart by title.
+            let titles: &[&str] = if case.separate_tracks {
+                case.tracks
+            } else {
+                &[]
+            };
+            assert_eq!(track_titles(&launch), titles, "{}: {launch}", case.name);
+            assert_eq!(
+                launch.contains("audiomixer name=mix"),
+                case.mixer,
+                "{}: {launch}",
+                case.name
+            );
+
+            for kind in [Bg, Mic] {
+                let recorded = case.sources.contains(&kind);
+                let origin = match kind {
+                    Bg => "pipewiresrc name=bg_src do-timestamp=true path=40 ! queue",
+                    Mic => "pipewiresrc name=mic_src do-timestamp=true path=41 ! queue",
+                };
+                assert_eq!(launch.contains(origin), recorded, "{}: {launch}", case.name);
+                let to_mix = match kind {
+                    Bg => "bg_tee. ! queue ! mix.sink_0",
+                    Mic => "mic_tee. ! queue ! mix.sink_1",
+                };
+                assert_eq!(
+                    launch.contains(to_mix),
+                    recorded && case.mixer,
+                    "{}: {launch}",
+                    case.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn no_audio_links_only_video() {
+        let launch = config(VideoInput::Test, None).launch_string();
+
+        assert!(launch.starts_with(SINK), "{launch}");
+        assert!(audio_pads(&launch).is_empty(), "{launch}");
+        assert!(!launch.contains("audiomixer"), "{launch}");
+        assert!(!launch.contains("audiotestsrc"), "{launch}");
+        assert!(launch.ends_with("! queue ! mux.video_0"), "{launch}");
+    }
+
+    #[test]
+    fn source_volume_and_mute() {
+        let mut audio = audio(&[AudioSourceKind::Mic], false, true);
+        audio.sources[0].volume = 50;
+        audio.sources[0].muted = true;
+        audio.sources[0].filters = String::from("audiornnoise ! ");
+        let launch = config(VideoInput::Test, Some(audio)).launch_string();
+
+        assert!(
+            launch.contains(
+                "audioconvert ! audioresample ! audiornnoise ! \
+                volume name=mic_volume volume=0.5 mute=true ! tee name=mic_tee"
+            ),
+            "{launch}"
+        );
+    }
+
+    #[test]
+    fn every_video_encoder() {
+        use VideoCodec::{Av1, Vp9, H264, H265};
+
+        let cases = [
+            (H264, "nvh264enc", "cudaupload ! nvh264enc bitrate=8000 gop-size=60 ! h264parse config-interval=-1"),
+            (H264, "vah264enc", "vah264enc rate-control=cbr bitrate=8000 key-int-max=60 ! h264parse config-interval=-1"),
+            (H264, "vaapih264enc", "vaapih264enc rate-control=cbr bitrate=8000 keyframe-period=60 ! h264parse config-interval=-1"),
+            (H264, "x264enc", "x264enc bitrate=8000 speed-preset=ultrafast tune=zerolatency key-int-max=60 ! h264parse config-interval=-1"),
+            (H265, "nvh265enc", "cudaupload ! nvh265enc bitrate=8000 gop-size=60 ! h265parse config-interval=-1"),
+            (H265, "vah265enc", "vah265enc rate-control=cbr bitrate=8000 key-int-max=60 ! h265parse config-interval=-1"),
+            (H265, "vaapih265enc", "vaapih265enc rate-control=cbr bitrate=8000 keyframe-period=60 ! h265parse config-interval=-1"),
+            (H265, "x265enc", "videoconvert ! video/x-raw,format=I420 ! x265enc bitrate=8000 speed-preset=ultrafast tune=zerolatency key-int-max=60 ! h265parse config-interval=-1"),
+            (Vp9, "vavp9enc", "vavp9enc rate-control=cbr bitrate=8000 key-int-max=60"),
+            (Vp9, "vaapivp9enc", "vaapivp9enc rate-control=cbr bitrate=8000 keyframe-period=60"),
+            (Vp9, "vp9enc", "videoconvert ! video/x-raw,format=I420 ! vp9enc target-bitrate=8000000 end-usage=cbr deadline=1 cpu-used=8 keyframe-max-dist=60"),
+            (Av1, "nvav1enc", "cudaupload ! nvav1enc bitrate=8000 gop-size=60 ! av1parse"),
+            (Av1, "vaav1enc", "vaav1enc rate-control=cbr bitrate=8000 key-int-max=60 ! av1parse"),
+            (Av1, "av1enc", "videoconvert ! video/x-raw,format=I420 ! av1enc target-bitrate=8000 end-usage=cbr usage-profile=realtime cpu-used=8 keyframe-max-dist=60 ! av1parse"),
+        ];
+
+        for (codec, factory, segment) in cases {
+            let mut config = config(VideoInput::Test, None);
+            config.video_encoder = video_encoder(codec, factory);
+            assert_eq!(config.video_encoder.factory, factory);
+
+            // Keyframes every two seconds.
+            let launch = config.launch_string();
+            let expected = format!(
+                "video/x-raw,width=1280,height=720,pixel-aspect-ratio=1/1,format=(string)NV12 ! \
+                videorate ! video/x-raw,framerate=30/1 ! \
+                queue max-size-buffers=8 leaky=downstream ! \
+                {segment} ! queue ! mux.video_0"
+            );
+            assert!(launch.contains(&expected), "{factory}: {launch}");
+        }
+    }
+
+    #[test]
+    fn single_stream_draws_the_cursor() {
+        let input = VideoInput::PipeWire {
+            fd: 7,
+            streams: vec![stream(51, Some((0, 0)), (2560, 1440))],
+            draw_cursor: true,
+        };
+        let config = config(input, None);
+        assert!(config.draws_cursor());
+
+        let launch = config.launch_string();
+        assert!(
+            launch.contains(
+                "appsrc name=screen is-live=true do-timestamp=true format=time ! \
+                queue max-size-buffers=8 leaky=downstream ! \
+                videoconvert ! overlaycomposition name=cursor ! \
+                videoconvert ! videoscale add-borders=true"
+            ),
+            "{launch}"
+        );
+        // The cursor stream delivers the frames too, no second consumer on the node.
+        assert!(!launch.contains("pipewiresrc"), "{launch}");
+        assert!(!launch.contains("compositor"), "{launch}");
+    }
+
+    #[test]
+    fn several_streams_are_composited() {
+        let input = VideoInput::PipeWire {
+            fd: 7,
+            streams: vec![
+                stream(51, Some((0, 0)), (2560, 1440)),
+                stream(52, Some((2560, 180)), (1920, 1080)),
+                // No position from the portal, lined up to the right of the others.
+                stream(53, None, (1280, 1024)),
+            ],
+            draw_cursor: true,
+        };
+        let config = config(input, None);
+        assert!(!config.draws_cursor());
+
+        let launch = config.launch_string();
+        assert!(
+            launch.contains(
+                "compositor name=capture background=black \
+                sink_0::xpos=0 sink_0::ypos=0 sink_0::width=2560 sink_0::height=1440 \
+                sink_1::xpos=2560 sink_1::ypos=180 sink_1::width=1920 sink_1::height=1080 \
+                sink_2::xpos=4480 sink_2::ypos=0 sink_2::width=1280 sink_2::height=1024 ! \
+                videoconvert ! videoscale add-borders=true"
+            ),
+            "{launch}"
+        );
+        for (index, node_id) in [51, 52, 53].into_iter().enumerate() {
+            let branch = format!(
+                "pipewiresrc do-timestamp=true fd=7 path={node_id} ! \
+                queue max-size-buffers=8 leaky=downstream ! videoconvert ! capture.sink_{index}"
+            );
+            assert!(launch.contains(&branch), "{launch}");
+        }
+        assert!(!launch.contains("overlaycomposition"), "{launch}");
+    }
+
+    #[test]
+    fn test_backend_records_test_sources() {
+        let mut audio = audio(&[AudioSourceKind::Bg, AudioSourceKind::Mic], false, true);
+        for source in &mut audio.sources {
+            source.input = AudioInput::Test;
+        }
+        let launch = config(VideoInput::Test, Some(audio)).launch_string();
+
+        assert!(
+            launch.contains(
+                "videotestsrc is-live=true pattern=ball ! videoconvert ! videoscale add-borders=true"
+            ),
+            "{launch}"
+        );
+        assert!(
+            launch.contains("audiotestsrc name=bg_src is-live=true wave=sine volume=0.2 ! queue"),
+            "{launch}"
+        );
+        assert!(
+            launch.contains("audiotestsrc name=mic_src is-live=true wave=ticks volume=0.2 ! queue"),
+            "{launch}"
+        );
+        assert!(!launch.contains("pipewiresrc"), "{launch}");
+    }
+
+    #[test]
+    fn same_output_ignores_where_the_input_comes_from() {
+        let both = [AudioSourceKind::Bg, AudioSourceKind::Mic];
+        let current = config(
+            VideoInput::PipeWire {
+                fd: 7,
+                streams: vec![stream(51, None, (1920, 1080))],
+                draw_cursor: true,
+            },
+            Some(audio(&both, false, true)),
+        );
+
+        // A new portal stream, and the mic unplugged: still one mixed track.
+        let mut restarted = config(
+            VideoInput::PipeWire {
+                fd: 9,
+                streams: vec![stream(60, None, (1920, 1080))],
+                draw_cursor: false,
+            },
+            Some(audio(&[AudioSourceKind::Bg], false, true)),
+        );
+        assert!(current.same_output(&restarted));
+
+        restarted.fps = 60;
+        assert!(!current.same_output(&restarted));
+
+        let separate = config(VideoInput::Test, Some(audio(&both, true, true)));
+        assert!(!current.same_output(&separate));
+        let silent = config(VideoInput::Test, None);
+        assert!(!current.same_output(&silent));
+        let mut h265 = config(VideoInput::Test, Some(audio(&both, false, true)));
+        h265.video_encoder = video_encoder(VideoCodec::H265, "x265enc");
+        assert!(!current.same_output(&h265));
+    }
+
+    #[test]
+    fn rejects_malformed_resolutions() {
+        for resolution in [
+            "",
+            "1920",
+            "1920-1080",
+            "1920*1080",
+            "1920X1080",
+            "1920x",
+            "x1080",
+            "1920x1080x2",
+            "-1920x1080",
+            "1920.5x1080",
+        ] {
+            assert!(
+                parse_resolution(resolution).is_err(),
+                "{resolution:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_resolutions() {
+        for resolution in ["0x1080", "1920x0", "0x0", "7682x4320", "7680x4322"] {
+            assert!(
+                parse_resolution(resolution).is_err(),
+                "{resolution} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_odd_resolutions() {
+        for resolution in ["1921x1080", "1920x1081", "1x1", "7679x4320"] {
+            assert!(
+                parse_resolution(resolution).is_err(),
+                "{resolution} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn parses_resolutions() {
+        assert_eq!(parse_resolution("1920x1080").unwrap(), (1920, 1080));
+        assert_eq!(parse_resolution(" 1280 x 720 ").unwrap(), (1280, 720));
+        assert_eq!(parse_resolution("2x2").unwrap(), (2, 2));
+        assert_eq!(parse_resolution("7680x4320").unwrap(), (7680, 4320));
+    }
+
+    #[test]
+    fn fps_bounds() {
+        assert!(check_fps(0).is_err());
+        assert!(check_fps(1).is_ok());
+        assert!(check_fps(MAX_FPS).is_ok());
+        assert!(check_fps(MAX_FPS + 1).is_err());
+    }
+
+    #[test]
+    fn video_bitrate_bounds() {
+        assert!(check_video_bitrate(0).is_err());
+        assert!(check_video_bitrate(MIN_VIDEO_BITRATE - 1).is_err());
+        assert!(check_video_bitrate(MIN_VIDEO_BITRATE).is_ok());
+        assert!(check_video_bitrate(u16::MAX).is_ok());
+    }
+}
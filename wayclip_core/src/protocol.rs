@@ -0,0 +1,382 @@
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::time::{timeout, Duration};
+
+// Bump when a request or response changes shape.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Bounds connecting and sending only. Replies aren't timed, a save waits behind every
+// save queued before it, and a daemon that dies closes the socket anyway.
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioSourceKind {
+    Mic,
+    #[serde(alias = "desktop")]
+    Bg,
+}
+
+impl FromStr for AudioSourceKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "mic" | "microphone" => Ok(AudioSourceKind::Mic),
+            "bg" | "desktop" => Ok(AudioSourceKind::Bg),
+            other => bail!("Unknown audio source '{other}' (expected mic or bg)"),
+        }
+    }
+}
+
+impl fmt::Display for AudioSourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioSourceKind::Mic => write!(f, "mic"),
+            AudioSourceKind::Bg => write!(f, "bg"),
+        }
+    }
+}
+
+impl AudioSourceKind {
+    // The `volume` element of the source's branch in the daemon pipeline.
+    pub fn element_name(&self) -> &'static str {
+        match self {
+            AudioSourceKind::Mic => "mic_volume",
+            AudioSourceKind::Bg => "bg_volume",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioLevel {
+    pub source: AudioSourceKind,
+    pub volume: u8,
+    pub muted: bool,
+}
+
+// Something being recorded right now.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SourceStatus {
+    // "video", "bg" or "mic".
+    pub name: String,
+    // The PipeWire node it records from, None for test sources.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<u32>,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SaveResult {
+    pub finished_at: DateTime<Local>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Fields past `error` were added later, older daemons leave them at their defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DaemonStatus {
+    pub state: String,
+    pub is_saving: bool,
+    // Waiting behind the save in progress.
+    pub queued_saves: usize,
+    // Muxed chunks lost between the appsink and the ring since startup.
+    pub dropped_frames: u64,
+    // Times the pipeline was rebuilt after failing.
+    pub restarts: u32,
+    // Why the pipeline is down, set while `state` is "Degraded".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub uptime_ms: u64,
+    #[serde(default)]
+    pub buffered_ms: u64,
+    // Chunks held in memory and spilled to disk.
+    #[serde(default)]
+    pub buffered_bytes: u64,
+    // Of the muxed stream over the last few seconds.
+    #[serde(default)]
+    pub bitrate_kbps: u64,
+    #[serde(default)]
+    pub sources: Vec<SourceStatus>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_save: Option<SaveResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Save {
+        // Only keep this much of the buffer, the whole buffer when unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_ms: Option<u64>,
+    },
+    Reload,
+    // Forget the remembered capture source and ask the portal for a new one.
+    ResetSource,
+    // Changes apply to the running pipeline, `persist` also writes them to settings.json.
+    SetAudio {
+        source: AudioSourceKind,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        volume: Option<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        muted: Option<bool>,
+        #[serde(default)]
+        persist: bool,
+    },
+    ToggleMute {
+        source: AudioSourceKind,
+        #[serde(default)]
+        persist: bool,
+    },
+    Exit,
+}
+
+impl Request {
+    // Bare lines like "save" from before the JSON protocol still work.
+    pub fn from_legacy(line: &str) -> Option<Self> {
+        match line {
+            "status" => Some(Request::Status),
+            "save" => Some(Request::Save { last_ms: None }),
+            "reload" => Some(Request::Reload),
+            "reset_source" => Some(Request::ResetSource),
+            "exit" => Some(Request::Exit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Status(DaemonStatus),
+    Saved { path: PathBuf },
+    Audio(AudioLevel),
+    Error { error: DaemonError },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DaemonError {
+    SaveQueueFull,
+    BufferEmpty,
+    SaveFailed { message: String },
+    ReloadFailed { message: String },
+    CaptureFailed { message: String },
+    PersistFailed { message: String },
+    UnsupportedVersion { expected: u32, got: u32 },
+    InvalidRequest { message: String },
+}
+
+impl fmt::Display for DaemonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonError::SaveQueueFull => write!(f, "Too many saves are queued already"),
+            DaemonError::BufferEmpty => write!(f, "Nothing has been recorded yet"),
+            DaemonError::SaveFailed { message } => write!(f, "Save failed: {message}"),
+            DaemonError::ReloadFailed { message } => write!(f, "Reload failed: {message}"),
+            DaemonError::CaptureFailed { message } => {
+                write!(f, "Failed to start screen capture: {message}")
+            }
+            DaemonError::PersistFailed { message } => {
+                write!(f, "Failed to save settings: {message}")
+            }
+            DaemonError::UnsupportedVersion { expected, got } => write!(
+                f,
+                "Protocol version mismatch (daemon speaks v{expected}, client sent v{got})"
+            ),
+            DaemonError::InvalidRequest { message } => write!(f, "Invalid request: {message}"),
+        }
+    }
+}
+
+impl Error for DaemonError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RequestEnvelope {
+    pub version: u32,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseEnvelope {
+    pub version: u32,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+impl From<Response> for ResponseEnvelope {
+    fn from(response: Response) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            response,
+        }
+    }
+}
+
+impl From<DaemonError> for Response {
+    fn from(error: DaemonError) -> Self {
+        Response::Error { error }
+    }
+}
+
+// Parses one line sent by a client, either a JSON envelope or a legacy bare word.
+pub fn parse_request(line: &str) -> Result<Request, DaemonError> {
+    let line = line.trim();
+    if let Some(request) = Request::from_legacy(line) {
+        return Ok(request);
+    }
+
+    let envelope: RequestEnvelope =
+        serde_json::from_str(line).map_err(|e| DaemonError::InvalidRequest {
+            message: e.to_string(),
+        })?;
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(DaemonError::UnsupportedVersion {
+            expected: PROTOCOL_VERSION,
+            got: envelope.version,
+        });
+    }
+    Ok(envelope.request)
+}
+
+pub fn encode_response(response: Response) -> String {
+    let envelope = ResponseEnvelope::from(response);
+    let mut line = serde_json::to_string(&envelope).unwrap_or_else(|e| {
+        format!(r#"{{"version":{PROTOCOL_VERSION},"type":"error","error":{{"kind":"invalid_request","message":"{e}"}}}}"#)
+    });
+    line.push('\n');
+    line
+}
+
+pub struct DaemonClient {
+    stream: BufReader<UnixStream>,
+}
+
+impl DaemonClient {
+    pub async fn connect(socket_path: impl AsRef<Path>) -> Result<Self> {
+        let socket_path = socket_path.as_ref();
+        let stream = timeout(IO_TIMEOUT, UnixStream::connect(socket_path))
+            .await
+            .context("Timed out connecting to the daemon")?
+            .with_context(|| {
+                format!(
+                    "Failed to connect to the daemon at {}. Is the daemon running?",
+                    socket_path.display()
+                )
+            })?;
+        Ok(Self {
+            stream: BufReader::new(stream),
+        })
+    }
+
+    pub async fn connect_default() -> Result<Self> {
+        let settings = Settings::load().await?;
+        Self::connect(&settings.daemon_socket_path).await
+    }
+
+    pub async fn request(&mut self, request: Request) -> Result<Response> {
+        let mut line = serde_json::to_string(&RequestEnvelope {
+            version: PROTOCOL_VERSION,
+            request,
+        })?;
+        line.push('\n');
+
+        let stream = self.stream.get_mut();
+        timeout(IO_TIMEOUT, async {
+            stream.write_all(line.as_bytes()).await?;
+            stream.flush().await
+        })
+        .await
+        .context("Timed out sending the request to the daemon")??;
+
+        let mut reply = String::new();
+        let read = self.stream.read_line(&mut reply).await?;
+        if read == 0 {
+            bail!("Daemon closed the connection without replying");
+        }
+
+        let envelope: ResponseEnvelope =
+            serde_json::from_str(reply.trim()).context("Daemon sent an invalid response")?;
+        Ok(envelope.response)
+    }
+
+    // Turns `Response::Error` into an `Err` carrying the `DaemonError`.
+    async fn expect(&mut self, request: Request) -> Result<Response> {
+        match self.request(request).await? {
+            Response::Error { error } => Err(error.into()),
+            response => Ok(response),
+        }
+    }
+
+    pub async fn status(&mut self) -> Result<DaemonStatus> {
+        match self.expect(Request::Status).await? {
+            Response::Status(status) => Ok(status),
+            other => bail!("Unexpected response to status: {other:?}"),
+        }
+    }
+
+    pub async fn save(&mut self, last: Option<Duration>) -> Result<PathBuf> {
+        let last_ms = last.map(|d| d.as_millis() as u64);
+        match self.expect(Request::Save { last_ms }).await? {
+            Response::Saved { path } => Ok(path),
+            other => bail!("Unexpected response to save: {other:?}"),
+        }
+    }
+
+    pub async fn reload(&mut self) -> Result<()> {
+        self.expect(Request::Reload).await.map(|_| ())
+    }
+
+    pub async fn reset_source(&mut self) -> Result<()> {
+        self.expect(Request::ResetSource).await.map(|_| ())
+    }
+
+    pub async fn set_audio(
+        &mut self,
+        source: AudioSourceKind,
+        volume: Option<u8>,
+        muted: Option<bool>,
+        persist: bool,
+    ) -> Result<AudioLevel> {
+        let request = Request::SetAudio {
+            source,
+            volume,
+            muted,
+            persist,
+        };
+        match self.expect(request).await? {
+            Response::Audio(level) => Ok(level),
+            other => bail!("Unexpected response to set_audio: {other:?}"),
+        }
+    }
+
+    pub async fn toggle_mute(
+        &mut self,
+        source: AudioSourceKind,
+        persist: bool,
+    ) -> Result<AudioLevel> {
+        match self.expect(Request::ToggleMute { source, persist }).await? {
+            Response::Audio(level) => Ok(level),
+            other => bail!("Unexpected response to toggle_mute: {other:?}"),
+        }
+    }
+
+    pub async fn exit(&mut self) -> Result<()> {
+        self.expect(Request::Exit).await.map(|_| ())
+    }
+}
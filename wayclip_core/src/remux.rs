@@ -0,0 +1,438 @@
+use crate::encoder::Container;
+use gstreamer::prelude::*;
+use gstreamer::{self as gst, Buffer, MessageView};
+use gstreamer_app::AppSrc;
+use std::error::Error;
+use std::fmt;
+use std::fs::remove_file;
+use std::io;
+use std::path::Path;
+use std::thread;
+
+#[derive(Debug)]
+pub enum RemuxError {
+    // A GStreamer plugin needed for the remux isn't installed.
+    MissingElement(&'static str),
+    Pipeline(String),
+    // Reading a chunk back (e.g. from a spill file) failed.
+    Input(io::Error),
+    // Posted on the bus while remuxing, `source` is the element path.
+    Stream {
+        source: String,
+        message: String,
+        debug: Option<String>,
+    },
+}
+
+impl fmt::Display for RemuxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemuxError::MissingElement(name) => {
+                write!(f, "GStreamer element '{name}' is not available")
+            }
+            RemuxError::Pipeline(message) => write!(f, "Failed to set up remux: {message}"),
+            RemuxError::Input(e) => write!(f, "Failed to read buffered chunk: {e}"),
+            RemuxError::Stream {
+                source,
+                message,
+                debug,
+            } => {
+                write!(f, "{source}: {message}")?;
+                if let Some(debug) = debug {
+                    write!(f, " ({debug})")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for RemuxError {}
+
+fn make(factory: &'static str) -> Result<gst::Element, RemuxError> {
+    gst::ElementFactory::make(factory)
+        .build()
+        .map_err(|_| RemuxError::MissingElement(factory))
+}
+
+fn static_pad(element: &gst::Element, name: &'static str) -> Result<gst::Pad, RemuxError> {
+    element
+        .static_pad(name)
+        .ok_or_else(|| RemuxError::Pipeline(format!("{} has no {name} pad", element.name())))
+}
+
+// Links every stream the demuxer exposes to a new muxer pad through its own queue,
+// without them the streams block each other.
+fn link_stream(
+    pipeline: &gst::Pipeline,
+    mux: &gst::Element,
+    pad: &gst::Pad,
+) -> Result<(), RemuxError> {
+    let caps = pad
+        .current_caps()
+        .ok_or_else(|| RemuxError::Pipeline(String::from("stream has no caps")))?;
+    let media = caps
+        .structure(0)
+        .map(|s| s.name().as_str())
+        .ok_or_else(|| RemuxError::Pipeline(String::from("stream has empty caps")))?;
+    let template = if media.starts_with("video/") {
+        "video_%u"
+    } else if media.starts_with("audio/") {
+        "audio_%u"
+    } else {
+        return Ok(());
+    };
+
+    let queue = make("queue")?;
+    pipeline
+        .add(&queue)
+        .map_err(|e| RemuxError::Pipeline(e.to_string()))?;
+    queue
+        .sync_state_with_parent()
+        .map_err(|e| RemuxError::Pipeline(e.to_string()))?;
+
+    let Some(mux_pad) = mux.request_pad_simple(template) else {
+        return Err(RemuxError::Pipeline(format!(
+            "muxer has no {template} pad for {media}"
+        )));
+    };
+    pad.link(&static_pad(&queue, "sink")?)
+        .map_err(|e| RemuxError::Pipeline(format!("{media}: {e:?}")))?;
+    static_pad(&queue, "src")?.link(&mux_pad).map_err(|e| {
+        RemuxError::Pipeline(format!("{media} is not supported by the muxer: {e:?}"))
+    })?;
+    Ok(())
+}
+
+// Remuxes a Matroska stream (the ring buffer's header and chunks, in order) into
+// `container` at `output`, faststart where the container has it. Blocks until the file
+// is finalized, a partial output is removed on error.
+pub fn remux_matroska<I>(chunks: I, output: &Path, container: Container) -> Result<(), RemuxError>
+where
+    I: IntoIterator<Item = io::Result<Buffer>>,
+    I::IntoIter: Send,
+{
+    let result = run_remux(chunks.into_iter(), output, container);
+    if result.is_err() {
+        let _ = remove_file(output);
+    }
+    result
+}
+
+fn run_remux<I>(chunks: I, output: &Path, container: Container) -> Result<(), RemuxError>
+where
+    I: Iterator<Item = io::Result<Buffer>> + Send,
+{
+    let pipeline = gst::Pipeline::new();
+    let src = make("appsrc")?;
+    let demux = make("matroskademux")?;
+    let mux = make(container.muxer())?;
+    let sink = make("filesink")?;
+
+    let appsrc = src
+        .clone()
+        .dynamic_cast::<AppSrc>()
+        .map_err(|_| RemuxError::MissingElement("appsrc"))?;
+    appsrc.set_caps(Some(&gst::Caps::new_empty_simple("video/x-matroska")));
+    appsrc.set_format(gst::Format::Bytes);
+    // Keeps spilled chunks from all being read into memory at once.
+    appsrc.set_block(true);
+    if container.supports_faststart() {
+        mux.set_property("faststart", true);
+    }
+    sink.set_property("location", output.to_string_lossy().as_ref());
+
+    pipeline
+        .add_many([&src, &demux, &mux, &sink])
+        .map_err(|e| RemuxError::Pipeline(e.to_string()))?;
+    src.link(&demux)
+        .and_then(|_| mux.link(&sink))
+        .map_err(|e| RemuxError::Pipeline(e.to_string()))?;
+
+    let pipeline_weak = pipeline.downgrade();
+    let mux_weak = mux.downgrade();
+    demux.connect_pad_added(move |demux, pad| {
+        let (Some(pipeline), Some(mux)) = (pipeline_weak.upgrade(), mux_weak.upgrade()) else {
+            return;
+        };
+        if let Err(e) = link_stream(&pipeline, &mux, pad) {
+            gst::element_error!(demux, gst::StreamError::Format, ["{}", e]);
+        }
+    });
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .map_err(|e| RemuxError::Pipeline(e.to_string()))?;
+
+    let bus = pipeline
+        .bus()
+        .ok_or_else(|| RemuxError::Pipeline(String::from("pipeline has no bus")))?;
+    let (bus_result, input_result) = thread::scope(|scope| {
+        let pusher = scope.spawn(|| {
+            let mut input_result = Ok(());
+            for chunk in chunks {
+                match chunk {
+                    Ok(buffer) => {
+                        // Only fails once the pipeline is flushing after an error.
+                        if appsrc.push_buffer(buffer).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        input_result = Err(e);
+                        break;
+                    }
+                }
+            }
+            let _ = appsrc.end_of_stream();
+            input_result
+        });
+
+        let bus_result = loop {
+            let Some(message) = bus.timed_pop_filtered(
+                gst::ClockTime::NONE,
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            ) else {
+                break Err(RemuxError::Pipeline(String::from("bus closed")));
+            };
+            match message.view() {
+                MessageView::Eos(..) => break Ok(()),
+                MessageView::Error(err) => {
+                    break Err(RemuxError::Stream {
+                        source: err
+                            .src()
+                            .map(|s| s.path_string().to_string())
+                            .unwrap_or_default(),
+                        message: err.error().to_string(),
+                        debug: err.debug().map(|d| d.to_string()),
+                    })
+                }
+                _ => {}
+            }
+        };
+
+        // Unblocks the pusher if it is still waiting on a full appsrc.
+        let _ = pipeline.set_state(gst::State::Null);
+        let input_result = pusher
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("reading the buffered chunks panicked")));
+        (bus_result, input_result)
+    });
+
+    bus_result?;
+    input_result.map_err(RemuxError::Input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gstreamer_app::AppSink;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    const AAC_ENCODERS: [&str; 3] = ["avenc_aac", "fdkaacenc", "voaacenc"];
+
+    // None (and the test skipped) when a plugin the test clip needs isn't installed.
+    fn elements(factories: &[&'static str]) -> Option<()> {
+        gst::init().unwrap();
+        let missing: Vec<_> = factories
+            .iter()
+            .filter(|factory| gst::ElementFactory::find(factory).is_none())
+            .collect();
+        if !missing.is_empty() {
+            eprintln!("Skipping, GStreamer elements {missing:?} are not installed");
+            return None;
+        }
+        Some(())
+    }
+
+    fn aac_encoder() -> Option<&'static str> {
+        gst::init().unwrap();
+        let encoder = AAC_ENCODERS
+            .into_iter()
+            .find(|factory| gst::ElementFactory::find(factory).is_some());
+        if encoder.is_none() {
+            eprintln!("Skipping, no AAC encoder is installed");
+        }
+        encoder
+    }
+
+    fn output(name: &str, container: Container) -> PathBuf {
+        env::temp_dir().join(format!(
+            "wayclip-remux-test-{}-{name}.{}",
+            process::id(),
+            container.extension()
+        ))
+    }
+
+    // Two seconds of test video and audio muxed like the capture pipeline does it, in
+    // the chunks its appsink hands to the ring.
+    fn matroska_chunks(audio_encoder: &str) -> Vec<Buffer> {
+        let pipeline = gst::parse::launch(&format!(
+            "matroskamux name=mux ! appsink name=sink sync=false \
+            videotestsrc num-buffers=60 ! video/x-raw,width=320,height=240,framerate=30/1 ! \
+            x264enc key-int-max=30 ! h264parse ! queue ! mux.video_0 \
+            audiotestsrc num-buffers=94 ! audioconvert ! audioresample ! \
+            {audio_encoder} ! queue ! mux.audio_0"
+        ))
+        .unwrap()
+        .downcast::<gst::Pipeline>()
+        .unwrap();
+        let sink = pipeline
+            .by_name("sink")
+            .unwrap()
+            .downcast::<AppSink>()
+            .unwrap();
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let mut chunks = Vec::new();
+        while let Ok(sample) = sink.pull_sample() {
+            chunks.push(sample.buffer_owned().unwrap());
+        }
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert!(!chunks.is_empty());
+        chunks
+    }
+
+    // Demuxes `path` into fakesinks and returns the media type of every stream in it.
+    fn demuxed_streams(path: &Path) -> Vec<String> {
+        let pipeline = gst::Pipeline::new();
+        let src = gst::ElementFactory::make("filesrc")
+            .property("location", path.to_str().unwrap())
+            .build()
+            .unwrap();
+        let parse = make("parsebin").unwrap();
+        pipeline.add_many([&src, &parse]).unwrap();
+        src.link(&parse).unwrap();
+
+        let streams = Arc::new(Mutex::new(Vec::new()));
+        let found = streams.clone();
+        let pipeline_weak = pipeline.downgrade();
+        parse.connect_pad_added(move |_, pad| {
+            let Some(pipeline) = pipeline_weak.upgrade() else {
+                return;
+            };
+            if let Some(caps) = pad.current_caps() {
+                let name = caps.structure(0).unwrap().name().to_string();
+                found.lock().unwrap().push(name);
+            }
+            let sink = make("fakesink").unwrap();
+            pipeline.add(&sink).unwrap();
+            sink.sync_state_with_parent().unwrap();
+            pad.link(&static_pad(&sink, "sink").unwrap()).unwrap();
+        });
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let message = pipeline
+            .bus()
+            .unwrap()
+            .timed_pop_filtered(
+                gst::ClockTime::from_seconds(10),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            )
+            .expect("demuxing timed out");
+        pipeline.set_state(gst::State::Null).unwrap();
+        assert!(
+            matches!(message.view(), MessageView::Eos(..)),
+            "demuxing failed: {message:?}"
+        );
+        let streams = streams.lock().unwrap().clone();
+        streams
+    }
+
+    // Types of the top level MP4 boxes, in file order.
+    fn top_level_boxes(data: &[u8]) -> Vec<String> {
+        let mut boxes = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= data.len() {
+            let size = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            boxes.push(String::from_utf8_lossy(&data[offset + 4..offset + 8]).into_owned());
+            let size = match size {
+                0 => break,
+                1 => u64::from_be_bytes(data[offset + 8..offset + 16].try_into().unwrap()) as usize,
+                size => size,
+            };
+            offset += size;
+        }
+        boxes
+    }
+
+    fn assert_playable(path: &Path, audio: &str) {
+        assert!(fs::metadata(path).unwrap().len() > 0);
+        let streams = demuxed_streams(path);
+        assert!(streams.iter().any(|s| s == "video/x-h264"), "{streams:?}");
+        assert!(streams.iter().any(|s| s == audio), "{streams:?}");
+    }
+
+    #[test]
+    fn remuxes_to_faststart_mp4() {
+        let Some(aac) = aac_encoder() else {
+            return;
+        };
+        let needed = [
+            "videotestsrc",
+            "x264enc",
+            "h264parse",
+            "audiotestsrc",
+            "aacparse",
+            "matroskamux",
+            "matroskademux",
+            "mp4mux",
+            "parsebin",
+        ];
+        if elements(&needed).is_none() {
+            return;
+        }
+
+        let path = output("faststart", Container::Mp4);
+        let chunks = matroska_chunks(&format!("{aac} ! aacparse"));
+        remux_matroska(chunks.into_iter().map(Ok), &path, Container::Mp4).unwrap();
+
+        assert_playable(&path, "audio/mpeg");
+        let boxes = top_level_boxes(&fs::read(&path).unwrap());
+        let moov = boxes.iter().position(|b| b == "moov").unwrap();
+        let mdat = boxes.iter().position(|b| b == "mdat").unwrap();
+        assert!(moov < mdat, "moov comes after mdat: {boxes:?}");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn remuxes_to_matroska() {
+        let needed = [
+            "videotestsrc",
+            "x264enc",
+            "h264parse",
+            "audiotestsrc",
+            "opusenc",
+            "opusparse",
+            "matroskamux",
+            "matroskademux",
+            "parsebin",
+        ];
+        if elements(&needed).is_none() {
+            return;
+        }
+
+        let path = output("matroska", Container::Matroska);
+        let chunks = matroska_chunks("opusenc ! opusparse");
+        remux_matroska(chunks.into_iter().map(Ok), &path, Container::Matroska).unwrap();
+
+        assert_playable(&path, "audio/x-opus");
+        let _ = fs::remove_file(path);
+    }
+
+    #[test]
+    fn failed_input_leaves_no_file() {
+        if elements(&["matroskademux", "mp4mux"]).is_none() {
+            return;
+        }
+
+        let path = output("failed", Container::Mp4);
+        let chunks = [Err(io::Error::other("spill file is gone"))];
+        assert!(remux_matroska(chunks, &path, Container::Mp4).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -1,37 +1,244 @@
-use crate::{log_to, logging::Logger};
-use gstreamer::ClockTime;
+use crate::{log_to, logging::Logger, settings::Settings};
+use gstreamer::{Buffer, BufferFlags, ClockTime};
+use nix::fcntl::posix_fallocate;
 use std::collections::VecDeque;
+use std::env;
+use std::fs::{create_dir_all, remove_file, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 const EBML_MAGIC: &[u8] = b"\x1A\x45\xDF\xA3";
+// What the audio tracks add on top of the video bitrate when sizing the spill file, kbit/s.
+const SPILL_AUDIO_KBPS: u64 = 1024;
+const MIN_SPILL_BYTES: u64 = 64 * 1024 * 1024;
+// Between the newest chunk and the first one of a pipeline continuing the buffer.
+const RESUME_GAP: ClockTime = ClockTime::from_mseconds(100);
 
-type Frame = Vec<u8>;
-type TimedFrame = (Frame, ClockTime);
+// Refcounted, snapshots and saves share the muxer's memory instead of copying it.
+pub type Frame = Buffer;
+
+// What a snapshot hands to a save, spilled GOPs are only read back while saving.
+#[derive(Clone)]
+pub enum Chunk {
+    Memory(Frame),
+    Spilled {
+        file: Arc<SpillFile>,
+        pos: u64,
+        len: usize,
+    },
+}
+
+impl Chunk {
+    // Spilled GOPs are read with `pread`, so several saves can share the same file.
+    pub fn into_buffer(self) -> io::Result<Frame> {
+        match self {
+            Chunk::Memory(buffer) => Ok(buffer),
+            Chunk::Spilled { file, pos, len } => Ok(Buffer::from_mut_slice(file.read(pos, len)?)),
+        }
+    }
+}
+
+// One preallocated, unlinked file per session that spilled GOPs are written around in.
+// Positions are bytes written since it was created, `pos % capacity` is where they are.
+pub struct SpillFile {
+    file: File,
+    capacity: u64,
+    // Everything before `head - capacity` has been written over. Moved before writing,
+    // so a read that still sees its range after `head - capacity` got intact data.
+    head: AtomicU64,
+}
+
+impl SpillFile {
+    fn create(dir: &Path, capacity: u64) -> io::Result<Self> {
+        create_dir_all(dir)?;
+        let path = dir.join(format!("{}.ring", process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)?;
+        // Only the handle keeps it alive, so nothing is left behind if the daemon dies.
+        remove_file(&path)?;
+        posix_fallocate(&file, 0, capacity as i64)?;
+        Ok(Self {
+            file,
+            capacity,
+            head: AtomicU64::new(0),
+        })
+    }
+
+    fn head(&self) -> u64 {
+        self.head.load(Ordering::SeqCst)
+    }
+
+    // Splits `len` bytes at `pos` into the part up to the end of the file and the
+    // part that wrapped around to its start.
+    fn split(&self, pos: u64, len: usize) -> (u64, usize) {
+        let offset = pos % self.capacity;
+        (offset, len.min((self.capacity - offset) as usize))
+    }
+
+    fn write_at(&self, pos: u64, data: &[u8]) -> io::Result<()> {
+        let (offset, first) = self.split(pos, data.len());
+        self.file.write_all_at(&data[..first], offset)?;
+        self.file.write_all_at(&data[first..], 0)
+    }
+
+    fn read(&self, pos: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        let (offset, first) = self.split(pos, len);
+        self.file.read_exact_at(&mut data[..first], offset)?;
+        self.file.read_exact_at(&mut data[first..], 0)?;
+        if self.head() > pos + self.capacity {
+            return Err(io::Error::other(
+                "spilled GOP was written over before the save got to it",
+            ));
+        }
+        Ok(data)
+    }
+}
+
+pub struct TimedFrame {
+    // None once the GOP this chunk belongs to was spilled to disk.
+    pub data: Option<Frame>,
+    pub size: usize,
+    pub pts: ClockTime,
+    // Not DELTA_UNIT, matroskamux only clears it on cluster starts and video keyframes
+    pub keyframe: bool,
+}
 
 pub struct RingBuffer {
     pub header: Vec<Frame>,
     pub header_complete: bool,
     pub buffer: VecDeque<TimedFrame>,
     pub capacity_duration: ClockTime,
+    // Upper bound for chunks held in memory, older GOPs are spilled or dropped past it.
+    pub max_memory_bytes: usize,
+    pub spill_dir: Option<PathBuf>,
+    pub spill_capacity: u64,
     pub logger: Logger,
+    // Absolute position of buffer[0], so GOP starts survive pops from the front.
+    front_index: u64,
+    // Absolute positions of every chunk that opens a GOP, oldest first.
+    gop_starts: VecDeque<u64>,
+    memory_bytes: usize,
+    // Always a prefix of the buffer, spilling goes oldest GOP first.
+    spilled: VecDeque<SpilledGop>,
+    // Created on the first spill, and again after the settings it was sized for changed.
+    spill_file: Option<Arc<SpillFile>>,
+    warned_memory_cap: bool,
+    warned_spill_full: bool,
+    // Set while a new muxer takes over the buffer, until its first keyframe.
+    resuming: bool,
+}
+
+struct SpilledGop {
+    start: u64,
+    end: u64,
+    file: Arc<SpillFile>,
+    pos: u64,
+    len: usize,
+}
+
+// The cache dir comes first, $XDG_RUNTIME_DIR is usually a tmpfs and so RAM again.
+pub fn spill_dir() -> Option<PathBuf> {
+    dirs::cache_dir()
+        .or_else(|| env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from))
+        .map(|dir| dir.join("wayclip").join("spill"))
+}
+
+// Twice the clip at the configured bitrate, the second half is what a save still reads
+// from while newer GOPs are written.
+fn spill_capacity(settings: &Settings) -> u64 {
+    let bytes_per_s = (settings.video_bitrate as u64 + SPILL_AUDIO_KBPS) * 1000 / 8;
+    (2 * settings.clip_length_s * bytes_per_s).max(MIN_SPILL_BYTES)
 }
 
 impl RingBuffer {
-    pub fn new(capacity_duration: ClockTime, logger: &Logger) -> Self {
-        log_to!(*logger, Info, [RING] => "RingBuffer initialized with duration {capacity_duration}");
+    pub fn new(settings: &Settings, logger: &Logger) -> Self {
+        let mut ring = Self::empty(logger);
+        ring.apply_settings(settings);
+        ring
+    }
+
+    fn empty(logger: &Logger) -> Self {
         Self {
             header: Vec::new(),
             header_complete: false,
             buffer: VecDeque::new(),
-            capacity_duration,
+            capacity_duration: ClockTime::ZERO,
+            max_memory_bytes: 0,
+            spill_dir: None,
+            spill_capacity: 0,
             logger: logger.clone(),
+            front_index: 0,
+            gop_starts: VecDeque::new(),
+            memory_bytes: 0,
+            spilled: VecDeque::new(),
+            spill_file: None,
+            warned_memory_cap: false,
+            warned_spill_full: false,
+            resuming: false,
         }
     }
 
-    pub fn push(&mut self, data: Vec<u8>, is_header: bool, pts: Option<ClockTime>) {
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.capacity_duration = ClockTime::from_seconds(settings.clip_length_s);
+        self.max_memory_bytes = settings.max_buffer_memory_mb as usize * 1024 * 1024;
+        let spill_dir = if settings.spill_buffer_to_disk {
+            spill_dir()
+        } else {
+            None
+        };
+        let spill_capacity = spill_capacity(settings);
+        if spill_dir != self.spill_dir || spill_capacity != self.spill_capacity {
+            // GOPs already spilled keep the old file alive until they are evicted.
+            self.spill_file = None;
+        }
+        self.spill_dir = spill_dir;
+        self.spill_capacity = spill_capacity;
+        self.warned_memory_cap = false;
+        self.warned_spill_full = false;
+        log_to!(self.logger, Info,
+            [RING] => "RingBuffer set to {}, at most {}MB in memory, spilling up to {}MB to {:?}",
+            self.capacity_duration,
+            settings.max_buffer_memory_mb,
+            self.spill_capacity / 1024 / 1024,
+            self.spill_dir
+        );
+        self.enforce_memory_cap();
+    }
+
+    pub fn push(&mut self, data: Frame) {
+        let pts = data.pts();
+        let is_header = data.flags().contains(BufferFlags::HEADER);
+        let is_keyframe = !data.flags().contains(BufferFlags::DELTA_UNIT);
+
+        if self.resuming {
+            match pts {
+                Some(pts) if is_keyframe && !is_header => {
+                    log_to!(self.logger, Info, [RING] => "New pipeline continues the buffer at {}.", pts);
+                    self.resuming = false;
+                }
+                _ => {
+                    log_to!(self.logger, Debug, [RING] => "Dropping chunk of the new muxer while waiting for its first keyframe.");
+                    return;
+                }
+            }
+        }
+
         if !self.header_complete {
-            let looks_like_ebml = data.windows(4).any(|w| w == EBML_MAGIC);
+            let looks_like_ebml = data
+                .map_readable()
+                .is_ok_and(|map| map.windows(4).any(|w| w == EBML_MAGIC));
             if is_header || (self.header.is_empty() && looks_like_ebml) {
-                log_to!(self.logger, Debug, [RING] => "Header chunk captured (heuristic), size: {}", data.len());
+                log_to!(self.logger, Debug, [RING] => "Header chunk captured (heuristic), size: {}", data.size());
                 self.header.push(data);
                 return;
             }
@@ -43,41 +250,530 @@ impl RingBuffer {
             self.header_complete = true;
         }
 
-        if let Some(timestamp) = pts {
-            self.buffer.push_back((data, timestamp));
+        let Some(timestamp) = pts else {
+            return;
+        };
+
+        if self.buffer.is_empty() && !is_keyframe {
+            log_to!(self.logger, Debug, [RING] => "Dropping delta chunk at {} while waiting for a keyframe.", timestamp);
+            return;
+        }
+
+        if let Some(first) = self.buffer.front() {
+            if timestamp < first.pts {
+                log_to!(self.logger, Warn, [RING] => "Timestamp reset detected (last < first). Clearing buffer to resync.");
+                self.clear_frames();
+                if !is_keyframe {
+                    return;
+                }
+            }
+        }
+
+        // A cluster header and the keyframe block after it are both non-delta, the GOP
+        // starts at the first chunk of such a run.
+        let opens_gop = is_keyframe && self.buffer.back().is_none_or(|last| !last.keyframe);
+        if opens_gop {
+            self.gop_starts
+                .push_back(self.front_index + self.buffer.len() as u64);
+        }
 
-            while let (Some((_, first_pts)), Some((_, last_pts))) =
-                (self.buffer.front(), self.buffer.back())
-            {
-                if let Some(duration) = last_pts.checked_sub(*first_pts) {
-                    if duration > self.capacity_duration {
-                        self.buffer.pop_front();
-                    } else {
-                        break;
+        let size = data.size();
+        self.memory_bytes += size;
+        self.buffer.push_back(TimedFrame {
+            data: Some(data),
+            size,
+            pts: timestamp,
+            keyframe: is_keyframe,
+        });
+
+        self.evict(timestamp);
+        self.enforce_memory_cap();
+    }
+
+    // Drops whole GOPs from the front as long as what is left still covers the capacity,
+    // so the buffer always starts on a keyframe and never holds less than requested.
+    fn evict(&mut self, newest: ClockTime) {
+        while let Some(&next_gop) = self.gop_starts.get(1) {
+            let next_offset = (next_gop - self.front_index) as usize;
+            let next_pts = self.buffer[next_offset].pts;
+            if newest.saturating_sub(next_pts) < self.capacity_duration {
+                break;
+            }
+            self.drop_until(next_gop);
+        }
+    }
+
+    // Spills (or drops, without a spill dir) the oldest complete GOP still in memory
+    // until the memory cap is met. The GOP being written is never touched.
+    fn enforce_memory_cap(&mut self) {
+        while self.memory_bytes > self.max_memory_bytes {
+            let first_in_memory = self.spilled.back().map_or(self.front_index, |gop| gop.end);
+            let Some(&end) = self
+                .gop_starts
+                .iter()
+                .find(|&&start| start > first_in_memory)
+            else {
+                break;
+            };
+
+            if let Some(dir) = self.spill_dir.clone() {
+                match self.spill(&dir, first_in_memory, end) {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        log_to!(self.logger, Error, [RING] => "Failed to spill GOP to {:?}, dropping it instead: {}", dir, e);
                     }
-                } else {
-                    log_to!(self.logger, Warn, [RING] => "Timestamp reset detected (last < first). Clearing buffer to resync.");
-                    self.buffer.clear();
-                    break;
                 }
             }
+
+            if !self.warned_memory_cap {
+                log_to!(self.logger, Warn,
+                    [RING] => "Memory cap of {}MB reached, buffer now holds only {}.",
+                    self.max_memory_bytes / 1024 / 1024,
+                    self.buffered_duration()
+                );
+                self.warned_memory_cap = true;
+            }
+            self.drop_until(end);
         }
     }
 
-    pub fn get_and_clear(&mut self) -> Vec<Frame> {
+    // Appends the chunks of one GOP to the spill file. Spilled GOPs it would write over
+    // are evicted first, they are always the oldest part of the buffer.
+    fn spill(&mut self, dir: &Path, start: u64, end: u64) -> io::Result<()> {
+        let file = match &self.spill_file {
+            Some(file) => file.clone(),
+            None => {
+                let file = Arc::new(SpillFile::create(dir, self.spill_capacity)?);
+                self.spill_file = Some(file.clone());
+                file
+            }
+        };
+
+        let from = (start - self.front_index) as usize;
+        let to = (end - self.front_index) as usize;
+        let len: usize = self
+            .buffer
+            .range(from..to)
+            .filter(|frame| frame.data.is_some())
+            .map(|frame| frame.size)
+            .sum();
+        if len as u64 > file.capacity {
+            return Err(io::Error::other(format!(
+                "GOP of {len} bytes is larger than the spill file"
+            )));
+        }
+
+        let pos = file.head();
+        let needed_until = (pos + len as u64).saturating_sub(file.capacity);
+        while let Some(gop_end) = self
+            .spilled
+            .iter()
+            .find(|gop| Arc::ptr_eq(&gop.file, &file) && gop.pos < needed_until)
+            .map(|gop| gop.end)
+        {
+            if !self.warned_spill_full {
+                log_to!(self.logger, Warn,
+                    [RING] => "Spill file of {}MB is full, buffer now holds only {}.",
+                    file.capacity / 1024 / 1024,
+                    self.buffered_duration()
+                );
+                self.warned_spill_full = true;
+            }
+            self.drop_until(gop_end);
+        }
+
+        let from = (start - self.front_index) as usize;
+        let to = (end - self.front_index) as usize;
+        file.head.store(pos + len as u64, Ordering::SeqCst);
+        let mut written = 0;
+        for frame in self.buffer.range(from..to) {
+            if let Some(data) = &frame.data {
+                let map = data
+                    .map_readable()
+                    .map_err(|_| io::Error::other("failed to map buffer"))?;
+                file.write_at(pos + written, &map)?;
+                written += map.len() as u64;
+            }
+        }
+
+        for frame in self.buffer.range_mut(from..to) {
+            if frame.data.take().is_some() {
+                self.memory_bytes -= frame.size;
+            }
+        }
+        self.spilled.push_back(SpilledGop {
+            start,
+            end,
+            file,
+            pos,
+            len,
+        });
+        log_to!(self.logger, Debug, [RING] => "Spilled {} bytes ({} chunks) to disk.", len, to - from);
+        Ok(())
+    }
+
+    // Pops every chunk before the absolute index `until`, which must open a GOP.
+    fn drop_until(&mut self, until: u64) {
+        let offset = (until - self.front_index) as usize;
+        for frame in self.buffer.drain(..offset) {
+            if frame.data.is_some() {
+                self.memory_bytes -= frame.size;
+            }
+        }
+        self.front_index = until;
+        while self.gop_starts.front().is_some_and(|&start| start < until) {
+            self.gop_starts.pop_front();
+        }
+        while self.spilled.front().is_some_and(|gop| gop.end <= until) {
+            self.spilled.pop_front();
+        }
+    }
+
+    fn clear_frames(&mut self) {
+        self.front_index += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.gop_starts.clear();
+        self.spilled.clear();
+        self.memory_bytes = 0;
+    }
+
+    pub fn buffered_duration(&self) -> ClockTime {
+        match (self.buffer.front(), self.buffer.back()) {
+            (Some(first), Some(last)) => last.pts.saturating_sub(first.pts),
+            _ => ClockTime::ZERO,
+        }
+    }
+
+    // In memory and spilled alike.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffer.iter().map(|frame| frame.size).sum()
+    }
+
+    // Average of the newest `window` of chunks in kbit/s, 0 until there is a span to measure.
+    pub fn recent_bitrate_kbps(&self, window: ClockTime) -> u64 {
+        let Some(newest) = self.buffer.back() else {
+            return 0;
+        };
+        let cutoff = newest.pts.saturating_sub(window);
+        let (bytes, oldest) = self
+            .buffer
+            .iter()
+            .rev()
+            .take_while(|frame| frame.pts >= cutoff)
+            .fold((0u64, newest.pts), |(bytes, _), frame| {
+                (bytes + frame.size as u64, frame.pts)
+            });
+        match newest.pts.saturating_sub(oldest).mseconds() {
+            0 => 0,
+            ms => bytes * 8 / ms,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        log_to!(self.logger, Info, [RING] => "Resetting buffer, waiting for a new header.");
+        self.header.clear();
+        self.header_complete = false;
+        self.resuming = false;
+        self.clear_frames();
+    }
+
+    // Where a new pipeline writing the same tracks has to start its timestamps to
+    // continue this buffer, None when there's nothing to continue.
+    pub fn resume_point(&self) -> Option<ClockTime> {
+        if !self.header_complete || self.header.is_empty() {
+            return None;
+        }
+        self.buffer.back().map(|newest| newest.pts + RESUME_GAP)
+    }
+
+    // Keeps the header and chunks for a new muxer writing the same tracks. Its own
+    // header is dropped, the old one describes its clusters just as well, and the
+    // buffer picks up again at its first keyframe.
+    pub fn resume(&mut self) {
+        log_to!(self.logger, Info, [RING] => "Keeping the buffer for the new pipeline.");
+        self.resuming = true;
+    }
+
+    // Offset of the last GOP that starts at or before `cutoff`.
+    fn gop_start_before(&self, cutoff: ClockTime) -> Option<usize> {
+        self.gop_starts
+            .iter()
+            .map(|&index| (index - self.front_index) as usize)
+            .take_while(|&offset| self.buffer[offset].pts <= cutoff)
+            .last()
+    }
+
+    // Copies out the header and the buffered chunks (only the tail starting at a keyframe
+    // when `last` is set) while leaving the buffer as is.
+    pub fn snapshot(&self, last: Option<ClockTime>) -> Vec<Chunk> {
         if self.header.is_empty() {
-            log_to!(self.logger, Error, [RING] => "get_and_clear called but no header was ever captured.");
+            log_to!(self.logger, Error, [RING] => "snapshot called but no header was ever captured.");
             return Vec::new();
         }
 
-        let mut all_data = self.header.clone();
-        all_data.extend(self.buffer.drain(..).map(|(frame, _)| frame));
+        let start = match (last, self.buffer.back()) {
+            (Some(last), Some(newest)) => match newest.pts.checked_sub(last) {
+                Some(cutoff) => self.gop_start_before(cutoff).unwrap_or(0),
+                None => 0,
+            },
+            _ => 0,
+        };
+
+        // `start` opens a GOP and spilled files hold whole GOPs, so they never straddle it.
+        let first = self.front_index + start as u64;
+        let mut all_data: Vec<Chunk> = self.header.iter().cloned().map(Chunk::Memory).collect();
+        all_data.extend(
+            self.spilled
+                .iter()
+                .filter(|gop| gop.start >= first)
+                .map(|gop| Chunk::Spilled {
+                    file: gop.file.clone(),
+                    pos: gop.pos,
+                    len: gop.len,
+                }),
+        );
+        all_data.extend(
+            self.buffer
+                .iter()
+                .skip(start)
+                .filter_map(|frame| frame.data.clone())
+                .map(Chunk::Memory),
+        );
 
         log_to!(self.logger, Info,
-            [RING] => "get_and_clear, returning {} header chunks + {} frames",
+            [RING] => "snapshot, returning {} header chunks + {} frames (skipped {})",
             self.header.len(),
-            all_data.len() - self.header.len()
+            all_data.len() - self.header.len(),
+            start
         );
         all_data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::remove_dir;
+
+    const CHUNK_SIZE: usize = 1000;
+    // A keyframe and two deltas.
+    const GOP_SIZE: usize = 3 * CHUNK_SIZE;
+    const HEADER: u8 = 0xFF;
+
+    fn ring() -> RingBuffer {
+        gstreamer::init().unwrap();
+        let log = env::temp_dir().join(format!("wayclip-ring-test-{}.log", process::id()));
+        let mut ring = RingBuffer::empty(&Logger::new(log).unwrap());
+        ring.capacity_duration = ClockTime::from_seconds(3600);
+        ring.max_memory_bytes = usize::MAX;
+        ring.push(frame(BufferFlags::HEADER, None, HEADER));
+        ring
+    }
+
+    fn spill_dir(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("wayclip-ring-test-{}-{test}", process::id()))
+    }
+
+    fn frame(flags: BufferFlags, pts_ms: Option<u64>, fill: u8) -> Frame {
+        let mut buffer = Buffer::from_mut_slice(vec![fill; CHUNK_SIZE]);
+        let buffer_mut = buffer.get_mut().unwrap();
+        buffer_mut.set_flags(flags);
+        buffer_mut.set_pts(pts_ms.map(ClockTime::from_mseconds));
+        buffer
+    }
+
+    // GOPs `gops` as a keyframe and two deltas 100ms apart, every chunk of GOP n is
+    // filled with n.
+    fn push_gops(ring: &mut RingBuffer, gops: std::ops::Range<u8>) {
+        for gop in gops {
+            let start_ms = gop as u64 * 300;
+            ring.push(frame(BufferFlags::empty(), Some(start_ms), gop));
+            ring.push(frame(BufferFlags::DELTA_UNIT, Some(start_ms + 100), gop));
+            ring.push(frame(BufferFlags::DELTA_UNIT, Some(start_ms + 200), gop));
+        }
+    }
+
+    fn gop_fills(gops: std::ops::Range<u8>) -> Vec<u8> {
+        let mut fills = vec![HEADER];
+        fills.extend(gops.flat_map(|gop| [gop; 3]));
+        fills
+    }
+
+    // Reads every chunk back and returns the fill of each CHUNK_SIZE block.
+    fn contents(chunks: Vec<Chunk>) -> Vec<u8> {
+        let mut data = Vec::new();
+        for chunk in chunks {
+            let buffer = chunk.into_buffer().unwrap();
+            data.extend_from_slice(&buffer.map_readable().unwrap());
+        }
+        assert_eq!(data.len() % CHUNK_SIZE, 0);
+        data.chunks(CHUNK_SIZE)
+            .map(|block| {
+                assert!(block.iter().all(|&byte| byte == block[0]));
+                block[0]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn eviction_removes_whole_gops() {
+        let mut ring = ring();
+        ring.capacity_duration = ClockTime::from_seconds(1);
+        push_gops(&mut ring, 0..10);
+
+        // The newest chunk is at 2.9s, GOP 6 at 1.8s is the last start still covering 1s.
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(6..10));
+        assert!(ring.buffered_duration() >= ring.capacity_duration);
+        assert!(ring.buffer.front().unwrap().keyframe);
+    }
+
+    #[test]
+    fn snapshot_of_the_last_seconds_starts_at_a_keyframe() {
+        let mut ring = ring();
+        push_gops(&mut ring, 0..5);
+
+        // 500ms back from 1.4s falls inside GOP 3, which opens at 900ms.
+        let snapshot = ring.snapshot(Some(ClockTime::from_mseconds(500)));
+        assert_eq!(contents(snapshot), gop_fills(3..5));
+        // Right on a keyframe.
+        let snapshot = ring.snapshot(Some(ClockTime::from_mseconds(800)));
+        assert_eq!(contents(snapshot), gop_fills(2..5));
+        // More than is buffered.
+        let snapshot = ring.snapshot(Some(ClockTime::from_seconds(60)));
+        assert_eq!(contents(snapshot), gop_fills(0..5));
+    }
+
+    #[test]
+    fn deltas_before_the_first_keyframe_are_dropped() {
+        let mut ring = ring();
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(0), 0xEE));
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(100), 0xEE));
+        assert_eq!(contents(ring.snapshot(None)), vec![HEADER]);
+
+        push_gops(&mut ring, 1..3);
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(1..3));
+    }
+
+    #[test]
+    fn timestamp_reset_waits_for_the_next_keyframe() {
+        let mut ring = ring();
+        push_gops(&mut ring, 5..7);
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(0), 0xEE));
+        assert_eq!(contents(ring.snapshot(None)), vec![HEADER]);
+
+        push_gops(&mut ring, 0..2);
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(0..2));
+    }
+
+    #[test]
+    fn resume_keeps_the_buffer_and_the_old_header() {
+        let mut ring = ring();
+        push_gops(&mut ring, 0..3);
+        // Newest chunk at 800ms.
+        let resume_at = ring.resume_point().unwrap();
+        assert_eq!(resume_at, ClockTime::from_mseconds(800) + RESUME_GAP);
+
+        ring.resume();
+        // The new muxer's header and anything before its first keyframe.
+        ring.push(frame(BufferFlags::HEADER, None, 0xEE));
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(950), 0xEE));
+        let start_ms = resume_at.mseconds();
+        ring.push(frame(BufferFlags::empty(), Some(start_ms), 3));
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(start_ms + 100), 3));
+        ring.push(frame(BufferFlags::DELTA_UNIT, Some(start_ms + 200), 3));
+
+        assert_eq!(ring.header.len(), 1);
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(0..4));
+        // The last GOP is the new pipeline's.
+        let snapshot = ring.snapshot(Some(ClockTime::from_mseconds(100)));
+        assert_eq!(contents(snapshot), gop_fills(3..4));
+    }
+
+    #[test]
+    fn nothing_to_resume_without_chunks() {
+        let mut ring = ring();
+        assert_eq!(ring.resume_point(), None);
+
+        ring.reset();
+        push_gops(&mut ring, 0..2);
+        // Still waiting for the header the reset asked for.
+        assert_eq!(ring.resume_point(), None);
+    }
+
+    #[test]
+    fn memory_cap_drops_oldest_whole_gops() {
+        let mut ring = ring();
+        ring.max_memory_bytes = GOP_SIZE * 5 / 2;
+        push_gops(&mut ring, 0..5);
+
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(3..5));
+        assert!(ring.memory_bytes <= ring.max_memory_bytes);
+        assert!(ring.buffer.front().unwrap().keyframe);
+    }
+
+    #[test]
+    fn memory_cap_keeps_the_gop_being_written() {
+        let mut ring = ring();
+        ring.max_memory_bytes = CHUNK_SIZE;
+        push_gops(&mut ring, 0..2);
+
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(1..2));
+        assert_eq!(ring.memory_bytes, GOP_SIZE);
+    }
+
+    #[test]
+    fn spilled_gops_round_trip_through_snapshot() {
+        let dir = spill_dir("round-trip");
+        let mut ring = ring();
+        ring.max_memory_bytes = GOP_SIZE * 5 / 2;
+        ring.spill_dir = Some(dir.clone());
+        ring.spill_capacity = 1024 * 1024;
+        push_gops(&mut ring, 0..5);
+
+        assert_eq!(ring.spilled.len(), 3);
+        assert_eq!(ring.memory_bytes, 2 * GOP_SIZE);
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(0..5));
+        // Starts in the middle of the spilled GOPs, at the one opening at 300ms.
+        assert_eq!(
+            contents(ring.snapshot(Some(ClockTime::from_mseconds(1000)))),
+            gop_fills(1..5)
+        );
+        let _ = remove_dir(dir);
+    }
+
+    #[test]
+    fn spill_file_wraps_and_evicts_by_offset() {
+        let dir = spill_dir("wrap");
+        let mut ring = ring();
+        ring.max_memory_bytes = GOP_SIZE;
+        ring.spill_dir = Some(dir.clone());
+        ring.spill_capacity = 4 * GOP_SIZE as u64;
+        push_gops(&mut ring, 0..10);
+
+        // GOPs 0 to 8 went to disk, only the last four of them still fit.
+        let file = ring.spill_file.clone().unwrap();
+        assert_eq!(file.head(), 9 * GOP_SIZE as u64);
+        assert_eq!(ring.spilled.len(), 4);
+        assert!(ring.spilled.iter().all(|gop| Arc::ptr_eq(&gop.file, &file)));
+        assert_eq!(contents(ring.snapshot(None)), gop_fills(5..10));
+        let _ = remove_dir(dir);
+    }
+
+    #[test]
+    fn overwritten_spill_fails_the_read() {
+        let dir = spill_dir("overwritten");
+        let mut ring = ring();
+        ring.max_memory_bytes = GOP_SIZE;
+        ring.spill_dir = Some(dir.clone());
+        ring.spill_capacity = 4 * GOP_SIZE as u64;
+        push_gops(&mut ring, 0..10);
+
+        let snapshot = ring.snapshot(None);
+        push_gops(&mut ring, 10..12);
+        assert!(matches!(snapshot[1], Chunk::Spilled { .. }));
+        assert!(snapshot[1].clone().into_buffer().is_err());
+        // Spilled after the snapshot's oldest GOP, not written over yet.
+        assert!(snapshot[3].clone().into_buffer().is_ok());
+        let _ = remove_dir(dir);
+    }
+}
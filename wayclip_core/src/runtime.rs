@@ -0,0 +1,61 @@
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::getuid;
+use std::env;
+use std::fs::{self, DirBuilder, Permissions};
+use std::io::{self, ErrorKind};
+use std::os::fd::AsFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// Where sockets and the PID file used to default to, settings still pointing here are
+// moved to the runtime dir on load.
+pub const LEGACY_PATHS: &[(&str, &str)] = &[
+    ("daemon_pid_path", "/tmp/wayclipd.pid"),
+    ("daemon_socket_path", "/tmp/wayclipd.sock"),
+    ("gui_socket_path", "/tmp/wayclipg.sock"),
+];
+
+// `$XDG_RUNTIME_DIR/wayclip`, only readable by the user. Without a runtime dir it falls
+// back to a per-user directory in /tmp, which `prepare_runtime_dir` checks the owner of.
+pub fn runtime_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("wayclip"),
+        _ => env::temp_dir().join(format!("wayclip-{}", getuid())),
+    }
+}
+
+pub fn deep_link_socket_path() -> PathBuf {
+    runtime_dir().join("deep_link.sock")
+}
+
+// Creates the runtime dir with 0700 before a socket or PID file goes in it, and refuses
+// one that someone else created. Paths configured elsewhere are left to the user.
+pub fn prepare_runtime_dir(path: impl AsRef<Path>) -> io::Result<()> {
+    let dir = runtime_dir();
+    if path.as_ref().parent() != Some(dir.as_path()) {
+        return Ok(());
+    }
+
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    let metadata = fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != getuid().as_raw() {
+        return Err(io::Error::new(
+            ErrorKind::PermissionDenied,
+            format!(
+                "{} is not a directory owned by the current user",
+                dir.display()
+            ),
+        ));
+    }
+    if metadata.mode() & 0o777 != 0o700 {
+        fs::set_permissions(&dir, Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+// Whether the process on the other end of a Unix socket runs as the same user. Every
+// listener checks this, the sockets trigger saves and carry auth tokens.
+pub fn is_same_user(stream: &impl AsFd) -> io::Result<bool> {
+    let credentials = getsockopt(stream, PeerCredentials)?;
+    Ok(credentials.uid() == getuid().as_raw())
+}
@@ -1,24 +1,44 @@
+use crate::audio::NoiseSuppression;
+use crate::capture::{CaptureBackend, CaptureSource};
 use crate::config_dir;
-use crate::get_default_audio_devices;
+use crate::cursor::parse_cursor_mode;
+use crate::encoder::check_settings;
 use crate::home_dir;
 use crate::log;
+use crate::pipeline::{check_fps, check_video_bitrate, parse_resolution};
+use crate::runtime::{runtime_dir, LEGACY_PATHS};
 use crate::PathBuf;
 use crate::Value;
 use anyhow::{Context, Result};
 use std::collections::HashSet;
 use tokio::fs;
 
+// Below this not even a single GOP at high bitrates fits.
+const MIN_BUFFER_MEMORY_MB: u64 = 64;
+
+fn runtime_path(name: &str) -> String {
+    runtime_dir().join(name).to_string_lossy().into_owned()
+}
+
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct Settings {
     pub api_url: String,
     pub auth_token: Option<String>,
     pub clip_name_formatting: String,
     pub clip_length_s: u64,
+    pub max_buffer_memory_mb: u64,
+    pub spill_buffer_to_disk: bool,
     pub clip_resolution: String,
+    pub capture_backend: String,
+    pub capture_source: String,
+    pub capture_monitor: String,
+    pub cursor_mode: String,
     pub clip_fps: u16,
     pub video_bitrate: u16,
     pub video_codec: String,
+    pub encoder_preference: String,
     pub audio_codec: String,
+    pub output_container: String,
     pub save_path_from_home_string: String,
     pub save_shortcut: String,
     pub open_gui_shortcut: String,
@@ -28,38 +48,63 @@ pub struct Settings {
     pub gui_socket_path: String,
     pub mic_node_name: String,
     pub bg_node_name: String,
+    pub follow_default_devices: bool,
     pub mic_volume: u8,
     pub bg_volume: u8,
+    pub mic_muted: bool,
+    pub bg_muted: bool,
+    pub mic_noise_suppression: String,
+    pub mic_noise_gate: bool,
+    pub mic_compressor: bool,
     pub include_mic_audio: bool,
     pub include_bg_audio: bool,
+    pub separate_audio_tracks: bool,
+    pub include_mixed_track: bool,
 }
 
 impl Settings {
+    // No device names means the defaults of the moment, the daemon looks them up
+    // when it builds the pipeline.
     pub async fn new() -> Result<Self> {
-        let (default_source, default_sink) = get_default_audio_devices().await.unwrap_or_default();
         Ok(Self {
             api_url: String::from("http://127.0.0.1:8080"),
             auth_token: None,
-            mic_node_name: default_source.unwrap_or_default(),
-            bg_node_name: default_sink.unwrap_or_default(),
+            mic_node_name: String::new(),
+            bg_node_name: String::new(),
+            follow_default_devices: false,
             clip_name_formatting: String::from("%Y-%m-%d_%H-%M-%S"),
             clip_length_s: 120,
+            max_buffer_memory_mb: 1024,
+            spill_buffer_to_disk: false,
             clip_resolution: String::from("1920x1080"),
+            capture_backend: String::from("portal"),
+            capture_source: String::from("monitor"),
+            capture_monitor: String::new(),
+            cursor_mode: String::from("hidden"),
             clip_fps: 60,
             video_bitrate: 15000,
             video_codec: String::from("h264"),
+            encoder_preference: String::from("auto"),
             audio_codec: String::from("aac"),
+            output_container: String::from("mp4"),
             save_path_from_home_string: String::from("Videos/wayclip"),
             save_shortcut: String::from("Alt+C"),
             open_gui_shortcut: String::from("Ctrl+Alt+C"),
             toggle_notifications: true,
-            daemon_pid_path: String::from("/tmp/wayclipd.pid"),
-            daemon_socket_path: String::from("/tmp/wayclipd.sock"),
-            gui_socket_path: String::from("/tmp/wayclipg.sock"),
+            daemon_pid_path: runtime_path("wayclipd.pid"),
+            daemon_socket_path: runtime_path("wayclipd.sock"),
+            gui_socket_path: runtime_path("wayclipg.sock"),
             mic_volume: 100,
             bg_volume: 75,
+            mic_muted: false,
+            bg_muted: false,
+            mic_noise_suppression: String::from("off"),
+            mic_noise_gate: false,
+            mic_compressor: false,
             include_mic_audio: true,
             include_bg_audio: true,
+            separate_audio_tracks: false,
+            include_mixed_track: true,
         })
     }
 
@@ -116,8 +161,14 @@ impl Settings {
 
         let saved_keys: HashSet<_> = saved_map.keys().cloned().collect();
         let default_keys: HashSet<_> = default_map.keys().cloned().collect();
-
-        if saved_keys == default_keys {
+        // Old world-writable /tmp defaults, replaced by the new ones.
+        let legacy_keys: Vec<&str> = LEGACY_PATHS
+            .iter()
+            .filter(|(key, old)| saved_map.get(*key).and_then(Value::as_str) == Some(*old))
+            .map(|(key, _)| *key)
+            .collect();
+
+        if saved_keys == default_keys && legacy_keys.is_empty() {
             //     log!([DEBUG] => "Settings file is up-to-date. Loading directly.");
             return serde_json::from_value(saved_value)
                 .context("Failed to deserialize up-to-date settings");
@@ -128,6 +179,10 @@ impl Settings {
         let default_map_mut = default_value.as_object_mut().unwrap();
 
         for (key, value) in saved_map {
+            if legacy_keys.contains(&key.as_str()) {
+                log!([TAURI] => "Moving '{}' from {} to {}", key, value, default_map_mut[key]);
+                continue;
+            }
             default_map_mut.insert(key.clone(), value.clone());
         }
 
@@ -159,80 +214,175 @@ impl Settings {
     }
 
     pub async fn update_key(key: &str, value: Value) -> Result<(), String> {
+        Self::update_keys([(key.to_string(), value)]).await
+    }
+
+    // Applies every change before writing settings.json once, a batch with a bad
+    // value leaves the file untouched.
+    pub async fn update_keys(
+        changes: impl IntoIterator<Item = (String, Value)>,
+    ) -> Result<(), String> {
         let mut settings = Self::load().await.map_err(|e| e.to_string())?;
+        settings.apply_changes(changes)?;
+        settings.save().await.map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    // Codecs and container are only checked against each other once the whole batch
+    // is in, switching e.g. mp4/aac to webm/opus is never valid one key at a time.
+    fn apply_changes(
+        &mut self,
+        changes: impl IntoIterator<Item = (String, Value)>,
+    ) -> Result<(), String> {
+        for (key, value) in changes {
+            self.set_key(&key, value)?;
+        }
+        check_settings(self).map_err(|e| e.to_string())
+    }
 
+    fn set_key(&mut self, key: &str, value: Value) -> Result<(), String> {
         match key {
             "api_url" => {
-                settings.api_url = Self::get_str(&value)?;
+                self.api_url = Self::get_str(&value)?;
             }
             "auth_token" => {
-                settings.auth_token = Some(Self::get_str(&value)?);
+                self.auth_token = Some(Self::get_str(&value)?);
             }
             "clip_name_formatting" => {
-                settings.clip_name_formatting = Self::get_str(&value)?;
+                self.clip_name_formatting = Self::get_str(&value)?;
             }
             "clip_length_s" => {
-                settings.clip_length_s = Self::get_u64(&value)?;
+                self.clip_length_s = Self::get_u64(&value)?;
+            }
+            "max_buffer_memory_mb" => {
+                let mb = Self::get_u64(&value)?;
+                if mb < MIN_BUFFER_MEMORY_MB {
+                    return Err(format!(
+                        "Buffer memory must be at least {MIN_BUFFER_MEMORY_MB}MB"
+                    ));
+                }
+                self.max_buffer_memory_mb = mb;
+            }
+            "spill_buffer_to_disk" => {
+                self.spill_buffer_to_disk = Self::get_bool(&value)?;
             }
             "clip_resolution" => {
-                settings.clip_resolution = Self::get_str(&value)?;
+                let resolution = Self::get_str(&value)?;
+                parse_resolution(&resolution).map_err(|e| e.to_string())?;
+                self.clip_resolution = resolution;
+            }
+            "capture_backend" => {
+                let backend = Self::get_str(&value)?;
+                backend
+                    .parse::<CaptureBackend>()
+                    .map_err(|e| e.to_string())?;
+                self.capture_backend = backend;
+            }
+            "capture_source" => {
+                let source = Self::get_str(&value)?;
+                source.parse::<CaptureSource>().map_err(|e| e.to_string())?;
+                self.capture_source = source;
+            }
+            "capture_monitor" => {
+                self.capture_monitor = Self::get_str(&value)?;
+            }
+            "cursor_mode" => {
+                let mode = Self::get_str(&value)?;
+                parse_cursor_mode(&mode).map_err(|e| e.to_string())?;
+                self.cursor_mode = mode;
             }
             "clip_fps" => {
-                settings.clip_fps = Self::get_u16(&value)?;
+                let fps = Self::get_u16(&value)?;
+                check_fps(fps).map_err(|e| e.to_string())?;
+                self.clip_fps = fps;
             }
             "include_bg_audio" => {
-                settings.include_bg_audio = Self::get_bool(&value)?;
+                self.include_bg_audio = Self::get_bool(&value)?;
             }
             "include_mic_audio" => {
-                settings.include_mic_audio = Self::get_bool(&value)?;
+                self.include_mic_audio = Self::get_bool(&value)?;
+            }
+            "separate_audio_tracks" => {
+                self.separate_audio_tracks = Self::get_bool(&value)?;
+            }
+            "include_mixed_track" => {
+                self.include_mixed_track = Self::get_bool(&value)?;
             }
             "video_bitrate" => {
-                settings.video_bitrate = Self::get_u16(&value)?;
+                let bitrate = Self::get_u16(&value)?;
+                check_video_bitrate(bitrate).map_err(|e| e.to_string())?;
+                self.video_bitrate = bitrate;
             }
             "video_codec" => {
-                settings.video_codec = Self::get_str(&value)?;
+                self.video_codec = Self::get_str(&value)?;
+            }
+            "encoder_preference" => {
+                self.encoder_preference = Self::get_str(&value)?;
             }
             "audio_codec" => {
-                settings.audio_codec = Self::get_str(&value)?;
+                self.audio_codec = Self::get_str(&value)?;
+            }
+            "output_container" => {
+                self.output_container = Self::get_str(&value)?;
             }
             "save_path_from_home_string" => {
-                settings.save_path_from_home_string = Self::get_str_valid_path(&value)?;
+                self.save_path_from_home_string = Self::get_str_valid_path(&value)?;
             }
             "save_shortcut" => {
-                settings.save_shortcut = Self::get_shortcut(&value)?;
+                self.save_shortcut = Self::get_shortcut(&value)?;
             }
             "open_gui_shortcut" => {
-                settings.open_gui_shortcut = Self::get_shortcut(&value)?;
+                self.open_gui_shortcut = Self::get_shortcut(&value)?;
             }
             "toggle_notifications" => {
-                settings.toggle_notifications = Self::get_bool(&value)?;
+                self.toggle_notifications = Self::get_bool(&value)?;
             }
             "daemon_pid_path" => {
-                settings.daemon_pid_path = Self::get_str(&value)?;
+                self.daemon_pid_path = Self::get_str(&value)?;
             }
             "gui_socket_path" => {
-                settings.gui_socket_path = Self::get_str(&value)?;
+                self.gui_socket_path = Self::get_str(&value)?;
             }
             "daemon_socket_path" => {
-                settings.daemon_socket_path = Self::get_str(&value)?;
+                self.daemon_socket_path = Self::get_str(&value)?;
             }
             "mic_node_name" => {
-                settings.mic_node_name = Self::get_str(&value)?;
+                self.mic_node_name = Self::get_str(&value)?;
             }
             "bg_node_name" => {
-                settings.bg_node_name = Self::get_str(&value)?;
+                self.bg_node_name = Self::get_str(&value)?;
+            }
+            "follow_default_devices" => {
+                self.follow_default_devices = Self::get_bool(&value)?;
             }
             "mic_volume" => {
-                settings.mic_volume = Self::get_u8(&value)?;
+                self.mic_volume = Self::get_u8(&value)?;
             }
             "bg_volume" => {
-                settings.bg_volume = Self::get_u8(&value)?;
+                self.bg_volume = Self::get_u8(&value)?;
+            }
+            "mic_muted" => {
+                self.mic_muted = Self::get_bool(&value)?;
+            }
+            "bg_muted" => {
+                self.bg_muted = Self::get_bool(&value)?;
+            }
+            "mic_noise_suppression" => {
+                let suppression = Self::get_str(&value)?;
+                suppression
+                    .parse::<NoiseSuppression>()
+                    .map_err(|e| e.to_string())?;
+                self.mic_noise_suppression = suppression;
+            }
+            "mic_noise_gate" => {
+                self.mic_noise_gate = Self::get_bool(&value)?;
+            }
+            "mic_compressor" => {
+                self.mic_compressor = Self::get_bool(&value)?;
             }
 
             _ => return Err("Invalid key has been used!".into()),
         }
-
-        settings.save().await.map_err(|e| e.to_string())?;
         Ok(())
     }
 
@@ -324,3 +474,97 @@ impl Settings {
         serde_json::to_value(settings).context("Failed to serialize settings to JSON")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn changes(pairs: &[(&str, Value)]) -> Vec<(String, Value)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn switches_codecs_and_container_in_one_batch() {
+        let mut settings = Settings::new().await.unwrap();
+        assert_eq!(settings.output_container, "mp4");
+
+        // Each of these alone leaves an mp4/aac/h264 combination that can't be stored.
+        settings
+            .apply_changes(changes(&[
+                ("output_container", json!("webm")),
+                ("video_codec", json!("vp9")),
+                ("audio_codec", json!("opus")),
+            ]))
+            .unwrap();
+        assert_eq!(settings.output_container, "webm");
+        assert_eq!(settings.video_codec, "vp9");
+        assert_eq!(settings.audio_codec, "opus");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_batch_that_ends_incompatible() {
+        let mut settings = Settings::new().await.unwrap();
+        let err = settings
+            .apply_changes(changes(&[("audio_codec", json!("opus"))]))
+            .unwrap_err();
+        assert!(err.contains(".mp4"), "{err}");
+
+        let mut settings = Settings::new().await.unwrap();
+        assert!(settings
+            .apply_changes(changes(&[
+                ("output_container", json!("webm")),
+                ("audio_codec", json!("opus")),
+            ]))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn audio_codec_is_free_without_audio() {
+        let mut settings = Settings::new().await.unwrap();
+        settings
+            .apply_changes(changes(&[
+                ("include_bg_audio", json!(false)),
+                ("include_mic_audio", json!(false)),
+                ("audio_codec", json!("flac")),
+            ]))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_values_and_unknown_keys() {
+        let mut settings = Settings::new().await.unwrap();
+        assert!(settings
+            .apply_changes(changes(&[("clip_fps", json!(0))]))
+            .is_err());
+        assert!(settings
+            .apply_changes(changes(&[("no_such_setting", json!(true))]))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_encoders_and_codecs() {
+        let mut settings = Settings::new().await.unwrap();
+        for (key, value) in [
+            ("encoder_preference", "nvnec"),
+            ("video_codec", "h256"),
+            ("audio_codec", "mp5"),
+            ("output_container", "avi"),
+        ] {
+            assert!(
+                settings
+                    .clone()
+                    .apply_changes(changes(&[(key, json!(value))]))
+                    .is_err(),
+                "{key} = {value} should be rejected"
+            );
+        }
+        settings
+            .apply_changes(changes(&[("encoder_preference", json!("vaapi"))]))
+            .unwrap();
+        assert_eq!(settings.encoder_preference, "vaapi");
+    }
+}
@@ -0,0 +1,216 @@
+// Runs the daemon binary on the test capture backend and saves a clip through the
+// socket, the way CI exercises the whole capture -> ring -> remux path.
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use tokio::time::{sleep, Duration, Instant};
+use wayclip_core::protocol::DaemonClient;
+
+const AAC_ENCODERS: [&str; 3] = ["avenc_aac", "fdkaacenc", "voaacenc"];
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+const BUFFER_TIMEOUT: Duration = Duration::from_secs(20);
+
+// Killed when the test fails before asking the daemon to exit.
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// None (and the test skipped) when a plugin the test pipeline needs isn't installed.
+fn elements() -> Option<()> {
+    gst::init().unwrap();
+    let mut missing: Vec<&str> = [
+        "videotestsrc",
+        "audiotestsrc",
+        "audiomixer",
+        "x264enc",
+        "h264parse",
+        "matroskamux",
+        "mp4mux",
+        "parsebin",
+    ]
+    .into_iter()
+    .filter(|factory| gst::ElementFactory::find(factory).is_none())
+    .collect();
+    if !AAC_ENCODERS
+        .iter()
+        .any(|factory| gst::ElementFactory::find(factory).is_some())
+    {
+        missing.push("an AAC encoder");
+    }
+    if !missing.is_empty() {
+        eprintln!("Skipping, GStreamer elements {missing:?} are not installed");
+        return None;
+    }
+    Some(())
+}
+
+// A home, config and runtime dir of its own, so no real settings, sockets or clips
+// are touched.
+fn test_dirs() -> PathBuf {
+    let root = std::env::temp_dir().join(format!("wayclip-daemon-test-{}", process::id()));
+    let _ = fs::remove_dir_all(&root);
+    for dir in ["home", "config/wayclip", "run"] {
+        fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    root
+}
+
+// Only what differs from the defaults, the daemon merges in the rest.
+fn write_settings(root: &Path) {
+    let settings = serde_json::json!({
+        "capture_backend": "test",
+        "follow_default_devices": false,
+        "include_bg_audio": true,
+        "include_mic_audio": true,
+        "encoder_preference": "software",
+        "video_codec": "h264",
+        "audio_codec": "aac",
+        "output_container": "mp4",
+        "clip_resolution": "320x240",
+        "clip_fps": 30,
+        "video_bitrate": 1000,
+        "clip_length_s": 30,
+    });
+    fs::write(
+        root.join("config/wayclip/settings.json"),
+        serde_json::to_string_pretty(&settings).unwrap(),
+    )
+    .unwrap();
+}
+
+fn spawn_daemon(root: &Path) -> Daemon {
+    let child = Command::new(env!("CARGO_BIN_EXE_daemon"))
+        .env("WAYCLIP_CAPTURE_BACKEND", "test")
+        .env("HOME", root.join("home"))
+        .env("XDG_CONFIG_HOME", root.join("config"))
+        .env("XDG_RUNTIME_DIR", root.join("run"))
+        .env_remove("DESKTOP_SESSION")
+        .stdin(Stdio::null())
+        .spawn()
+        .expect("failed to start the daemon");
+    Daemon(child)
+}
+
+async fn connect(daemon: &mut Daemon, socket: &Path) -> DaemonClient {
+    let started = Instant::now();
+    loop {
+        if let Some(status) = daemon.0.try_wait().unwrap() {
+            panic!("daemon exited during startup with {status}");
+        }
+        if let Ok(client) = DaemonClient::connect(socket).await {
+            return client;
+        }
+        assert!(
+            started.elapsed() < STARTUP_TIMEOUT,
+            "daemon socket {} never came up",
+            socket.display()
+        );
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+// Demuxes `path` into fakesinks and returns the media type of every stream in it.
+fn demuxed_streams(path: &Path) -> Vec<String> {
+    let pipeline = gst::Pipeline::new();
+    let src = gst::ElementFactory::make("filesrc")
+        .property("location", path.to_str().unwrap())
+        .build()
+        .unwrap();
+    let parse = gst::ElementFactory::make("parsebin").build().unwrap();
+    pipeline.add_many([&src, &parse]).unwrap();
+    src.link(&parse).unwrap();
+
+    let streams = Arc::new(Mutex::new(Vec::new()));
+    let found = streams.clone();
+    let pipeline_weak = pipeline.downgrade();
+    parse.connect_pad_added(move |_, pad| {
+        let Some(pipeline) = pipeline_weak.upgrade() else {
+            return;
+        };
+        if let Some(caps) = pad.current_caps() {
+            let name = caps.structure(0).unwrap().name().to_string();
+            found.lock().unwrap().push(name);
+        }
+        let sink = gst::ElementFactory::make("fakesink").build().unwrap();
+        pipeline.add(&sink).unwrap();
+        sink.sync_state_with_parent().unwrap();
+        pad.link(&sink.static_pad("sink").unwrap()).unwrap();
+    });
+
+    pipeline.set_state(gst::State::Playing).unwrap();
+    let message = pipeline
+        .bus()
+        .unwrap()
+        .timed_pop_filtered(
+            gst::ClockTime::from_seconds(10),
+            &[gst::MessageType::Eos, gst::MessageType::Error],
+        )
+        .expect("demuxing timed out");
+    pipeline.set_state(gst::State::Null).unwrap();
+    assert!(
+        matches!(message.view(), gst::MessageView::Eos(..)),
+        "demuxing failed: {message:?}"
+    );
+    let streams = streams.lock().unwrap().clone();
+    streams
+}
+
+#[tokio::test]
+async fn saves_a_clip_from_the_test_backend() {
+    if elements().is_none() {
+        return;
+    }
+    let root = test_dirs();
+    write_settings(&root);
+    let mut daemon = spawn_daemon(&root);
+
+    let socket = root.join("run/wayclip/wayclipd.sock");
+    let mut client = connect(&mut daemon, &socket).await;
+
+    let started = Instant::now();
+    loop {
+        let status = client.status().await.unwrap();
+        assert_ne!(status.state, "Degraded", "{status:?}");
+        if status.buffered_ms >= 2000 {
+            break;
+        }
+        assert!(
+            started.elapsed() < BUFFER_TIMEOUT,
+            "only {}ms got buffered",
+            status.buffered_ms
+        );
+        sleep(Duration::from_millis(250)).await;
+    }
+
+    let path = client.save(None).await.unwrap();
+    assert!(
+        path.starts_with(root.join("home/Videos/wayclip")),
+        "{}",
+        path.display()
+    );
+    assert_eq!(path.extension().unwrap(), "mp4");
+    assert!(fs::metadata(&path).unwrap().len() > 0);
+
+    let streams = demuxed_streams(&path);
+    assert!(
+        streams.iter().any(|s| s == "video/x-h264"),
+        "no video in {streams:?}"
+    );
+    assert!(
+        streams.iter().any(|s| s == "audio/mpeg"),
+        "no audio in {streams:?}"
+    );
+
+    client.exit().await.unwrap();
+    drop(daemon);
+    let _ = fs::remove_dir_all(&root);
+}
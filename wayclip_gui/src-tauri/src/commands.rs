@@ -3,14 +3,41 @@ use serde_json::Value;
 use std::path::Path;
 use tauri::State;
 use wayclip_core::{
-    check_if_exists, delete_file, get_all_audio_devices, log, rename_all_entries,
-    settings::Settings, update_liked, AudioDevice, PaginatedClips,
+    check_if_exists,
+    control::DaemonManager,
+    delete_file, get_all_audio_devices, log,
+    protocol::{AudioLevel, AudioSourceKind, DaemonClient, DaemonError, DaemonStatus},
+    rename_all_entries,
+    settings::Settings,
+    update_liked, AudioDevice, PaginatedClips,
 };
 
 #[tauri::command(async)]
 pub async fn update_settings(key: &str, value: Value) -> Result<(), String> {
-    match Settings::update_key(key, value).await {
-        Ok(_) => Ok(()),
+    reload_after_update(Settings::update_key(key, value).await).await
+}
+
+// Saves a whole settings category at once, the daemon only reloads after the last key.
+#[tauri::command(async)]
+pub async fn update_settings_many(changes: serde_json::Map<String, Value>) -> Result<(), String> {
+    reload_after_update(Settings::update_keys(changes).await).await
+}
+
+async fn reload_after_update(updated: Result<(), String>) -> Result<(), String> {
+    match updated {
+        Ok(_) => match DaemonManager::new().reload().await {
+            Ok(()) => Ok(()),
+            // Saved, but the running daemon refused to apply them.
+            Err(e) if e.downcast_ref::<DaemonError>().is_some() => {
+                let err_msg = format!("Settings saved, but the daemon did not apply them: {e}");
+                log!([TAURI] => "{}", &err_msg);
+                Err(err_msg)
+            }
+            Err(e) => {
+                log!([TAURI] => "Daemon not reloaded: {}", e);
+                Ok(())
+            }
+        },
         Err(e) => {
             let err_msg = format!("Failed to update settings: {}", &e);
             log!([TAURI] => "{}", &err_msg);
@@ -149,3 +176,41 @@ pub async fn rename_clip(
 pub async fn get_all_audio_devices_command() -> Result<Vec<AudioDevice>, String> {
     get_all_audio_devices().await
 }
+
+#[tauri::command(async)]
+pub async fn set_audio_command(
+    source: AudioSourceKind,
+    volume: Option<u8>,
+    muted: Option<bool>,
+    persist: bool,
+) -> Result<AudioLevel, String> {
+    let mut client = DaemonClient::connect_default()
+        .await
+        .map_err(|e| e.to_string())?;
+    client
+        .set_audio(source, volume, muted, persist)
+        .await
+        .map_err(|e| format!("Failed to set {source} audio: {e}"))
+}
+
+#[tauri::command(async)]
+pub async fn toggle_mute_command(
+    source: AudioSourceKind,
+    persist: bool,
+) -> Result<AudioLevel, String> {
+    let mut client = DaemonClient::connect_default()
+        .await
+        .map_err(|e| e.to_string())?;
+    client
+        .toggle_mute(source, persist)
+        .await
+        .map_err(|e| format!("Failed to toggle {source} mute: {e}"))
+}
+
+#[tauri::command(async)]
+pub async fn get_daemon_status_command() -> Result<DaemonStatus, String> {
+    DaemonManager::new()
+        .status()
+        .await
+        .map_err(|e| e.to_string())
+}
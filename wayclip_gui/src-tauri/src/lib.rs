@@ -8,12 +8,13 @@ use tauri::{
 };
 use tauri_plugin_store::{Store, StoreExt};
 use wayclip_core::{
-    gather_clip_data, generate_all_previews, log, settings::Settings, ClipData, Collect, Payload,
-    PullClipsArgs, WAYCLIP_TRIGGER_PATH,
+    gather_clip_data, generate_all_previews, log,
+    protocol::DaemonClient,
+    runtime::{deep_link_socket_path, is_same_user, prepare_runtime_dir},
+    settings::Settings,
+    ClipData, Collect, Payload, PullClipsArgs,
 };
 
-pub const DEEP_LINK_SOCKET_PATH: &str = "/tmp/wayclip_deep_link.sock";
-
 pub mod auth;
 pub mod commands;
 
@@ -69,7 +70,9 @@ fn setup_socket_listener(app: AppHandle<Wry>, socket_path: String) {
             }
         }
 
-        let listener = match UnixListener::bind(&socket_path) {
+        let listener = match prepare_runtime_dir(&socket_path)
+            .and_then(|_| UnixListener::bind(&socket_path))
+        {
             Ok(listener) => listener,
             Err(e) => {
                 app.emit(
@@ -89,6 +92,9 @@ fn setup_socket_listener(app: AppHandle<Wry>, socket_path: String) {
 
         for stream in listener.incoming() {
             match stream {
+                Ok(stream) if !is_same_user(&stream).unwrap_or(false) => {
+                    log!([TAURI] => "[WARN] Rejected GUI socket connection from another user.");
+                }
                 Ok(stream) => {
                     let mut reader = BufReader::new(stream);
                     let mut line = String::new();
@@ -118,14 +124,16 @@ fn setup_socket_listener(app: AppHandle<Wry>, socket_path: String) {
 
 
 fn setup_deep_link_listener(app: AppHandle<Wry>) {
-    let socket_path = DEEP_LINK_SOCKET_PATH;
+    let socket_path = deep_link_socket_path();
 
     // Clean up old socket file from a crash
-    if std::fs::metadata(socket_path).is_ok() {
-        let _ = std::fs::remove_file(socket_path);
+    if std::fs::metadata(&socket_path).is_ok() {
+        let _ = std::fs::remove_file(&socket_path);
     }
 
-    let listener = match UnixListener::bind(socket_path) {
+    let listener = match prepare_runtime_dir(&socket_path)
+        .and_then(|_| UnixListener::bind(&socket_path))
+    {
         Ok(listener) => listener,
         Err(e) => {
             log!([TAURI] => "[CRITICAL_ERROR] Could not bind deep link socket: {}. Deep links will not work.", e);
@@ -135,9 +143,13 @@ fn setup_deep_link_listener(app: AppHandle<Wry>) {
 
     // This thread will own the listener and handle incoming connections.
     std::thread::spawn(move || {
-        log!([TAURI] => "Deep link listener thread started at {}", socket_path);
+        log!([TAURI] => "Deep link listener thread started at {}", socket_path.display());
         for stream in listener.incoming() {
             match stream {
+                // Another user could otherwise log this one in with their own token.
+                Ok(stream) if !is_same_user(&stream).unwrap_or(false) => {
+                    log!([TAURI] => "[WARN] Rejected deep link from another user.");
+                }
                 Ok(mut stream) => {
                     let mut url = String::new();
                     if stream.read_to_string(&mut url).is_ok() && !url.is_empty() {
@@ -271,15 +283,17 @@ pub fn run() {
                             app.exit(0);
                         }
                         "clip" => {
-                            log!([TAURI] => "Clip event received. Spawning trigger script.");
-                            if let Err(e) = std::process::Command::new("sh")
-                                .arg(WAYCLIP_TRIGGER_PATH)
-                                .spawn()
-                            {
-                                log!([TAURI] => "[ERROR] failed to run script: {:?}", e);
-                            } else {
-                                log!([TAURI] => "Trigger script launched successfully.");
-                            }
+                            log!([TAURI] => "Clip event received. Asking daemon to save.");
+                            tauri::async_runtime::spawn(async move {
+                                let result = match DaemonClient::connect_default().await {
+                                    Ok(mut client) => client.save(None).await,
+                                    Err(e) => Err(e),
+                                };
+                                match result {
+                                    Ok(path) => log!([TAURI] => "Clip saved to {}", path.display()),
+                                    Err(e) => log!([TAURI] => "[ERROR] failed to save clip: {}", e),
+                                }
+                            });
                         }
                         _ => {
                             log!([TAURI] => "Menu item {:?} not handled", event.id);
@@ -333,12 +347,16 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .invoke_handler(tauri::generate_handler![
             commands::update_settings,
+            commands::update_settings_many,
             commands::pull_settings,
             commands::pull_clips,
             commands::delete_clip,
             commands::like_clip,
             commands::rename_clip,
             commands::get_all_audio_devices_command,
+            commands::set_audio_command,
+            commands::toggle_mute_command,
+            commands::get_daemon_status_command,
             auth::check_auth_status,
             auth::get_me,
             auth::logout
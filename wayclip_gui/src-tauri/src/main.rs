@@ -6,7 +6,7 @@ use std::os::unix::net::UnixStream;
 fn main() {
     if let Some(url) = std::env::args().nth(1) {
         if url.starts_with("wayclip://") {
-            if let Ok(mut stream) = UnixStream::connect(gui_lib::DEEP_LINK_SOCKET_PATH) {
+            if let Ok(mut stream) = UnixStream::connect(wayclip_core::runtime::deep_link_socket_path()) {
                 if stream.write_all(url.as_bytes()).is_ok() {
                     println!("[wayclip] Deep link sent to main instance. Exiting now.");
                     std::process::exit(0);
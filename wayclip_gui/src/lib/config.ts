@@ -16,11 +16,29 @@ export const defaultSettings: Setting[] = [
         name: 'Clip length',
         description: 'The length of the clip in seconds.',
         type: 'select',
-        options: [30, 60, 120, 180, 240, 300, 600],
+        options: [30, 60, 120, 180, 240, 300, 600, 900, 1800],
         defaultValue: 120,
         storageKey: 'clip_length_s',
         category: categories.general,
     },
+    {
+        name: 'Buffer memory limit',
+        description:
+            'Most memory in MB the clip buffer may use. Older footage is dropped past it, unless spilling to disk is on.',
+        type: 'select',
+        options: [256, 512, 1024, 2048, 4096],
+        defaultValue: 1024,
+        storageKey: 'max_buffer_memory_mb',
+        category: categories.general,
+    },
+    {
+        name: 'Spill buffer to disk',
+        description: 'Move older footage past the memory limit to a cache file, for long clips on machines with little RAM.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'spill_buffer_to_disk',
+        category: categories.general,
+    },
     {
         name: 'Clip resolution',
         description: 'The resolution of the clip. Higher resolution means larger file size.',
@@ -30,6 +48,35 @@ export const defaultSettings: Setting[] = [
         storageKey: 'clip_resolution',
         category: categories.general,
     },
+    {
+        name: 'Capture source',
+        description:
+            'What to record. All monitors records every monitor picked in the screen share dialog, side by side.',
+        type: 'select',
+        options: ['monitor', 'window', 'all_monitors'],
+        defaultValue: 'monitor',
+        storageKey: 'capture_source',
+        category: categories.general,
+    },
+    {
+        name: 'Capture monitor',
+        description:
+            'Connector name of the monitor to record (e.g. DP-1) when several are picked. Leave empty to use the first one.',
+        type: 'string',
+        defaultValue: '',
+        storageKey: 'capture_monitor',
+        category: categories.general,
+    },
+    {
+        name: 'Cursor',
+        description:
+            'Whether the mouse cursor shows up in clips. Metadata draws it from the stream instead of the compositor, use it if embedded does nothing.',
+        type: 'select',
+        options: ['hidden', 'embedded', 'metadata'],
+        defaultValue: 'hidden',
+        storageKey: 'cursor_mode',
+        category: categories.general,
+    },
     {
         name: 'Clip FPS',
         description: 'The FPS of the clip. Higher FPS means smoother video.',
@@ -43,20 +90,40 @@ export const defaultSettings: Setting[] = [
         name: 'Video codec',
         description: 'The codec to use for the video.',
         type: 'select',
-        options: ['h264', 'hevc', 'vp9'],
+        options: ['h264', 'hevc', 'vp9', 'av1'],
         defaultValue: 'h264',
         storageKey: 'video_codec',
         category: categories.general,
     },
+    {
+        name: 'Video encoder',
+        description: 'Which encoder backend to prefer. Falls back to the next available one.',
+        tooltip: 'auto: NVENC, then VA-API, then software. nvenc: NVIDIA GPUs. vaapi: AMD/Intel GPUs.',
+        type: 'select',
+        options: ['auto', 'nvenc', 'vaapi', 'software'],
+        defaultValue: 'auto',
+        storageKey: 'encoder_preference',
+        category: categories.general,
+    },
     {
         name: 'Audio codec',
-        description: 'The codec to use for the audio.',
+        description: 'The codec to use for the audio. MP4 clips only support AAC.',
         type: 'select',
-        options: ['aac', 'opus', 'mp3'],
+        options: ['aac', 'opus', 'flac'],
         defaultValue: 'aac',
         storageKey: 'audio_codec',
         category: categories.general,
     },
+    {
+        name: 'Output container',
+        description:
+            'The file format clips are saved as. MP4 and MOV take H.264/HEVC with AAC, WebM takes VP9/AV1 with Opus, MKV takes everything.',
+        type: 'select',
+        options: ['mp4', 'mkv', 'webm', 'mov'],
+        defaultValue: 'mp4',
+        storageKey: 'output_container',
+        category: categories.general,
+    },
     {
         name: 'Video bitrate',
         description: 'The bitrate of the video in kbps.',
@@ -105,7 +172,7 @@ export const defaultSettings: Setting[] = [
         storageKey: 'mic_node_name',
         category: categories.audio,
         name: 'Input Device',
-        description: 'Select your microphone or input device',
+        description: 'Select your microphone or input device, the system default is used until you do',
         defaultValue: '',
         currentValue: '',
         type: 'select',
@@ -114,11 +181,20 @@ export const defaultSettings: Setting[] = [
         storageKey: 'bg_node_name',
         category: categories.audio,
         name: 'Desktop Audio',
-        description: 'Select your desktop audio device (sink)',
+        description: 'Select your desktop audio device (sink), the system default is used until you do',
         defaultValue: '',
         currentValue: '',
         type: 'select',
     },
+    {
+        name: 'Follow default devices',
+        description:
+            'Record whatever the system default input and output are, switching when they change. Unplugged devices are picked up again when they come back.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'follow_default_devices',
+        category: categories.audio,
+    },
     {
         storageKey: 'mic_volume',
         category: categories.audio,
@@ -135,6 +211,48 @@ export const defaultSettings: Setting[] = [
         defaultValue: '75',
         type: 'slider',
     },
+    {
+        name: 'Mute microphone',
+        description: 'Keep recording the mic track but silence it. Can be toggled with `wayclip audio mic --toggle`.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'mic_muted',
+        category: categories.audio,
+    },
+    {
+        name: 'Mute desktop audio',
+        description: 'Keep recording the desktop track but silence it.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'bg_muted',
+        category: categories.audio,
+    },
+    {
+        name: 'Mic noise suppression',
+        description:
+            'Filters background noise like fans and keyboards out of the mic. Needs the webrtcdsp or audiornnoise GStreamer plugin.',
+        type: 'select',
+        options: ['off', 'webrtc', 'rnnoise'],
+        defaultValue: 'off',
+        storageKey: 'mic_noise_suppression',
+        category: categories.audio,
+    },
+    {
+        name: 'Mic noise gate',
+        description: 'Silences the mic while you are not talking.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'mic_noise_gate',
+        category: categories.audio,
+    },
+    {
+        name: 'Mic compressor',
+        description: 'Evens out loud and quiet speech so peaks do not drown out the rest.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'mic_compressor',
+        category: categories.audio,
+    },
     {
         name: 'Include mic audio',
         description: 'Whether to include mic audio in the clip.',
@@ -152,4 +270,20 @@ export const defaultSettings: Setting[] = [
         storageKey: 'include_bg_audio',
         category: categories.audio,
     },
+    {
+        name: 'Separate audio tracks',
+        description: 'Save desktop and mic audio as their own tracks, so they can be edited separately.',
+        type: 'boolean',
+        defaultValue: false,
+        storageKey: 'separate_audio_tracks',
+        category: categories.audio,
+    },
+    {
+        name: 'Include mixed track',
+        description: 'With separate tracks, also add a mixed track for players that only play one.',
+        type: 'boolean',
+        defaultValue: true,
+        storageKey: 'include_mixed_track',
+        category: categories.audio,
+    },
 ];
@@ -44,6 +44,8 @@ export const getPreview = async (path: string): Promise<string> => {
     else if (ext === 'jpg' || ext === 'jpeg') mimeType = 'image/jpeg';
     else if (ext === 'mp4') mimeType = 'video/mp4';
     else if (ext === 'webm') mimeType = 'video/webm';
+    else if (ext === 'mkv') mimeType = 'video/x-matroska';
+    else if (ext === 'mov') mimeType = 'video/quicktime';
 
     const blob = new Blob([uint8Array], { type: mimeType });
     const url = URL.createObjectURL(blob);
@@ -61,6 +63,8 @@ export const getVideo = async (path: string): Promise<string> => {
     else if (ext === 'jpg' || ext === 'jpeg') mimeType = 'image/jpeg';
     else if (ext === 'mp4') mimeType = 'video/mp4';
     else if (ext === 'webm') mimeType = 'video/webm';
+    else if (ext === 'mkv') mimeType = 'video/x-matroska';
+    else if (ext === 'mov') mimeType = 'video/quicktime';
 
     const blob = new Blob([uint8Array], { type: mimeType });
     const url = URL.createObjectURL(blob);
@@ -51,4 +51,34 @@ export interface AudioDevice {
     name: string;
     id: number;
     node_name: string;
+    media_class: 'source' | 'sink';
+    channels: string[];
+    is_default: boolean;
+}
+
+export interface SourceStatus {
+    name: 'video' | 'bg' | 'mic';
+    node_id?: number;
+    muted: boolean;
+}
+
+export interface SaveResult {
+    finished_at: string;
+    path?: string;
+    error?: string;
+}
+
+export interface DaemonStatus {
+    state: string;
+    is_saving: boolean;
+    queued_saves: number;
+    dropped_frames: number;
+    restarts: number;
+    error?: string;
+    uptime_ms: number;
+    buffered_ms: number;
+    buffered_bytes: number;
+    bitrate_kbps: number;
+    sources: SourceStatus[];
+    last_save?: SaveResult;
 }
@@ -20,7 +20,7 @@ const Editor = () => {
             const data = await invoke<PaginatedClips>('pull_clips', {
                 page: page,
                 pageSize: 1,
-                searchQuery: searchQuery.replace('.mp4', ''),
+                searchQuery: searchQuery.replace(/\.(mp4|mkv|webm|mov)$/, ''),
             });
             console.log(data.clips[0]);
             if (data.clips[0]) {
@@ -1,6 +1,103 @@
+import { invoke } from '@tauri-apps/api/core';
 import { cn } from '@/lib/utils';
 import { FiSidebar } from '@vertisanpro/react-icons/fi';
 import { useSidebar } from '@/hooks/sidebar';
+import { Card, CardContent } from '@/components/ui/card';
+import { DaemonStatus, SourceStatus } from '@/lib/types';
+import { convertLength, convertSize, convertTime } from '@/lib/lib';
+import { ReactNode, useEffect, useState } from 'react';
+
+const STATUS_POLL_MS = 2000;
+
+const sourceLabel = (source: SourceStatus): string => {
+    const name = { video: 'Screen', bg: 'Desktop audio', mic: 'Microphone' }[source.name] ?? source.name;
+    const details = [source.node_id !== undefined ? `node ${source.node_id}` : '', source.muted ? 'muted' : '']
+        .filter(Boolean)
+        .join(', ');
+    return details ? `${name} (${details})` : name;
+};
+
+const StatusRow = ({ label, children }: { label: string; children: ReactNode }) => (
+    <div className='flex items-start justify-between gap-6 py-3 border-b border-zinc-800 last:border-b-0'>
+        <span className='text-zinc-400'>{label}</span>
+        <span className='text-right text-white'>{children}</span>
+    </div>
+);
+
+const DaemonStatusCard = () => {
+    const [status, setStatus] = useState<DaemonStatus | null>(null);
+    const [error, setError] = useState<string | null>(null);
+
+    useEffect(() => {
+        const fetchStatus = async () => {
+            try {
+                setStatus(await invoke<DaemonStatus>('get_daemon_status_command'));
+                setError(null);
+            } catch (e) {
+                setStatus(null);
+                setError(String(e));
+            }
+        };
+        fetchStatus();
+        const interval = setInterval(fetchStatus, STATUS_POLL_MS);
+        return () => clearInterval(interval);
+    }, []);
+
+    if (!status) {
+        return (
+            <Card className='bg-zinc-900/50 border-zinc-800'>
+                <CardContent className='px-6 text-zinc-400'>{error ?? 'Loading daemon status...'}</CardContent>
+            </Card>
+        );
+    }
+
+    const lastSave = status.last_save;
+    return (
+        <Card className='bg-zinc-900/50 border-zinc-800'>
+            <CardContent className='px-6'>
+                <StatusRow label='Pipeline'>
+                    <span
+                        className={cn(
+                            status.state === 'Playing' && 'text-green-400',
+                            status.state === 'Degraded' && 'text-red-400',
+                        )}
+                    >
+                        {status.state}
+                    </span>
+                    {status.error && <p className='text-sm text-red-400'>{status.error}</p>}
+                </StatusRow>
+                <StatusRow label='Uptime'>{convertLength(status.uptime_ms / 1000)} ({status.restarts} restarts)</StatusRow>
+                <StatusRow label='Buffer'>
+                    {convertLength(status.buffered_ms / 1000)}, {convertSize(status.buffered_bytes)}
+                </StatusRow>
+                <StatusRow label='Bitrate'>{status.bitrate_kbps} kbps</StatusRow>
+                <StatusRow label='Dropped chunks'>
+                    <span className={cn(status.dropped_frames > 0 && 'text-yellow-400')}>{status.dropped_frames}</span>
+                </StatusRow>
+                <StatusRow label='Sources'>
+                    {status.sources.map((source, i) => (
+                        <p key={`${source.name}-${i}`}>{sourceLabel(source)}</p>
+                    ))}
+                </StatusRow>
+                <StatusRow label='Saves'>
+                    {status.is_saving ? `Saving, ${status.queued_saves} queued` : 'Idle'}
+                </StatusRow>
+                <StatusRow label='Last save'>
+                    {lastSave ? (
+                        <>
+                            <p className={cn(lastSave.error && 'text-red-400')}>
+                                {lastSave.error ?? lastSave.path?.split('/').pop()}
+                            </p>
+                            <p className='text-sm text-zinc-400'>{convertTime(lastSave.finished_at)}</p>
+                        </>
+                    ) : (
+                        'None yet'
+                    )}
+                </StatusRow>
+            </CardContent>
+        </Card>
+    );
+};
 
 const Home = () => {
     const { toggleSidebar } = useSidebar();
@@ -18,6 +115,13 @@ const Home = () => {
                 <div className='w-[1px] h-8 mr-1 bg-zinc-800' />
                 <h1 className='text-2xl font-bold'>Home</h1>
             </div>
+            <div className='pt-8 w-2xl mx-auto'>
+                <div className='flex items-center gap-3 mb-4'>
+                    <h2 className='text-lg font-semibold text-white'>Daemon</h2>
+                    <div className='h-px bg-zinc-800 flex-1' />
+                </div>
+                <DaemonStatusCard />
+            </div>
         </div>
     );
 };
@@ -53,9 +53,7 @@ const Settings = () => {
         setSavingCategories((prev) => new Set(prev).add(categoryName));
 
         try {
-            for (const [key, value] of categoryChanges) {
-                await invoke('update_settings', { key, value });
-            }
+            await invoke('update_settings_many', { changes: Object.fromEntries(categoryChanges) });
 
             setCurrentSettings((prev) =>
                 prev.map((setting) => {
@@ -189,12 +187,8 @@ const Settings = () => {
                                             options={
                                                 setting.type === 'select' && setting.category === categories.audio
                                                     ? setting.storageKey === 'mic_node_name'
-                                                        ? audioDevices.filter((d) =>
-                                                              d.node_name.startsWith('alsa_input'),
-                                                          )
-                                                        : audioDevices.filter((d) =>
-                                                              d.node_name.startsWith('alsa_output'),
-                                                          )
+                                                        ? audioDevices.filter((d) => d.media_class === 'source')
+                                                        : audioDevices.filter((d) => d.media_class === 'sink')
                                                     : setting.options
                                             }
                                             value={getSettingValue(setting)}